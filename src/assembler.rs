@@ -1,69 +1,167 @@
-use std::{collections::HashMap, panic, todo};
+use std::collections::{HashMap, HashSet};
 
-use crate::scanner::{self, tokenize, Token, TokenType};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::instruction::{Instruction, Register};
+use crate::scanner::{self, tokenize, NumericalValue, Token, TokenType};
 
-pub fn assemble(source: String) -> Vec<u8> {
-    let tokens = tokenize(source);
-    let mut parser = Parser::new(tokens);
+/// Where the program is loaded, so the address of the first instruction
+const PROGRAM_START: u16 = 0x200;
 
+/// Assembles the source into a ROM, or every problem found with it. Each line is checked even
+/// after an error, so one run reports everything that needs fixing.
+pub fn assemble(source: String) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let (tokens, mut diagnostics) = tokenize(&source);
+    let mut parser = Parser::new(tokens);
     let machine_code = parser.generate_machine_code();
-    return machine_code;
+
+    // A line that didn't scan usually won't parse either, so only its scanning errors are useful.
+    // Past the first parsing error on a line the rest are usually knock on effects of it.
+    let lines_with_errors: HashSet<usize> = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.span.line)
+        .collect();
+    let mut parser_diagnostics = parser.diagnostics;
+    parser_diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    parser_diagnostics.dedup_by_key(|diagnostic| diagnostic.span.line);
+    diagnostics.extend(
+        parser_diagnostics
+            .into_iter()
+            .filter(|diagnostic| !lines_with_errors.contains(&diagnostic.span.line)),
+    );
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+        return Err(diagnostics);
+    }
+    return Ok(machine_code);
 }
 
 struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
     fn new(tokens: Vec<Token>) -> Self {
-        return Parser { tokens, current: 0 };
+        return Parser {
+            tokens,
+            current: 0,
+            diagnostics: Vec::new(),
+        };
+    }
+
+    /// First pass: works out the address every statement will end up at, so code labels can be
+    /// used before they're defined. Returns the value of every label, code or constant.
+    fn layout_pass(&mut self) -> HashMap<Vec<char>, u16> {
+        let mut labels: HashMap<Vec<char>, u16> = HashMap::new();
+        let mut define = |name: &[char], value: u16, span: Span, diagnostics: &mut Vec<_>| {
+            if let Some(old_value) = labels.insert(name.to_owned(), value) {
+                diagnostics.push(Diagnostic::new(
+                    format!(
+                        "Label '{}' was already defined with value 0x{:X}",
+                        name.iter().collect::<String>(),
+                        old_value
+                    ),
+                    span,
+                ));
+            }
+        };
+
+        let mut address = PROGRAM_START;
+        for (i, token) in self.tokens.iter().enumerate() {
+            match token.token_type {
+                TokenType::CodeLabel => {
+                    define(&token.word, address, token.span, &mut self.diagnostics)
+                }
+                TokenType::LabelIdentifier => {
+                    match lone_number(&self.tokens[i + 1..]) {
+                        // Drop the :
+                        Some(value) => {
+                            define(&token.word[1..], value, token.span, &mut self.diagnostics)
+                        }
+                        None => self.diagnostics.push(Diagnostic::new(
+                            format!(
+                                "Was expecting a number after {}, like {} 0x300",
+                                token.word.iter().collect::<String>(),
+                                token.word.iter().collect::<String>()
+                            ),
+                            token.span,
+                        )),
+                    }
+                }
+                TokenType::DB | TokenType::DW => {
+                    let value_count = self.tokens[i + 1..]
+                        .iter()
+                        .take_while(|token| token.token_type != TokenType::Newline)
+                        .filter(|token| token.token_type != TokenType::Comma)
+                        .count();
+                    let value_size = if token.token_type == TokenType::DW {
+                        2
+                    } else {
+                        1
+                    };
+                    address = address.wrapping_add((value_count * value_size) as u16);
+                }
+                // Bad directives don't move anything, they're reported when generating the code
+                TokenType::ORG => {
+                    if let Some(target) = lone_number(&self.tokens[i + 1..]) {
+                        address = address.max(target);
+                    }
+                }
+                TokenType::ALIGN => {
+                    if let Some(boundary) = lone_number(&self.tokens[i + 1..]).filter(|b| *b > 0) {
+                        address = (address as usize).next_multiple_of(boundary as usize) as u16;
+                    }
+                }
+                TokenType::SpriteRow => {
+                    address = address.wrapping_add(sprite_row_length(token) as u16);
+                }
+                token_type if is_instruction(token_type) => {
+                    address = address.wrapping_add(statement_length(&self.tokens[i + 1..]))
+                }
+                _ => {}
+            }
+        }
+        return labels;
     }
 
+    /// Second pass over the tokens, before generating code: swaps labels for their values, and
+    /// drops the label definitions
     fn label_pre_pass(&mut self) -> Vec<Token> {
+        let labels = self.layout_pass();
         let mut processed_tokens = Vec::with_capacity(self.tokens.len());
-        let mut labels: HashMap<Vec<char>, u16> = HashMap::new();
 
         while self.current < self.tokens.len() {
-            // for i in 0..self.tokens.len() {
             let token = self.tokens[self.current].clone();
             self.advance();
-            let i = self.current;
             // SPEEDUP: Don't clone
             match token.token_type {
                 TokenType::LabelIdentifier => {
-                    // TODO(Reece): Range check
-                    if self.match_tokens_consume_if_true(&[
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) {
-                        let number = self.tokens[i].literal.unwrap();
-
-                        // HACK: Just want to keep moving, Stop the cloning when I can be bothered
-                        // to worry about lifetimes
-                        let mut token_name = token.word.clone();
-                        token_name.remove(0);
-                        let token_name_again = token_name.clone();
-
-                        match labels.insert(token_name_again, number) {
-                            None => {}
-                            Some(x) => {
-                                panic!("{:?} was already defined with value {}", token_name, x)
-                            }
-                        }
-                    } else {
-                        panic!("Was expecting a number and a newline after LabelIdentifier, found {:?}, {:?}", self.tokens[i], self.tokens[i + 1]);
-                    };
+                    // Already checked by the layout pass. Skip the value, but keep the newline
+                    while !self.check(TokenType::Newline) {
+                        self.advance();
+                    }
                 }
+                TokenType::CodeLabel => {}
                 TokenType::Label => {
                     let value = match labels.get(&token.word) {
-                        None => panic!("Could not find value for {:?}", &token.word),
-                        Some(x) => x,
+                        None => {
+                            self.diagnostics.push(Diagnostic::new(
+                                format!(
+                                    "Label '{}' is not defined",
+                                    token.word.iter().collect::<String>()
+                                ),
+                                token.span,
+                            ));
+                            // Carry on as if it was, to find any other problems
+                            0
+                        }
+                        Some(x) => *x,
                     };
                     let mut value_token = token;
                     value_token.token_type =
                         TokenType::NumericalValue(scanner::NumericalValue::Label);
-                    value_token.literal = Some(*value);
+                    value_token.literal = Some(value);
                     processed_tokens.push(value_token);
                 }
                 TokenType::Number => {
@@ -79,8 +177,7 @@ impl Parser {
         return processed_tokens;
     }
 
-    /// Any instruction that uses a register specified by hexadecimal will be assumed to be valid
-    /// for now
+    /// Any problems are added to diagnostics, and the rest of the statement is skipped
     fn generate_machine_code(&mut self) -> Vec<u8> {
         // Didn't feel necessary to generate "statements" from the tokens just to generate the
         // machine code at the time
@@ -94,276 +191,37 @@ impl Parser {
         while self.current < self.tokens.len() {
             let current_token = self.tokens[self.current].clone();
             self.advance();
-            match current_token.token_type {
-                TokenType::CLS | TokenType::RET => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev].to_owned();
-                    if !self.next_token_is_newline() {
-                        // TODO(reece): Some way to do line counts for better error messages
-                        // TODO(reece): Better error handling for parser errors
-                        panic!(
-                            "{:?} was expecting a newline. Instead found {:?}",
-                            current_token.token_type,
-                            self.next_token().token_type
-                        );
-                    }
-                    machine_code.append(&mut Parser::machine_code_for_instruction(
-                        &current_token,
-                        &following_tokens,
-                    ));
-                }
-                TokenType::JP | TokenType::Call | TokenType::SKP | TokenType::SKNP => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev + 1].to_owned();
-                    if !self.match_tokens_consume_if_true(&[
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        // TODO(reece): This matching on the label + number is getting very annoying
-                        TokenType::NumericalValue(scanner::NumericalValue::Label),
-                        TokenType::Newline,
-                    ]) {
-                        panic!(
-                            "{:?} was expecting a number/label and a new line. Instead found {:?} and {:?}",
-                            current_token.token_type,
-                            following_tokens[0].token_type,
-                            following_tokens[1].token_type,
-                        );
-                    } else {
-                        machine_code.append(&mut Parser::machine_code_for_instruction(
-                            &current_token,
-                            &following_tokens,
-                        ));
-                    }
-                }
-                TokenType::LD => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev + 2].to_owned();
-                    if !self.match_tokens_consume_if_true(&[
-                        TokenType::IRegister,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::IRegister,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Label),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Label),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::Register,
-                        TokenType::Newline,
-                    ]) {
-                        // TODO(reece): Better way for parsing messages here. Could just have our
-                        // slices of expected Tokens be the thing we create the message from
-                        panic!(
-                            "{:?} was expecting I, a comma, a number/label, and a new line, or register, comma, number/label, newline, or register, comma, number/label, register.. Instead found {:?} and {:?} and {:?}",
-                            current_token.token_type,
-                            following_tokens[0].token_type,
-                            following_tokens[1].token_type,
-                            following_tokens[2].token_type,
-                        );
-                    } else {
-                        machine_code.append(&mut Parser::machine_code_for_instruction(
-                            &current_token,
-                            following_tokens.as_slice(),
-                        ));
-                    }
-                }
-                TokenType::SE | TokenType::SNE => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev + 2].to_owned();
-                    if !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::Register,
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Label),
-                        TokenType::Newline,
-                    ]) {
-                        panic!(
-                            "{:?} was expecting a register, a comma, a register, and a new line, or a register, a comma, a number/label, and a new line. Instead found {:?} and {:?} and {:?} and {:?}",
-                            current_token.token_type,
-                            self.tokens[prev].token_type,
-                            self.tokens[prev + 1].token_type,
-                            self.tokens[prev + 2].token_type,
-                            self.tokens[prev + 3].token_type,
-                        );
-                    } else {
-                        machine_code.append(&mut Parser::machine_code_for_instruction(
-                            &current_token,
-                            &following_tokens,
-                        ));
-                    }
-                }
-                TokenType::RND => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev + 2].to_owned();
-                    if !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Label),
-                        TokenType::Newline,
-                    ]) {
-                        panic!(
-                            "{:?} was expecting a register, a comma, a number/label, and a new line. Instead found {:?} and {:?} and {:?}",
-                            current_token.token_type,
-                            self.tokens[prev].token_type,
-                            self.tokens[prev + 1].token_type,
-                            self.tokens[prev + 2].token_type,
-                        );
-                    } else {
-                        machine_code.append(&mut Parser::machine_code_for_instruction(
-                            &current_token,
-                            &following_tokens,
-                        ));
-                    }
-                }
-                TokenType::ADD => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev + 3].to_owned();
-                    if !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::Register,
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Label),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::IRegister,
-                        TokenType::Comma,
-                        TokenType::Register,
-                        TokenType::Newline,
-                    ]) {
-                        panic!(
-                            "{:?} was expecting a register, a comma, a register, and a new line, or a register, a comma, a number/label, and a new line, or an iregister, a comma, a register, and a new line. Instead found {:?} and {:?} and {:?} and {:?}",
-                            current_token.token_type,
-                            self.tokens[prev].token_type,
-                            self.tokens[prev + 1].token_type,
-                            self.tokens[prev + 2].token_type,
-                            self.tokens[prev + 3].token_type,
-                        );
-                    } else {
-                        machine_code.append(&mut Parser::machine_code_for_instruction(
-                            &current_token,
-                            &following_tokens,
-                        ));
-                    }
-                }
-                TokenType::OR
-                | TokenType::XOR
-                | TokenType::SUB
-                | TokenType::AND
-                | TokenType::SUBN
-                | TokenType::SHL
-                | TokenType::SHR => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev + 3].to_owned();
-                    if !self.match_tokens(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::Register,
-                        TokenType::Newline,
-                    ]) {
-                        panic!(
-                            "{:?} was expecting a register, a comma, a register, and a new line. Instead found {:?} and {:?} and {:?} and {:?}",
-                            current_token.token_type,
-                            self.tokens[prev].token_type,
-                            self.tokens[prev + 1].token_type,
-                            self.tokens[prev + 2].token_type,
-                            self.tokens[prev + 3].token_type,
-                        );
-                    } else {
-                        machine_code.append(&mut Parser::machine_code_for_instruction(
-                            &current_token,
-                            &following_tokens,
-                        ));
-                    }
+            let result = match current_token.token_type {
+                TokenType::Newline => Ok(()),
+                TokenType::DB | TokenType::DW => self.data(&current_token, &mut machine_code),
+                TokenType::ORG => self.org(&current_token, &mut machine_code),
+                TokenType::ALIGN => self.align(&current_token, &mut machine_code),
+                TokenType::SPRITE => self.sprite(&current_token, &mut machine_code),
+                token_type if is_instruction(token_type) => {
+                    // Everything up to the end of the line
+                    let operands: Vec<Token> = self.tokens[self.current..]
+                        .iter()
+                        .take_while(|token| token.token_type != TokenType::Newline)
+                        .cloned()
+                        .collect();
+                    self.advance_by(operands.len());
+                    Parser::machine_code_for_instruction(&current_token, &operands)
+                        .map(|mut bytes| machine_code.append(&mut bytes))
                 }
-                TokenType::DRAW => {
-                    let prev = self.current;
-                    let following_tokens = self.tokens[prev..=prev + 5].to_owned();
-                    if !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Number),
-                        TokenType::Newline,
-                    ]) && !self.match_tokens_consume_if_true(&[
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::Register,
-                        TokenType::Comma,
-                        TokenType::NumericalValue(scanner::NumericalValue::Label),
-                        TokenType::Newline,
-                    ]) {
-                        panic!(
-                            "{:?} was expecting a register, a comma, a register, a comma, a number/label and a new line. Instead found {:?} and {:?} and {:?} and {:?} and {:?} and {:?}",
-                            current_token.token_type,
-                            self.tokens[prev].token_type,
-                            self.tokens[prev + 1].token_type,
-                            self.tokens[prev + 2].token_type,
-                            self.tokens[prev + 3].token_type,
-                            self.tokens[prev + 4].token_type,
-                            self.tokens[prev + 5].token_type,
-                        );
-                    } else {
-                        machine_code.append(&mut Parser::machine_code_for_instruction(
-                            &current_token,
-                            &following_tokens,
-                        ));
-                    }
-                }
-
-                TokenType::Newline => {
-                    // Do nothing
-                }
-                TokenType::Number
-                | TokenType::Addr
-                | TokenType::Comma
-                | TokenType::Colon
-                | TokenType::IRegister
-                | TokenType::Label
-                | TokenType::LabelIdentifier
-                | TokenType::NumericalValue(_)
-                | TokenType::Register => {
-                    panic!(
-                        "Was not expecting a {:?} ({:?})",
-                        current_token.token_type, current_token.word
-                    );
+                _ => Err(Diagnostic::new(
+                    format!(
+                        "Was expecting an instruction, found '{}'",
+                        current_token.word.iter().collect::<String>()
+                    ),
+                    current_token.span,
+                )),
+            };
+
+            if let Err(diagnostic) = result {
+                self.diagnostics.push(diagnostic);
+                // Carry on from the next line
+                while !self.is_at_end() && !self.check(TokenType::Newline) {
+                    self.advance();
                 }
             }
         }
@@ -371,316 +229,298 @@ impl Parser {
         return machine_code;
     }
 
-    /// Assumes there are enough Tokens in following_tokens to generate machine code
-    /// i.e for LD following_tokens would contain at least [Register, Comma, Number, Newline]
-    fn machine_code_for_instruction(
-        instruction_token: &Token,
-        following_tokens: &[Token],
-    ) -> Vec<u8> {
-        // SPEEDUP(reece): Don't just clone the tokens for the following_tokens
-        // Might be worth having an intermediate state between Tokens and machine code to make
-        // codegen easier. We're going to need to pass a slice of tokens otherwise
-        // dbg!(token.token_type);
-        let mut machine_code = Vec::new();
-
-        match instruction_token.token_type {
-            TokenType::Comma | TokenType::Newline => {
-                // Do nothing. Should we error though?
-            }
-            // TODO(reece): Fair bit of repitition here, any chance of minimizing?
-            TokenType::RET => {
-                let first_byte = 0x00;
-                let second_byte = 0xEE;
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::CLS => {
-                let first_byte = 0x00;
-                let second_byte = 0xE0;
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::JP => {
-                // 1nnn
-                let opcode = 0x1;
-                let addr = following_tokens[0].literal.unwrap();
-                let first_byte = opcode << 4 | (addr >> 8) as u8;
-                let second_byte = (addr & 0x00FF) as u8;
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::Call => {
-                // 2nnn
-                let opcode = 0x2;
-                let addr = following_tokens[0].literal.unwrap();
-                let first_byte = opcode << 4 | (addr >> 8) as u8;
-                let second_byte = (addr & 0x00FF) as u8;
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::SKP => {
-                // Ex9E
-                let opcode = 0xE;
-                let first_byte = opcode << 4 | (following_tokens[0].literal.unwrap() & 0xF) as u8;
-                let second_byte = 0x9E;
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::SKNP => {
-                // ExA1
-                let opcode = 0xE;
-                let first_byte = opcode << 4 | (following_tokens[0].literal.unwrap() & 0xF) as u8;
-                let second_byte = 0xA1;
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::LD => {
-                let token_types_to_consider = [
-                    following_tokens[0].token_type,
-                    following_tokens[2].token_type,
-                ];
-
-                match token_types_to_consider {
-                    [TokenType::Register, TokenType::NumericalValue(_)] => {
-                        // 6xkk
-                        let mut first_byte = 6;
-                        first_byte = first_byte << 4;
-                        first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                        let second_byte = following_tokens[2].literal.unwrap() as u8;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
-                    [TokenType::Register, TokenType::Register] => {
-                        // 8xy0
-                        let mut first_byte = 8;
-                        first_byte = first_byte << 4;
-                        first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                        let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                        second_byte = second_byte << 4;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
-                    [TokenType::IRegister, TokenType::NumericalValue(_)] => {
-                        // Annn
-                        let mut first_byte = 0xA;
-                        let addr = following_tokens[2].literal.unwrap();
-                        first_byte = first_byte << 4;
-                        first_byte = first_byte | ((addr >> 8) & 0xF) as u8;
-                        let second_byte = (addr & 0xFF) as u8;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
-                    x => todo!("Unimplemented or invalid machine code for {:?}.", x),
+    /// One or more values separated by commas, e.g db 0xF0, 0x90, 0xF0. dw writes each value as
+    /// two bytes, high byte first
+    fn data(&mut self, directive: &Token, machine_code: &mut Vec<u8>) -> Result<(), Diagnostic> {
+        let name: String = directive.word.iter().collect();
+        loop {
+            let token = self.next_token().clone();
+            let value = match token {
+                Token {
+                    token_type: TokenType::NumericalValue(_),
+                    literal: Some(value),
+                    ..
+                } => value,
+                _ => {
+                    return Err(Diagnostic::new(
+                        format!("{} was expecting a number or a label", name),
+                        token.span,
+                    ))
                 }
+            };
+            if directive.token_type == TokenType::DW {
+                machine_code.extend_from_slice(&value.to_be_bytes());
+            } else if value > 0xFF {
+                return Err(Diagnostic::new(
+                    format!("{} can only take bytes, 0x{:X} is too big", name, value),
+                    token.span,
+                ));
+            } else {
+                machine_code.push(value as u8);
             }
+            self.advance();
 
-            TokenType::SE => {
-                let token_types_to_consider = [
-                    following_tokens[0].token_type,
-                    following_tokens[2].token_type,
-                ];
-
-                match token_types_to_consider {
-                    [TokenType::Register, TokenType::NumericalValue(_)] => {
-                        // 3xkk
-                        let mut first_byte = 3;
-                        first_byte = first_byte << 4;
-                        first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                        let second_byte = following_tokens[2].literal.unwrap() as u8;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
-                    [TokenType::Register, TokenType::Register] => {
-                        // 5xy0
-                        let mut first_byte = 5;
-                        first_byte = first_byte << 4;
-                        first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                        let second_byte = following_tokens[2].literal.unwrap() as u8;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
-
-                    x => todo!("Unimplemented or invalid machine code for {:?}.", x),
-                }
+            if self.check(TokenType::Comma) {
+                self.advance();
+            } else if self.check(TokenType::Newline) {
+                return Ok(());
+            } else {
+                return Err(Diagnostic::new(
+                    format!("{} was expecting a comma or a new line after a value", name),
+                    self.next_token().span,
+                ));
             }
-            TokenType::SNE => {
-                // 9xy0
-                let mut first_byte = 9;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let second_byte = following_tokens[2].literal.unwrap() as u8;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
+        }
+    }
+
+    /// Pads with zeros up to an address, e.g org 0x300. The ROM is one block of memory from
+    /// 0x200, so it can only move forwards
+    fn org(&mut self, directive: &Token, machine_code: &mut Vec<u8>) -> Result<(), Diagnostic> {
+        let target = self.directive_number(directive, "org 0x300")?;
+        let address = PROGRAM_START as usize + machine_code.len();
+        if (target.value as usize) < address {
+            return Err(Diagnostic::new(
+                format!(
+                    "org can't go back to 0x{:X}, the program is already up to 0x{:X}",
+                    target.value, address
+                ),
+                target.span,
+            ));
+        }
+        machine_code.resize(target.value as usize - PROGRAM_START as usize, 0);
+        return Ok(());
+    }
+
+    /// Pads with zeros up to the next address that's a multiple of the number, e.g align 2 so
+    /// that code after an odd number of bytes starts on an even address
+    fn align(&mut self, directive: &Token, machine_code: &mut Vec<u8>) -> Result<(), Diagnostic> {
+        let boundary = self.directive_number(directive, "align 2")?;
+        if boundary.value == 0 {
+            return Err(Diagnostic::new("Can't align to 0", boundary.span));
+        }
+        let address = PROGRAM_START as usize + machine_code.len();
+        let aligned = address.next_multiple_of(boundary.value as usize);
+        machine_code.resize(aligned - PROGRAM_START as usize, 0);
+        return Ok(());
+    }
+
+    /// The number that has to follow org and align, on its own
+    fn directive_number(&mut self, directive: &Token, example: &str) -> Result<Number, Diagnostic> {
+        let token = self.next_token().clone();
+        let number = match token {
+            Token {
+                token_type: TokenType::NumericalValue(NumericalValue::Number),
+                literal: Some(value),
+                ..
+            } => Number {
+                value,
+                span: token.span,
+            },
+            _ => {
+                return Err(Diagnostic::new(
+                    format!(
+                        "{} was expecting a number, like {}",
+                        directive.word.iter().collect::<String>(),
+                        example
+                    ),
+                    token.span,
+                ))
             }
-            TokenType::OR => {
-                // 8xy1
-                let mut first_byte = 8;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | 1;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
+        };
+        self.advance();
+        if !self.check(TokenType::Newline) {
+            return Err(Diagnostic::new(
+                "Was expecting a new line after the number",
+                self.next_token().span,
+            ));
+        }
+        return Ok(number);
+    }
+
+    /// A bitmap drawn with a row of # and . on each line after the sprite directive, e.g
+    ///
+    /// ```text
+    /// sprite
+    /// .##.
+    /// #..#
+    /// .##.
+    /// ```
+    ///
+    /// Each row is a byte with the leftmost pixel in the top bit, which is how DRW reads them from
+    /// I. Rows 9 to 16 pixels wide are two bytes, for SUPER-CHIP's 16x16 sprites.
+    fn sprite(&mut self, directive: &Token, machine_code: &mut Vec<u8>) -> Result<(), Diagnostic> {
+        let mut error = None;
+        if !self.check(TokenType::Newline) {
+            error = Some(Diagnostic::new(
+                "The rows of a sprite go on the lines after it",
+                self.next_token().span,
+            ));
+            while !self.is_at_end() && !self.check(TokenType::Newline) {
+                self.advance();
             }
-            TokenType::AND => {
-                // 8xy2
-                let mut first_byte = 8;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | 2;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
+        }
+
+        // Rows are taken even if there was a problem, so they aren't reported as well
+        let mut rows = Vec::new();
+        while let Some(
+            [Token {
+                token_type: TokenType::Newline,
+                ..
+            }, row @ Token {
+                token_type: TokenType::SpriteRow,
+                ..
+            }, Token {
+                token_type: TokenType::Newline,
+                ..
+            }],
+        ) = self.tokens.get(self.current..self.current + 3)
+        {
+            rows.push(row.clone());
+            self.advance_by(2);
+        }
+        if let Some(error) = error {
+            return Err(error);
+        }
+
+        let Some(first_row) = rows.first() else {
+            return Err(Diagnostic::new(
+                "sprite was expecting rows of # and . on the lines after it, like ..##..",
+                directive.span,
+            ));
+        };
+        let width = first_row.word.len();
+        if width > 16 {
+            return Err(Diagnostic::new(
+                format!("Sprites can be at most 16 pixels wide, this is {}", width),
+                first_row.span,
+            ));
+        }
+        for row in &rows {
+            if row.word.len() != width {
+                return Err(Diagnostic::new(
+                    format!(
+                        "Every row of a sprite has to be {} pixels wide, like the first",
+                        width
+                    ),
+                    row.span,
+                ));
             }
-            TokenType::XOR => {
-                // 8xy3
-                let mut first_byte = 8;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | 3;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
+            let mut pixels: u16 = 0;
+            for (x, pixel) in row.word.iter().enumerate() {
+                if *pixel == '#' {
+                    pixels |= 0x8000 >> x;
+                }
             }
-            TokenType::ADD => {
-                let token_types_to_consider = [
-                    following_tokens[0].token_type,
-                    following_tokens[2].token_type,
-                ];
-
-                match token_types_to_consider {
-                    [TokenType::Register, TokenType::NumericalValue(_)] => {
-                        // 7xkk
-                        let mut first_byte = 7;
-                        first_byte = first_byte << 4;
-                        first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                        let second_byte = following_tokens[2].literal.unwrap() as u8;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
-                    [TokenType::Register, TokenType::Register] => {
-                        // 8xy4
-                        let mut first_byte = 8;
-                        first_byte = first_byte << 4;
-                        first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                        let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                        second_byte = second_byte << 4;
-                        second_byte = second_byte | 4;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
-                    [TokenType::IRegister, TokenType::Register] => {
-                        // Fx1E
-                        let mut first_byte = 0xF;
-                        first_byte = first_byte << 4;
-                        first_byte =
-                            first_byte | (following_tokens[2].literal.unwrap() & 0xF) as u8;
-                        let second_byte = 0x1E;
-
-                        machine_code.push(first_byte);
-                        machine_code.push(second_byte);
-                    }
+            let bytes = pixels.to_be_bytes();
+            machine_code.extend_from_slice(&bytes[..sprite_row_length(row)]);
+        }
+        return Ok(());
+    }
 
-                    x => todo!("Unimplemented or invalid machine code for {:?}.", x),
+    /// The machine code for the instruction with the given operands, which are everything after
+    /// the instruction on its line
+    fn machine_code_for_instruction(
+        instruction_token: &Token,
+        operand_tokens: &[Token],
+    ) -> Result<Vec<u8>, Diagnostic> {
+        // Makes the forms below a lot easier to match on than the tokens themselves
+        let operands: Vec<Operand> = operand_tokens.iter().map(Operand::from).collect();
+
+        use Operand::*;
+        let instruction = match (instruction_token.token_type, operands.as_slice()) {
+            (TokenType::SYS, [Value(addr)]) => Instruction::Sys(addr.address()?),
+            (TokenType::CLS, []) => Instruction::Cls,
+            (TokenType::RET, []) => Instruction::Ret,
+            (TokenType::SCD, [Value(n)]) => Instruction::ScrollDown(n.nibble()?),
+            (TokenType::SCR, []) => Instruction::ScrollRight,
+            (TokenType::SCL, []) => Instruction::ScrollLeft,
+            (TokenType::EXIT, []) => Instruction::Exit,
+            (TokenType::LOW, []) => Instruction::LowRes,
+            (TokenType::HIGH, []) => Instruction::HighRes,
+            (TokenType::JP, [Value(addr)]) => Instruction::Jp(addr.address()?),
+            (TokenType::JP, [V(register), Comma, Value(addr)]) => {
+                // SUPER-CHIP reads this as Bxnn, so the register has to be V0 or match the top
+                // nibble of the address to mean the same thing either way
+                let address = addr.address()?;
+                if *register != 0 && *register as u16 != address >> 8 {
+                    return Err(Diagnostic::new(
+                        format!(
+                            "JP V{:X}, 0x{:X} can only use V0 or V{:X} as its register",
+                            register,
+                            address,
+                            address >> 8
+                        ),
+                        operand_tokens[0].span,
+                    ));
                 }
+                Instruction::JpV0(address)
             }
-            TokenType::SUB => {
-                // 8xy5
-                let mut first_byte = 8;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | 5;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::SUBN => {
-                // 8xy7
-                let mut first_byte = 8;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | 7;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::RND => {
-                // Cxkk
-                let mut first_byte = 0xC;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let second_byte = following_tokens[2].literal.unwrap() as u8;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::DRAW => {
-                let mut first_byte = 0xD;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | (following_tokens[4].literal.unwrap() as u8 & 0xF);
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
-            }
-            TokenType::SHL => {
-                // 8xyE
-                let mut first_byte = 0x8;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | 0xE;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
+            (TokenType::Call, [Value(addr)]) => Instruction::Call(addr.address()?),
+            (TokenType::SE, [V(x), Comma, Value(kk)]) => Instruction::SeVxByte(*x, kk.byte()?),
+            (TokenType::SE, [V(x), Comma, V(y)]) => Instruction::SeVxVy(*x, *y),
+            (TokenType::SNE, [V(x), Comma, Value(kk)]) => Instruction::SneVxByte(*x, kk.byte()?),
+            (TokenType::SNE, [V(x), Comma, V(y)]) => Instruction::SneVxVy(*x, *y),
+            (TokenType::LD, [V(x), Comma, Value(kk)]) => Instruction::LdVxByte(*x, kk.byte()?),
+            (TokenType::LD, [V(x), Comma, V(y)]) => Instruction::LdVxVy(*x, *y),
+            (TokenType::LD, [I, Comma, Value(addr)]) => Instruction::LdIAddr(addr.address()?),
+            // The whole 16 bits are used, so there's nothing to range check
+            (TokenType::LD, [I, Comma, Long, Value(addr)]) => Instruction::LdILong(addr.value),
+            (TokenType::LD, [V(x), Comma, DelayTimer]) => Instruction::LdVxDt(*x),
+            (TokenType::LD, [V(x), Comma, Key]) => Instruction::LdVxK(*x),
+            (TokenType::LD, [DelayTimer, Comma, V(x)]) => Instruction::LdDtVx(*x),
+            (TokenType::LD, [SoundTimer, Comma, V(x)]) => Instruction::LdStVx(*x),
+            (TokenType::LD, [Font, Comma, V(x)]) => Instruction::LdFVx(*x),
+            (TokenType::LD, [BigFont, Comma, V(x)]) => Instruction::LdHfVx(*x),
+            (TokenType::LD, [Bcd, Comma, V(x)]) => Instruction::LdBVx(*x),
+            (TokenType::LD, [IndirectI, Comma, V(x)]) => Instruction::LdIVx(*x),
+            (TokenType::LD, [V(x), Comma, IndirectI]) => Instruction::LdVxI(*x),
+            (TokenType::LD, [IndirectI, Comma, V(x), Dash, V(y)]) => Instruction::LdIVxVy(*x, *y),
+            (TokenType::LD, [V(x), Dash, V(y), Comma, IndirectI]) => Instruction::LdVxVyI(*x, *y),
+            (TokenType::LD, [RplFlags, Comma, V(x)]) => Instruction::LdRVx(*x),
+            (TokenType::LD, [V(x), Comma, RplFlags]) => Instruction::LdVxR(*x),
+            (TokenType::ADD, [V(x), Comma, Value(kk)]) => Instruction::AddVxByte(*x, kk.byte()?),
+            (TokenType::ADD, [V(x), Comma, V(y)]) => Instruction::AddVxVy(*x, *y),
+            (TokenType::ADD, [I, Comma, V(x)]) => Instruction::AddIVx(*x),
+            (TokenType::OR, [V(x), Comma, V(y)]) => Instruction::Or(*x, *y),
+            (TokenType::AND, [V(x), Comma, V(y)]) => Instruction::And(*x, *y),
+            (TokenType::XOR, [V(x), Comma, V(y)]) => Instruction::Xor(*x, *y),
+            (TokenType::SUB, [V(x), Comma, V(y)]) => Instruction::Sub(*x, *y),
+            (TokenType::SUBN, [V(x), Comma, V(y)]) => Instruction::Subn(*x, *y),
+            // Vy is optional, since it's only used with the shift_uses_vy quirk
+            (TokenType::SHR, [V(x)]) => Instruction::Shr(*x, *x),
+            (TokenType::SHR, [V(x), Comma, V(y)]) => Instruction::Shr(*x, *y),
+            (TokenType::SHL, [V(x)]) => Instruction::Shl(*x, *x),
+            (TokenType::SHL, [V(x), Comma, V(y)]) => Instruction::Shl(*x, *y),
+            (TokenType::RND, [V(x), Comma, Value(kk)]) => Instruction::Rnd(*x, kk.byte()?),
+            (TokenType::DRAW, [V(x), Comma, V(y), Comma, Value(n)]) => {
+                Instruction::Drw(*x, *y, n.nibble()?)
             }
-            TokenType::SHR => {
-                // 8xy6
-                let mut first_byte = 0x8;
-                first_byte = first_byte << 4;
-                first_byte = first_byte | following_tokens[0].literal.unwrap() as u8;
-                let mut second_byte = following_tokens[2].literal.unwrap() as u8;
-                second_byte = second_byte << 4;
-                second_byte = second_byte | 0x6;
-
-                machine_code.push(first_byte);
-                machine_code.push(second_byte);
+            (TokenType::SKP, [V(x)]) => Instruction::Skp(*x),
+            (TokenType::SKNP, [V(x)]) => Instruction::Sknp(*x),
+            (TokenType::PLANE, [Value(n)]) => Instruction::Plane(n.nibble()?),
+            (TokenType::AUDIO, []) => Instruction::Audio,
+            (TokenType::PITCH, [V(x)]) => Instruction::Pitch(*x),
+            _ => {
+                let span = match operand_tokens.last() {
+                    Some(last) => instruction_token.span.to(last.span),
+                    None => instruction_token.span,
+                };
+                return Err(Diagnostic::new(
+                    format!(
+                        "{} can't take these operands",
+                        instruction_token.word.iter().collect::<String>()
+                    ),
+                    span,
+                ));
             }
-
-            unimplemented_token => todo!("{:?}", unimplemented_token),
-        }
-        return machine_code;
+        };
+        return Ok(instruction.encode());
     }
 
     fn next_token(&self) -> &Token {
         return &self.tokens[self.current];
     }
 
-    fn next_token_is_newline(&self) -> bool {
-        return self.tokens[self.current].token_type == TokenType::Newline;
-    }
-
     /// Does not consume the current token
     fn check(&self, token_type: TokenType) -> bool {
         if self.is_at_end() {
@@ -688,25 +528,6 @@ impl Parser {
         }
         return self.next_token().token_type == token_type;
     }
-    fn check_at(&self, token_type: TokenType, idx: usize) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-        // TODO(reece): Bounds check
-        return self.tokens[idx].token_type == token_type;
-    }
-
-    fn check_all(&self, token_types: &[TokenType]) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-        for (i, token_type) in token_types.iter().enumerate() {
-            if !self.check_at(*token_type, self.current + i) {
-                return false;
-            }
-        }
-        return true;
-    }
 
     fn advance(&mut self) {
         self.current += 1;
@@ -716,38 +537,158 @@ impl Parser {
         self.current += amount;
     }
 
-    /// Consumes the current_token if it matches the given token type, advancing when matching
-    fn match_tokens(&mut self, token_types: &[TokenType]) -> bool {
-        for token_type in token_types {
-            if self.check(*token_type) {
-                self.advance();
-            } else {
-                return false;
-            }
-        }
-        return true;
+    fn is_at_end(&self) -> bool {
+        return self.current >= self.tokens.len();
     }
+}
 
-    /// Consumes the given tokens if they match, advancing only if ALL tokens match
-    /// Useful for instructions that have many different forms (Like SE accepting registers or
-    /// number), so we don't skip over the same tokens we're trying to match against.
-    fn match_tokens_consume_if_true(&mut self, token_types: &[TokenType]) -> bool {
-        if self.check_all(token_types) {
-            self.advance_by(token_types.len());
-            return true;
-        } else {
-            return false;
+/// An instruction's operand, with the value pulled out of the token where it has one
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    V(Register),
+    /// A number or a label
+    Value(Number),
+    I,
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    BigFont,
+    Bcd,
+    RplFlags,
+    Long,
+    Comma,
+    Dash,
+    /// Anything that can't be an operand, like another instruction
+    Other(TokenType),
+}
+
+/// A numerical operand, and where it was so it can be pointed at if it's out of range
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Number {
+    value: u16,
+    span: Span,
+}
+
+impl Number {
+    fn at_most(&self, max: u16, what: &str) -> Result<u16, Diagnostic> {
+        if self.value > max {
+            return Err(Diagnostic::new(
+                format!(
+                    "0x{:X} is too big for {}, which goes up to 0x{:X}",
+                    self.value, what, max
+                ),
+                self.span,
+            ));
         }
+        return Ok(self.value);
     }
 
-    fn is_at_end(&self) -> bool {
-        // TODO(reece): DRAW is just a stand in until we decide what end means
-        if self.next_token().token_type == TokenType::DRAW {
-            panic!("Time to decide an actual end token intead of DRAW");
-        } else {
-            return false;
-        }
+    fn address(&self) -> Result<u16, Diagnostic> {
+        return self.at_most(0xFFF, "an address");
+    }
+
+    fn byte(&self) -> Result<u8, Diagnostic> {
+        return Ok(self.at_most(0xFF, "a byte")? as u8);
     }
+
+    fn nibble(&self) -> Result<u8, Diagnostic> {
+        return Ok(self.at_most(0xF, "a nibble")? as u8);
+    }
+}
+
+impl From<&Token> for Operand {
+    fn from(token: &Token) -> Self {
+        return match token.token_type {
+            // The scanner only makes registers for V0 to VF
+            TokenType::Register => Operand::V(token.literal.unwrap() as Register),
+            TokenType::NumericalValue(_) => Operand::Value(Number {
+                value: token.literal.unwrap(),
+                span: token.span,
+            }),
+            TokenType::IRegister => Operand::I,
+            TokenType::IndirectI => Operand::IndirectI,
+            TokenType::DelayTimer => Operand::DelayTimer,
+            TokenType::SoundTimer => Operand::SoundTimer,
+            TokenType::Key => Operand::Key,
+            TokenType::Font => Operand::Font,
+            TokenType::BigFont => Operand::BigFont,
+            TokenType::Bcd => Operand::Bcd,
+            TokenType::RplFlags => Operand::RplFlags,
+            TokenType::Long => Operand::Long,
+            TokenType::Comma => Operand::Comma,
+            TokenType::Dash => Operand::Dash,
+            other => Operand::Other(other),
+        };
+    }
+}
+
+/// The token types that start an instruction. See statement_length for how long they are
+fn is_instruction(token_type: TokenType) -> bool {
+    return matches!(
+        token_type,
+        TokenType::SYS
+            | TokenType::SCD
+            | TokenType::SCR
+            | TokenType::SCL
+            | TokenType::EXIT
+            | TokenType::LOW
+            | TokenType::HIGH
+            | TokenType::PLANE
+            | TokenType::AUDIO
+            | TokenType::PITCH
+            | TokenType::LD
+            | TokenType::JP
+            | TokenType::Call
+            | TokenType::SE
+            | TokenType::SNE
+            | TokenType::ADD
+            | TokenType::SUB
+            | TokenType::SUBN
+            | TokenType::AND
+            | TokenType::XOR
+            | TokenType::OR
+            | TokenType::RND
+            | TokenType::DRAW
+            | TokenType::SKP
+            | TokenType::SKNP
+            | TokenType::RET
+            | TokenType::CLS
+            | TokenType::SHL
+            | TokenType::SHR
+    );
+}
+
+/// The value of a number that's the only thing left on the line, like the one after :name
+fn lone_number(tokens: &[Token]) -> Option<u16> {
+    return match tokens.get(..2) {
+        Some(
+            [value @ Token {
+                token_type: TokenType::Number | TokenType::NumericalValue(NumericalValue::Number),
+                ..
+            }, Token {
+                token_type: TokenType::Newline,
+                ..
+            }],
+        ) => value.literal,
+        _ => None,
+    };
+}
+
+/// How many bytes a row of a sprite takes up. Up to 8 pixels fit in one byte
+fn sprite_row_length(row: &Token) -> usize {
+    return if row.word.len() > 8 { 2 } else { 1 };
+}
+
+/// How many bytes an instruction assembles to, given the tokens after it. Always 2, apart from
+/// XO-CHIP's LD I, long addr
+fn statement_length(following_tokens: &[Token]) -> u16 {
+    let is_long = following_tokens
+        .iter()
+        .take_while(|token| token.token_type != TokenType::Newline)
+        .any(|token| token.token_type == TokenType::Long);
+    return if is_long { 4 } else { 2 };
 }
 
 #[cfg(test)]
@@ -758,13 +699,303 @@ mod tests {
     fn it_assembles_maze() {
         let maze_assembly = std::fs::read_to_string("./test_programs/maze.asm").unwrap();
         let maze_machine_code = std::fs::read("./test_programs/maze.ch8").unwrap();
-        assert_eq!(assemble(maze_assembly), maze_machine_code);
+        assert_eq!(assemble(maze_assembly), Ok(maze_machine_code));
     }
 
     #[test]
     fn it_assembles_with_labels() {
         let label_assembly = std::fs::read_to_string("./test_programs/labels.asm").unwrap();
         let label_machine_code = std::fs::read("./test_programs/labels.ch8").unwrap();
-        assert_eq!(assemble(label_assembly), label_machine_code);
+        assert_eq!(assemble(label_assembly), Ok(label_machine_code));
+    }
+
+    #[test]
+    fn it_assembles_jump_tables() {
+        let jump_table_assembly =
+            std::fs::read_to_string("./test_programs/jump_table.asm").unwrap();
+        let jump_table_machine_code = std::fs::read("./test_programs/jump_table.ch8").unwrap();
+        assert_eq!(assemble(jump_table_assembly), Ok(jump_table_machine_code));
+    }
+
+    #[test]
+    fn it_assembles_the_quirks_test() {
+        let quirks_assembly = std::fs::read_to_string("./test_programs/quirks.asm").unwrap();
+        let quirks_machine_code = std::fs::read("./test_programs/quirks.ch8").unwrap();
+        assert_eq!(assemble(quirks_assembly), Ok(quirks_machine_code));
+    }
+
+    #[test]
+    fn keywords_and_registers_can_be_any_case() {
+        for program in ["maze", "quirks"] {
+            let assembly =
+                std::fs::read_to_string(format!("./test_programs/{}.asm", program)).unwrap();
+            let machine_code = std::fs::read(format!("./test_programs/{}.ch8", program)).unwrap();
+            assert_eq!(assemble(assembly.to_lowercase()), Ok(machine_code.clone()));
+            assert_eq!(
+                assemble(assembly.to_uppercase().replace("0X", "0x")),
+                Ok(machine_code)
+            );
+        }
+        assert_eq!(
+            assemble("Ld v1, [i]\nlD I, LONG 0x1234\n".to_string()),
+            assemble("LD V1, [I]\nLD I, long 0x1234\n".to_string())
+        );
+    }
+
+    #[test]
+    fn it_assembles_code_labels() {
+        let source = "\
+            start:\n\
+            CALL draw\n\
+            JP start\n\
+            draw:\n\
+            LD I, square\n\
+            DRW V0, V1, 0x2\n\
+            RET\n\
+            square:\n\
+            DB 0xF0, 0x90\n";
+        assert_eq!(
+            assemble(source.to_string()).unwrap(),
+            vec![0x22, 0x04, 0x12, 0x00, 0xA2, 0x0A, 0xD0, 0x12, 0x00, 0xEE, 0xF0, 0x90]
+        );
+    }
+
+    /// Each diagnostic as line:column: message
+    fn errors(source: &str) -> Vec<String> {
+        return assemble(source.to_string())
+            .unwrap_err()
+            .iter()
+            .map(Diagnostic::to_string)
+            .collect();
+    }
+
+    #[test]
+    fn undefined_labels_are_an_error() {
+        assert_eq!(
+            errors("JP nowhere\n"),
+            vec!["1:4: Label 'nowhere' is not defined"]
+        );
+    }
+
+    #[test]
+    fn duplicate_labels_are_an_error() {
+        assert_eq!(
+            errors("loop:\nCLS\nloop:\nJP loop\n"),
+            vec!["3:1: Label 'loop' was already defined with value 0x200"]
+        );
+    }
+
+    #[test]
+    fn reserved_words_cant_be_labels() {
+        assert_eq!(
+            errors("F:\nCLS\n:DT 0x300\nloop:\nJP loop\n"),
+            vec![
+                "1:1: 'F' is a reserved word, so it can't be a label",
+                "3:1: 'DT' is a reserved word, so it can't be a label",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_line() {
+        let source = "\
+            LD V0, 0x100\n\
+            CLS\n\
+            DRW V0, V1\n\
+            LD V1, 0y12 ; a typo\n\
+            ADD V1, V2\n\
+            JP VZZ\n\
+            V1, 0x2\n\
+            DB 0x1, 300\n\
+            LD VG, 0x1";
+        assert_eq!(
+            errors(source),
+            vec![
+                "1:8: 0x100 is too big for a byte, which goes up to 0xFF",
+                "3:1: DRW can't take these operands",
+                "4:8: 0y12 is not a valid number",
+                "6:4: Label 'VZZ' is not defined",
+                "7:1: Was expecting an instruction, found 'V1'",
+                "8:9: DB can only take bytes, 0x12C is too big",
+                "9:4: There is no register VG, they go from V0 to VF",
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_diagnostics_with_the_file_and_line() {
+        let source = "CLS\nJP 0x1000\n";
+        let diagnostics = assemble(source.to_string()).unwrap_err();
+        assert_eq!(
+            diagnostics[0].render("game.asm", source),
+            "game.asm:2:4: error: 0x1000 is too big for an address, which goes up to 0xFFF\n\
+             JP 0x1000\n   \
+             ^^^^^^"
+        );
+    }
+
+    #[test]
+    fn garbage_is_reported_not_panicked_on() {
+        for source in [
+            "0x",
+            ":",
+            ":1",
+            "[",
+            "[I",
+            "V",
+            "LD",
+            ",,,",
+            "DB",
+            "DB 1,",
+            "\u{1F980}",
+            "JP V1, 0x200",
+        ] {
+            assert!(assemble(source.to_string()).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn it_assembles_every_instruction() {
+        let lines: &[(&str, &[u8])] = &[
+            ("SYS 0x123", &[0x01, 0x23]),
+            ("CLS", &[0x00, 0xE0]),
+            ("RET", &[0x00, 0xEE]),
+            ("SCD 0x4", &[0x00, 0xC4]),
+            ("SCR", &[0x00, 0xFB]),
+            ("SCL", &[0x00, 0xFC]),
+            ("EXIT", &[0x00, 0xFD]),
+            ("LOW", &[0x00, 0xFE]),
+            ("HIGH", &[0x00, 0xFF]),
+            ("JP 0x234", &[0x12, 0x34]),
+            ("CALL 0x345", &[0x23, 0x45]),
+            ("SE V1, 0x42", &[0x31, 0x42]),
+            ("SNE V2, 66", &[0x42, 0x42]),
+            ("SE V3, V4", &[0x53, 0x40]),
+            ("LD [I], V3 - V5", &[0x53, 0x52]),
+            ("LD V3 - V5, [I]", &[0x53, 0x53]),
+            ("LD V5, 0xFF", &[0x65, 0xFF]),
+            ("ADD V6, 0x01", &[0x76, 0x01]),
+            ("LD V7, V8", &[0x87, 0x80]),
+            ("OR V7, V8", &[0x87, 0x81]),
+            ("AND V7, V8", &[0x87, 0x82]),
+            ("XOR V7, V8", &[0x87, 0x83]),
+            ("ADD V7, V8", &[0x87, 0x84]),
+            ("SUB V7, V8", &[0x87, 0x85]),
+            ("SHR V7, V8", &[0x87, 0x86]),
+            ("SHR V7", &[0x87, 0x76]),
+            ("SUBN V7, V8", &[0x87, 0x87]),
+            ("SHL V7, V8", &[0x87, 0x8E]),
+            ("SNE V9, VA", &[0x99, 0xA0]),
+            ("LD I, 0x456", &[0xA4, 0x56]),
+            ("JP V0, 0x300", &[0xB3, 0x00]),
+            ("RND VB, 0x0F", &[0xCB, 0x0F]),
+            ("DRW VC, VD, 0x5", &[0xDC, 0xD5]),
+            ("SKP VE", &[0xEE, 0x9E]),
+            ("SKNP VE", &[0xEE, 0xA1]),
+            ("LD I, long 0x1234", &[0xF0, 0x00, 0x12, 0x34]),
+            ("PLANE 0x3", &[0xF3, 0x01]),
+            ("AUDIO", &[0xF0, 0x02]),
+            ("LD V1, DT", &[0xF1, 0x07]),
+            ("LD V1, K", &[0xF1, 0x0A]),
+            ("LD DT, V1", &[0xF1, 0x15]),
+            ("LD ST, V1", &[0xF1, 0x18]),
+            ("ADD I, V1", &[0xF1, 0x1E]),
+            ("LD F, V1", &[0xF1, 0x29]),
+            ("LD HF, V1", &[0xF1, 0x30]),
+            ("LD B, V1", &[0xF1, 0x33]),
+            ("PITCH V1", &[0xF1, 0x3A]),
+            ("LD [I], V1", &[0xF1, 0x55]),
+            ("LD V1, [I]", &[0xF1, 0x65]),
+            ("LD R, V1", &[0xF1, 0x75]),
+            ("LD V1, R", &[0xF1, 0x85]),
+        ];
+        for (line, machine_code) in lines {
+            assert_eq!(
+                assemble(format!("{}\n", line)).as_deref(),
+                Ok(*machine_code),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn long_loads_count_as_4_bytes_for_labels() {
+        let source = "LD I, long 0x1234\nend:\nJP end\n";
+        assert_eq!(
+            assemble(source.to_string()).unwrap(),
+            vec![0xF0, 0x00, 0x12, 0x34, 0x12, 0x04]
+        );
+    }
+
+    #[test]
+    fn it_assembles_data_directives() {
+        let source = "\
+            db 0x1, 2\n\
+            byte 0x3\n\
+            dw 0x1234, end\n\
+            align 4\n\
+            org 0x20A\n\
+            end:\n\
+            JP end\n";
+        assert_eq!(
+            assemble(source.to_string()).unwrap(),
+            vec![0x01, 0x02, 0x03, 0x12, 0x34, 0x02, 0x0A, 0x00, 0x00, 0x00, 0x12, 0x0A]
+        );
+        assert_eq!(
+            assemble(source.to_uppercase().replace("0X", "0x")).unwrap(),
+            assemble(source.to_string()).unwrap()
+        );
+    }
+
+    #[test]
+    fn sprites_are_the_bytes_drw_reads() {
+        let source = "\
+            LD I, zero\n\
+            DRW V0, V1, 0x5\n\
+            zero: sprite\n\
+            ####\n\
+            #..# ; the sides\n\
+            #..#\n\
+            #..#\n\
+            ####\n\
+            wide:\n\
+            sprite\n\
+            ################\n\
+            #..............#\n";
+        assert_eq!(
+            assemble(source.to_string()).unwrap(),
+            vec![
+                0xA2, 0x04, 0xD0, 0x15, // The same bytes as the 0 in the font
+                0xF0, 0x90, 0x90, 0x90, 0xF0, // Rows wider than 8 pixels take two bytes
+                0xFF, 0xFF, 0x80, 0x01,
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_directives_are_an_error() {
+        let source = "\
+            CLS\n\
+            org 0x200\n\
+            align 0\n\
+            dw\n\
+            sprite\n\
+            ..##\n\
+            .##\n\
+            sprite\n\
+            CLS\n\
+            ##";
+        assert_eq!(
+            errors(source),
+            vec![
+                "2:5: org can't go back to 0x200, the program is already up to 0x202",
+                "3:7: Can't align to 0",
+                "4:3: dw was expecting a number or a label",
+                "7:1: Every row of a sprite has to be 4 pixels wide, like the first",
+                "8:1: sprite was expecting rows of # and . on the lines after it, like ..##..",
+                "10:1: Was expecting an instruction, found '##'",
+            ]
+        );
     }
 }
@@ -0,0 +1,45 @@
+//! Assembles CHIP-8 source into a ROM.
+//!
+//! chip8-asm <source> <output>
+//!
+//! Any errors are printed with the line they're on, and nothing is written.
+
+use chip_8_emulator::assembler::assemble;
+
+const USAGE: &str = "Usage: chip8-asm <source> <output>";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let [source_path, output_path] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+
+    let source = match std::fs::read_to_string(source_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", source_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let rom = match assemble(source.clone()) {
+        Ok(rom) => rom,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}\n", diagnostic.render(source_path, &source));
+            }
+            eprintln!("{} error(s), nothing was written", diagnostics.len());
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = std::fs::write(output_path, rom) {
+        eprintln!("Couldn't write {}: {}", output_path, e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,44 @@
+//! Disassembles a CHIP-8 ROM into source the assembler accepts.
+//!
+//! chip8-disasm <rom> [output]
+//!
+//! Writes to stdout when no output path is given.
+
+use chip_8_emulator::disassembler::disassemble;
+
+const USAGE: &str = "Usage: chip8-disasm <rom> [output]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let (rom_path, output_path) = match args.as_slice() {
+        [rom_path] => (rom_path, None),
+        [rom_path, output_path] => (rom_path, Some(output_path)),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let rom = match std::fs::read(rom_path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", rom_path, e);
+            std::process::exit(1);
+        }
+    };
+    let source = disassemble(&rom);
+
+    match output_path {
+        None => print!("{}", source),
+        Some(output_path) => {
+            if let Err(e) = std::fs::write(output_path, source) {
+                eprintln!("Couldn't write {}: {}", output_path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! Runs a CHIP-8 ROM without a window or audio, for CI and the like.
+//!
+//! chip8-headless [OPTIONS] <ROM>
+//!
+//! The same as chip-8-emulator --headless, but without needing SDL. See headless::run
+
+use chip_8_emulator::cli;
+use chip_8_emulator::headless;
+
+fn main() {
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::HEADLESS_USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.show_help {
+        print!("{}", cli::HEADLESS_USAGE);
+        return;
+    }
+    let Some(rom_path) = &options.rom_path else {
+        eprintln!("No ROM given\n\n{}", cli::HEADLESS_USAGE);
+        std::process::exit(2);
+    };
+
+    let rom_bytes = match std::fs::read(rom_path) {
+        Ok(rom_bytes) => rom_bytes,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", rom_path, e);
+            std::process::exit(1);
+        }
+    };
+    std::process::exit(headless::run(&rom_bytes, &options));
+}
@@ -1,10 +1,31 @@
+use std::collections::VecDeque;
+
+use crate::instruction::{Instruction, InvalidOpcode};
+use crate::rng::Rng;
+
 pub const CHIP_DISPLAY_WIDTH_IN_PIXELS: usize = 64;
 pub const CHIP_DISPLAY_HEIGHT_IN_PIXELS: usize = 32;
+pub const SUPER_CHIP_DISPLAY_WIDTH_IN_PIXELS: usize = 128;
+pub const SUPER_CHIP_DISPLAY_HEIGHT_IN_PIXELS: usize = 64;
+/// Big enough for the high resolution mode. In low resolution mode only the first
+/// CHIP_DISPLAY_WIDTH_IN_PIXELS * CHIP_DISPLAY_HEIGHT_IN_PIXELS pixels are used.
+pub const DISPLAY_BUFFER_SIZE: usize =
+    SUPER_CHIP_DISPLAY_WIDTH_IN_PIXELS * SUPER_CHIP_DISPLAY_HEIGHT_IN_PIXELS;
 const PROGRAM_OFFSET: usize = 0x200;
+pub const CHIP_MEMORY_SIZE_IN_BYTES: usize = 4096;
+pub const XO_CHIP_MEMORY_SIZE_IN_BYTES: usize = 65536;
+/// Each pixel in the display buffer is a bitmask of which planes are lit. Plain CHIP-8 and
+/// SUPER-CHIP only ever draw to the first plane, XO-CHIP can draw to both.
+pub const FIRST_PLANE: u8 = 0b01;
+pub const SECOND_PLANE: u8 = 0b10;
+/// Played when a ROM hasn't loaded its own audio pattern. 500Hz at the default pitch.
+const DEFAULT_AUDIO_PATTERN: [u8; 16] = [0xF0; 16];
+const DEFAULT_PITCH: u8 = 64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chip8 {
-    pub memory: [u8; 4096],
+    /// CHIP_MEMORY_SIZE_IN_BYTES long, or XO_CHIP_MEMORY_SIZE_IN_BYTES in XO-CHIP mode
+    pub memory: Vec<u8>,
     // uppermost 256 bytes (0xF00-0xFFF) potentially reserved for display refresh
     // 96 bytes down from that (0xEA0-0xEFF) is call stack and other internal usage stuff
     //
@@ -14,51 +35,370 @@ pub struct Chip8 {
     pub stack_pointer: u8,
     // Holds memory locations. Better name for this?
     pub i_register: u16,
-    pub display_buffer: [bool; CHIP_DISPLAY_WIDTH_IN_PIXELS * CHIP_DISPLAY_HEIGHT_IN_PIXELS],
+    /// Laid out in rows of display_width() pixels. See idx_for_display.
+    /// Each pixel is a bitmask of FIRST_PLANE and SECOND_PLANE.
+    pub display_buffer: [u8; DISPLAY_BUFFER_SIZE],
+    /// Which planes draws, clears and scrolls affect. Set by XO-CHIP's Fn01
+    pub selected_planes: u8,
+    /// SUPER-CHIP 128x64 mode, toggled by 00FE/00FF
+    pub high_resolution: bool,
     pub delay_timer: u8,
     pub sound_timer: u8,
+    /// Which keypad keys are down, as of the last key event applied. Change them with key_down
+    /// and key_up, not directly, or Fx0A won't see the edges.
     pub keys: [bool; 16],
+    /// Key events waiting for their cycle, oldest first. See queue_key_event
+    pub key_events: VecDeque<KeypadEvent>,
+    /// Instructions run so far. Key events are timestamped with it
+    pub cycles: u64,
     pub should_play_sound: bool,
+    pub quirks: Quirks,
+    /// SUPER-CHIP's "RPL user flags" from the HP-48, used by Fx75/Fx85
+    pub rpl_flags: [u8; 16],
+    /// Set by 00FD. Nothing else will execute once the interpreter has exited.
+    pub exited: bool,
+    /// XO-CHIP 1-bit audio samples, played from the most significant bit of the first byte
+    pub audio_pattern: [u8; 16],
+    /// XO-CHIP pitch register. See audio_playback_rate
+    pub pitch: u8,
+    /// fnv1a_hash of the ROM we were created with. Save states from other ROMs are rejected
+    pub rom_hash: u64,
+    /// Where Cxkk gets its random numbers from
+    pub rng: Rng,
+    /// How run_a_frame decides how many instructions to run
+    pub execution_model: ExecutionModel,
+    /// Where Fx0A is up to. See waiting_for_key
+    pub key_wait: KeyWait,
+}
+
+/// Fx0A's progress. The program counter stays on the Fx0A until it's done. Moved along by key
+/// events as they're applied, so a quick tap between two instructions still counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyWait {
+    NotWaiting,
+    /// Waiting for a key to go down. Keys that were already held when the Fx0A started don't
+    /// count, since they never go down while we're waiting.
+    ForPress,
+    /// key went down, and with the key_wait_on_release quirk we wait for it to come back up
+    ForRelease {
+        key: u8,
+    },
+    /// The Fx0A stores key in Vx and moves on next time it runs
+    Finished {
+        key: u8,
+    },
 }
 
-fn last_byte(val: u16) -> u8 {
-    (val & 0x00FF) as u8
+/// A keypad key going down or up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeypadEvent {
+    pub key: u8,
+    pub pressed: bool,
+    /// Applied just before this instruction (counting from Chip8::cycles) runs
+    pub cycle: u64,
 }
 
-fn first_byte(val: u16) -> u8 {
-    ((val & 0xFF00) >> 8) as u8
+/// The behaviours that differ between CHIP-8 interpreters over the years. ROMs written for one
+/// interpreter will often misbehave on another, so these let us pick which one we act like.
+/// See <https://chip8.gulrak.net/#quirk5> for a rundown of each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8xy6/8xyE shift Vy and store the result in Vx, instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    /// How far Fx55/Fx65 move I after storing/loading registers
+    pub load_store_i_increment: LoadStoreIncrement,
+    /// 8xy1/8xy2/8xy3 set VF to 0 after the logic op
+    pub logic_ops_reset_vf: bool,
+    /// Sprites drawn past the edge of the display are clipped instead of wrapping around to the
+    /// other side. The starting position of the sprite always wraps.
+    pub clip_sprites: bool,
+    /// Bnnn jumps to xnn + Vx instead of nnn + V0 (Bxnn)
+    pub jump_with_offset_uses_vx: bool,
+    /// DRW waits for the next vertical blank, so only one sprite can be drawn per frame
+    pub display_wait: bool,
+    /// Fx0A finishes when the key is let go, like the VIP, instead of as soon as it's pressed
+    pub key_wait_on_release: bool,
+    /// Not strictly a quirk, but enables the XO-CHIP extensions: 64KB of memory, F000 nnnn,
+    /// 5xy2/5xy3, Fn01 plane selection, F002 audio patterns and Fx3A pitch
+    pub xo_chip: bool,
 }
 
-fn first_nibble(val: u8) -> u8 {
-    (val & 0xF0) >> 4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadStoreIncrement {
+    /// I is left alone (SUPER-CHIP 1.1)
+    Unchanged,
+    /// I = I + x (CHIP-48)
+    X,
+    /// I = I + x + 1 (COSMAC VIP)
+    XPlusOne,
+}
+
+impl Quirks {
+    /// The original CHIP-8 interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Self {
+        return Quirks {
+            shift_uses_vy: true,
+            load_store_i_increment: LoadStoreIncrement::XPlusOne,
+            logic_ops_reset_vf: true,
+            clip_sprites: true,
+            jump_with_offset_uses_vx: false,
+            display_wait: true,
+            key_wait_on_release: true,
+            xo_chip: false,
+        };
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Self {
+        return Quirks {
+            shift_uses_vy: false,
+            load_store_i_increment: LoadStoreIncrement::X,
+            logic_ops_reset_vf: false,
+            clip_sprites: true,
+            jump_with_offset_uses_vx: true,
+            display_wait: false,
+            key_wait_on_release: true,
+            xo_chip: false,
+        };
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn superchip() -> Self {
+        return Quirks {
+            shift_uses_vy: false,
+            load_store_i_increment: LoadStoreIncrement::Unchanged,
+            logic_ops_reset_vf: false,
+            clip_sprites: true,
+            jump_with_offset_uses_vx: true,
+            display_wait: false,
+            key_wait_on_release: true,
+            xo_chip: false,
+        };
+    }
+
+    /// XO-CHIP, as implemented by Octo
+    pub fn xo_chip() -> Self {
+        return Quirks {
+            shift_uses_vy: true,
+            load_store_i_increment: LoadStoreIncrement::XPlusOne,
+            logic_ops_reset_vf: false,
+            clip_sprites: false,
+            jump_with_offset_uses_vx: false,
+            display_wait: false,
+            key_wait_on_release: true,
+            xo_chip: true,
+        };
+    }
+
+    /// Looks up a preset by the name used on the command line/config files
+    pub fn from_preset_name(name: &str) -> Option<Self> {
+        return match name {
+            "vip" | "chip8" | "cosmac-vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" | "superchip" => Some(Quirks::superchip()),
+            "xochip" | "xo-chip" => Some(Quirks::xo_chip()),
+            _ => None,
+        };
+    }
+
+    /// The opposite of from_preset_name, for quirks that match a preset exactly
+    pub fn preset_name(&self) -> Option<&'static str> {
+        return ["vip", "chip48", "schip", "xochip"]
+            .into_iter()
+            .find(|name| Quirks::from_preset_name(name) == Some(*self));
+    }
 }
 
-fn last_nibble(val: u8) -> u8 {
-    val & 0x0F
+/// Something went wrong executing the ROM. The program counter is left pointing at the
+/// instruction that caused it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip8Error {
+    InvalidOpcode {
+        opcode: u16,
+        program_counter: usize,
+    },
+    /// CALL with no room left on the stack
+    StackOverflow {
+        opcode: u16,
+        program_counter: usize,
+    },
+    /// RET with nothing on the stack to return to
+    StackUnderflow {
+        opcode: u16,
+        program_counter: usize,
+    },
+    /// An instruction tried to read or write past the end of memory
+    MemoryOutOfRange {
+        opcode: u16,
+        program_counter: usize,
+        address: usize,
+    },
+    /// The program counter ran off the end of memory, so there was no opcode to read
+    ProgramCounterOutOfRange {
+        program_counter: usize,
+    },
+}
+
+impl Chip8Error {
+    pub fn program_counter(&self) -> usize {
+        return match *self {
+            Chip8Error::InvalidOpcode {
+                program_counter, ..
+            }
+            | Chip8Error::StackOverflow {
+                program_counter, ..
+            }
+            | Chip8Error::StackUnderflow {
+                program_counter, ..
+            }
+            | Chip8Error::MemoryOutOfRange {
+                program_counter, ..
+            }
+            | Chip8Error::ProgramCounterOutOfRange { program_counter } => program_counter,
+        };
+    }
+
+    pub fn opcode(&self) -> Option<u16> {
+        return match *self {
+            Chip8Error::InvalidOpcode { opcode, .. }
+            | Chip8Error::StackOverflow { opcode, .. }
+            | Chip8Error::StackUnderflow { opcode, .. }
+            | Chip8Error::MemoryOutOfRange { opcode, .. } => Some(opcode),
+            Chip8Error::ProgramCounterOutOfRange { .. } => None,
+        };
+    }
+}
+
+impl std::fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Chip8Error::InvalidOpcode { .. } => write!(f, "Invalid opcode")?,
+            Chip8Error::StackOverflow { .. } => write!(f, "Stack overflow")?,
+            Chip8Error::StackUnderflow { .. } => write!(f, "Stack underflow")?,
+            Chip8Error::MemoryOutOfRange { address, .. } => {
+                write!(f, "Memory address 0x{:X} out of range", address)?
+            }
+            Chip8Error::ProgramCounterOutOfRange { .. } => {
+                write!(f, "Program counter out of range")?
+            }
+        }
+        if let Some(opcode) = self.opcode() {
+            write!(f, ", opcode: {:04X}", opcode)?;
+        }
+        return write!(f, ", PC: {:X}", self.program_counter());
+    }
+}
+
+impl std::error::Error for Chip8Error {}
+
+/// FNV-1a hash. Stable between runs and builds, unlike std's hashers, so it's safe to write to
+/// disk or compare against in tests
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+/// Vx to Vy inclusive, counting down if x > y
+fn register_range(x: u8, y: u8) -> Vec<u8> {
+    if x <= y {
+        return (x..=y).collect();
+    }
+    return (y..=x).rev().collect();
 }
 
 type TimeTakenInMicroSeconds = u32;
 
+/// How long the VIP's 60Hz frame is, in the same rough microseconds as the instruction timings
+pub const FRAME_TIME_IN_MICROSECONDS: u32 = 16_000;
+
+/// How many instructions make up a frame. Can be changed while running, and is part of the save
+/// state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionModel {
+    /// Instructions take roughly as long as they did on the COSMAC VIP. See process_a_frame
+    VipTiming,
+    /// See process_a_frame_of_instructions
+    InstructionsPerFrame(u32),
+    /// As many instructions as fit in a real 60Hz frame, for benchmarking. Unlike the others, how
+    /// far a frame gets depends on the machine, so runs aren't repeatable.
+    /// See process_a_frame_unlimited
+    Unlimited,
+}
+
+impl ExecutionModel {
+    /// Cycles VipTiming -> InstructionsPerFrame -> Unlimited -> VipTiming, so one key can switch
+    /// between them
+    pub fn next(self, instructions_per_frame: u32) -> Self {
+        return match self {
+            ExecutionModel::VipTiming => {
+                ExecutionModel::InstructionsPerFrame(instructions_per_frame)
+            }
+            ExecutionModel::InstructionsPerFrame(_) => ExecutionModel::Unlimited,
+            ExecutionModel::Unlimited => ExecutionModel::VipTiming,
+        };
+    }
+}
+
+/// A common choice for ROMs that expect a fixed number of instructions per frame
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 15;
+
+/// How often process_a_frame_unlimited checks the clock
+const UNLIMITED_INSTRUCTIONS_BETWEEN_CLOCK_CHECKS: u32 = 1000;
+
 impl Chip8 {
-    pub fn new(rom: &[u8]) -> Self {
+    pub fn new(rom: &[u8], quirks: Quirks) -> Self {
+        return Chip8::with_rng(rom, quirks, Rng::from_entropy());
+    }
+
+    /// Same as new, but Cxkk's random numbers come from the given seed, so every run with the same
+    /// input plays out the same way
+    pub fn with_seed(rom: &[u8], quirks: Quirks, seed: u64) -> Self {
+        return Chip8::with_rng(rom, quirks, Rng::new(seed));
+    }
+
+    fn with_rng(rom: &[u8], quirks: Quirks, rng: Rng) -> Self {
         let mut chip = Chip8 {
-            memory: [0; 4096],
+            memory: if quirks.xo_chip {
+                vec![0; XO_CHIP_MEMORY_SIZE_IN_BYTES]
+            } else {
+                vec![0; CHIP_MEMORY_SIZE_IN_BYTES]
+            },
             data_registers: [0; 16],
             program_counter: PROGRAM_OFFSET,
             i_register: 0,
-            display_buffer: [false; CHIP_DISPLAY_WIDTH_IN_PIXELS * CHIP_DISPLAY_HEIGHT_IN_PIXELS],
+            display_buffer: [0; DISPLAY_BUFFER_SIZE],
+            selected_planes: FIRST_PLANE,
+            high_resolution: false,
             stack_pointer: 0,
             stack: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
             keys: [false; 16],
+            key_events: VecDeque::new(),
+            cycles: 0,
             should_play_sound: false,
+            quirks,
+            rpl_flags: [0; 16],
+            exited: false,
+            audio_pattern: DEFAULT_AUDIO_PATTERN,
+            pitch: DEFAULT_PITCH,
+            rom_hash: fnv1a_hash(rom),
+            rng,
+            execution_model: ExecutionModel::VipTiming,
+            key_wait: KeyWait::NotWaiting,
         };
 
         // Fonts sit at the start of memory
         for (i, byte) in FONT_SPRITES.iter().enumerate() {
             chip.memory[i + FONT_START_LOCATION] = *byte;
         }
+        for (i, byte) in BIG_FONT_SPRITES.iter().enumerate() {
+            chip.memory[i + BIG_FONT_START_LOCATION] = *byte;
+        }
 
         for (i, byte) in rom.iter().enumerate() {
             chip.memory[PROGRAM_OFFSET + i] = *byte;
@@ -80,12 +420,96 @@ impl Chip8 {
         }
     }
 
+    /// True while stuck on an Fx0A waiting for a key
+    pub fn waiting_for_key(&self) -> bool {
+        return self.key_wait != KeyWait::NotWaiting;
+    }
+
+    /// Presses a keypad key (0 to F) as of the next instruction
+    pub fn key_down(&mut self, key: u8) {
+        self.queue_key_event(KeypadEvent {
+            key,
+            pressed: true,
+            cycle: self.cycles,
+        });
+    }
+
+    /// Lets go of a keypad key (0 to F) as of the next instruction
+    pub fn key_up(&mut self, key: u8) {
+        self.queue_key_event(KeypadEvent {
+            key,
+            pressed: false,
+            cycle: self.cycles,
+        });
+    }
+
+    /// Queues a key event for the given cycle, which can be in the future for replaying input.
+    /// Events for the same cycle are applied in the order they were queued.
+    pub fn queue_key_event(&mut self, event: KeypadEvent) {
+        let event = KeypadEvent {
+            key: event.key & 0xF,
+            ..event
+        };
+        let idx = self
+            .key_events
+            .iter()
+            .position(|queued| queued.cycle > event.cycle)
+            .unwrap_or(self.key_events.len());
+        self.key_events.insert(idx, event);
+    }
+
+    /// Queues whatever key_down and key_up calls are needed for exactly these keys to be held,
+    /// once everything already queued has been applied
+    pub fn set_keys(&mut self, keys: [bool; 16]) {
+        let mut eventual_keys = self.keys;
+        for event in &self.key_events {
+            eventual_keys[event.key as usize] = event.pressed;
+        }
+        for key in 0..16u8 {
+            if keys[key as usize] && !eventual_keys[key as usize] {
+                self.key_down(key);
+            } else if !keys[key as usize] && eventual_keys[key as usize] {
+                self.key_up(key);
+            }
+        }
+    }
+
+    /// Applies the key events that are due by this cycle. A key only changes once per cycle, so
+    /// a press and release queued together are both seen by at least one instruction, with the
+    /// release left for the next cycle.
+    fn apply_due_key_events(&mut self) {
+        let mut changed = [false; 16];
+        while let Some(&event) = self.key_events.front() {
+            if event.cycle > self.cycles || changed[event.key as usize] {
+                break;
+            }
+            self.key_events.pop_front();
+            // e.g key repeats from the frontend, which aren't edges
+            if self.keys[event.key as usize] == event.pressed {
+                continue;
+            }
+            changed[event.key as usize] = true;
+            self.keys[event.key as usize] = event.pressed;
+
+            self.key_wait = match self.key_wait {
+                KeyWait::ForPress if event.pressed && self.quirks.key_wait_on_release => {
+                    KeyWait::ForRelease { key: event.key }
+                }
+                KeyWait::ForPress if event.pressed => KeyWait::Finished { key: event.key },
+                KeyWait::ForRelease { key } if key == event.key && !event.pressed => {
+                    KeyWait::Finished { key }
+                }
+                key_wait => key_wait,
+            };
+        }
+    }
+
     pub fn should_play_sound(&self) -> bool {
         return self.should_play_sound;
     }
 
-    pub fn process_a_frame(&mut self, keys: [bool; 16], processing_time_target: u32) {
-        let mut elapsed_time = 0;
+    /// Decrements the 60Hz timers, once per frame
+    fn update_timers(&mut self) {
         if self.delay_timer != 0 {
             self.delay_timer -= 1;
         }
@@ -95,170 +519,358 @@ impl Chip8 {
         } else {
             self.should_play_sound = false;
         }
+    }
+
+    pub fn process_a_frame(&mut self, processing_time_target: u32) -> Result<(), Chip8Error> {
+        let mut elapsed_time = 0;
+        self.update_timers();
 
         while elapsed_time < processing_time_target {
-            let processing_time = self.process_next_instruction(keys);
+            let (instruction, processing_time) = self.step()?;
             // This max check seems fine for now instead of returning an Option or something
             if processing_time == TimeTakenInMicroSeconds::MAX {
                 break;
             }
             elapsed_time += processing_time;
+            if self.waits_for_vblank(instruction) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    /// With the display_wait quirk, the VIP waits for the vertical blank interrupt before drawing,
+    /// so nothing else runs until the next frame. This is what limits games to 60 sprites a second
+    /// and keeps the flicker down.
+    fn waits_for_vblank(&self, instruction: Instruction) -> bool {
+        return matches!(instruction, Instruction::Drw(..)) && self.quirks.display_wait;
+    }
+
+    /// Runs a 60Hz frame's worth of instructions, using the current execution model
+    pub fn run_a_frame(&mut self) -> Result<(), Chip8Error> {
+        return match self.execution_model {
+            ExecutionModel::VipTiming => self.process_a_frame(FRAME_TIME_IN_MICROSECONDS),
+            ExecutionModel::InstructionsPerFrame(instructions_per_frame) => {
+                self.process_a_frame_of_instructions(instructions_per_frame)
+            }
+            ExecutionModel::Unlimited => self
+                .process_a_frame_unlimited(std::time::Duration::from_micros(
+                    FRAME_TIME_IN_MICROSECONDS as u64,
+                ))
+                .map(|_| ()),
+        };
+    }
+
+    /// Same as process_a_frame, but runs a fixed number of instructions instead of estimating how
+    /// long each one takes. Most ROMs written after the VIP expect this.
+    pub fn process_a_frame_of_instructions(
+        &mut self,
+        instructions_per_frame: u32,
+    ) -> Result<(), Chip8Error> {
+        self.update_timers();
+
+        for _ in 0..instructions_per_frame {
+            let (instruction, processing_time) = self.step()?;
+            if processing_time == TimeTakenInMicroSeconds::MAX {
+                break;
+            }
+            if self.waits_for_vblank(instruction) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Same as process_a_frame, but keeps running instructions until the given amount of real time
+    /// has passed. Returns how many instructions were run.
+    pub fn process_a_frame_unlimited(
+        &mut self,
+        time_budget: std::time::Duration,
+    ) -> Result<u64, Chip8Error> {
+        let start = std::time::Instant::now();
+        self.update_timers();
+
+        let mut instructions_run = 0;
+        loop {
+            for _ in 0..UNLIMITED_INSTRUCTIONS_BETWEEN_CLOCK_CHECKS {
+                let (instruction, processing_time) = self.step()?;
+                instructions_run += 1;
+                if processing_time == TimeTakenInMicroSeconds::MAX {
+                    return Ok(instructions_run);
+                }
+                if self.waits_for_vblank(instruction) {
+                    return Ok(instructions_run);
+                }
+            }
+            if start.elapsed() >= time_budget {
+                return Ok(instructions_run);
+            }
         }
     }
 
     /// Performs the next instruction at the current program counter.
+    /// Errors if the instruction can't be executed, leaving the program counter on it.
     /// Returns the AVERAGE micro seconds taken to execute that instruction (Does not accurately
     /// emulate timings. See <https://jackson-s.me/2019/07/13/Chip-8-Instruction-Scheduling-and-Frequency.html>)
-    pub fn process_next_instruction(&mut self, keys: [bool; 16]) -> TimeTakenInMicroSeconds {
-        // Opcodes and most documentation taken from http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#3.1
-        self.keys = keys;
-        let opcode: u16 = (self.memory[self.program_counter] as u16) << 8
-            | self.memory[self.program_counter + 1] as u16;
-
-        let first_nibble_first_byte = first_nibble(first_byte(opcode));
-        let second_nibble_first_byte = last_nibble(first_byte(opcode));
+    pub fn process_next_instruction(&mut self) -> Result<TimeTakenInMicroSeconds, Chip8Error> {
+        let (_, processing_time) = self.step()?;
+        return Ok(processing_time);
+    }
+
+    /// Applies any key events that are due, then fetches and executes the next instruction
+    fn step(&mut self) -> Result<(Instruction, TimeTakenInMicroSeconds), Chip8Error> {
+        self.apply_due_key_events();
+        let instruction = self.fetch_instruction()?;
+        let processing_time = self.execute(instruction)?;
+        self.cycles += 1;
+        return Ok((instruction, processing_time));
+    }
+
+    /// Decodes the instruction at the current program counter
+    pub fn fetch_instruction(&self) -> Result<Instruction, Chip8Error> {
+        if self.program_counter + 1 >= self.memory.len() {
+            return Err(Chip8Error::ProgramCounterOutOfRange {
+                program_counter: self.program_counter,
+            });
+        }
+        // Enough for the longest instruction, if there's that much memory left
+        let bytes =
+            &self.memory[self.program_counter..(self.program_counter + 4).min(self.memory.len())];
         // println!("PC: 0x{:X}, op: 0x{:X}", self.program_counter, opcode);
-        let x_register = last_nibble(first_byte(opcode));
-        let x = self.data_registers[x_register as usize];
-        let y_register = first_nibble(last_byte(opcode));
-        let y = self.data_registers[y_register as usize];
-
-        match first_nibble_first_byte {
-            0x0 => match last_byte(opcode) {
-                0xE0 => {
-                    // 00E0 - CLS
-                    // Clear the display
-                    self.display_buffer =
-                        [false; CHIP_DISPLAY_WIDTH_IN_PIXELS * CHIP_DISPLAY_HEIGHT_IN_PIXELS];
-                    self.increment_pc();
-                    return 109;
-                }
-                0xEE => {
-                    // 00EE - RET
-                    // Return from a subroutine.
-                    self.program_counter = self.stack[self.stack_pointer as usize] as usize;
-                    self.stack_pointer -= 1;
-                    self.increment_pc();
-                    return 105;
+
+        return Instruction::decode_bytes(bytes)
+            .map_err(|InvalidOpcode(opcode)| self.invalid_opcode(opcode));
+    }
+
+    /// Executes the instruction as if it was the one at the current program counter.
+    /// See process_next_instruction for what's returned.
+    pub fn execute(
+        &mut self,
+        instruction: Instruction,
+    ) -> Result<TimeTakenInMicroSeconds, Chip8Error> {
+        let opcode = instruction.opcode();
+        if instruction.is_xo_chip_only() && !self.quirks.xo_chip {
+            return Err(self.invalid_opcode(opcode));
+        }
+
+        match instruction {
+            Instruction::Sys(_) => {
+                // 0nnn - SYS addr
+                // Jump to a machine code routine at nnn. Ignored by modern interpreters, and we
+                // have no machine code to run, so treat it as something we can't execute.
+                return Err(self.invalid_opcode(opcode));
+            }
+            Instruction::Cls => {
+                // 00E0 - CLS
+                // Clear the display (only the selected planes in XO-CHIP)
+                for pixel in self.display_buffer.iter_mut() {
+                    *pixel &= !self.selected_planes;
                 }
-                _ => {
-                    unimplemented_opcode(
+                self.increment_pc();
+                return Ok(109);
+            }
+            Instruction::ScrollDown(n) => {
+                // 00Cn - SCD nibble (SUPER-CHIP)
+                // Scroll the display down n pixels.
+                self.scroll_display(0, n as isize);
+                self.increment_pc();
+                return Ok(109);
+            }
+            Instruction::ScrollRight => {
+                // 00FB - SCR (SUPER-CHIP)
+                // Scroll the display right 4 pixels.
+                self.scroll_display(4, 0);
+                self.increment_pc();
+                return Ok(109);
+            }
+            Instruction::ScrollLeft => {
+                // 00FC - SCL (SUPER-CHIP)
+                // Scroll the display left 4 pixels.
+                self.scroll_display(-4, 0);
+                self.increment_pc();
+                return Ok(109);
+            }
+            Instruction::Exit => {
+                // 00FD - EXIT (SUPER-CHIP)
+                // Exit the interpreter.
+                // The program counter stays put so nothing else runs, same as waiting for a
+                // key press.
+                self.exited = true;
+                return Ok(TimeTakenInMicroSeconds::MAX);
+            }
+            Instruction::LowRes => {
+                // 00FE - LOW (SUPER-CHIP)
+                // Switch to the 64x32 display.
+                self.high_resolution = false;
+                self.display_buffer = [0; DISPLAY_BUFFER_SIZE];
+                self.increment_pc();
+                return Ok(109);
+            }
+            Instruction::HighRes => {
+                // 00FF - HIGH (SUPER-CHIP)
+                // Switch to the 128x64 display.
+                self.high_resolution = true;
+                self.display_buffer = [0; DISPLAY_BUFFER_SIZE];
+                self.increment_pc();
+                return Ok(109);
+            }
+            Instruction::Ret => {
+                // 00EE - RET
+                // Return from a subroutine.
+                if self.stack_pointer == 0 {
+                    return Err(Chip8Error::StackUnderflow {
                         opcode,
-                        first_nibble_first_byte,
-                        second_nibble_first_byte,
-                        self.program_counter,
-                    );
+                        program_counter: self.program_counter,
+                    });
                 }
-            },
-            0x1 => {
+                self.program_counter = self.stack[self.stack_pointer as usize] as usize;
+                self.stack_pointer -= 1;
+                self.increment_pc();
+                return Ok(105);
+            }
+            Instruction::Jp(address_to_jump) => {
                 // 1nnn - Jump (JP) addr
-                let address_to_jump = opcode & 0x0FFF;
                 self.program_counter = address_to_jump as usize;
-                return 105;
+                return Ok(105);
             }
-            0x2 => {
+            Instruction::Call(address) => {
                 // 2nnn - CALL addr
                 // Call subroutine at nnn.
 
                 // Yes, we're missing out the first stack place always, but that is the specified
                 // behaviour
+                if self.stack_pointer as usize + 1 >= self.stack.len() {
+                    return Err(Chip8Error::StackOverflow {
+                        opcode,
+                        program_counter: self.program_counter,
+                    });
+                }
                 self.stack_pointer += 1;
                 self.stack[self.stack_pointer as usize] = self.program_counter as u16;
-                self.program_counter = (opcode & 0x0FFF) as usize;
-                return 105;
+                self.program_counter = address as usize;
+                return Ok(105);
             }
-            0x3 => {
+            Instruction::SeVxByte(register, val_to_compare) => {
                 // 3xkk - Skip Equal (SE) Vx, byte
                 // Skip next instruction if Vx = kk.
-                let register = second_nibble_first_byte;
-                let val_to_compare = last_byte(opcode);
-
                 self.increment_pc();
 
                 if self.data_registers[register as usize] == val_to_compare {
-                    self.increment_pc();
+                    self.skip_next_instruction();
                 }
-                return 55;
+                return Ok(55);
             }
-            0x4 => {
+            Instruction::SneVxByte(register, val_to_compare) => {
                 // 4xkk - Skip Not Equal (SNE) Vx, byte
                 // Skip next instruction if Vx != kk.
-                let register = second_nibble_first_byte;
-                let val_to_compare = last_byte(opcode);
-
                 self.increment_pc();
 
                 if self.data_registers[register as usize] != val_to_compare {
-                    self.increment_pc();
+                    self.skip_next_instruction();
                 }
-                return 55;
+                return Ok(55);
             }
-            0x5 => {
+            Instruction::SeVxVy(x_register, y_register) => {
                 // 5xy0 - Skip Equal (SE) Vx, Vy
                 // Skip next instruction if Vx = Vy.
                 self.increment_pc();
 
-                if x == y {
-                    self.increment_pc();
+                if self.data_registers[x_register as usize]
+                    == self.data_registers[y_register as usize]
+                {
+                    self.skip_next_instruction();
                 }
-                return 73;
+                return Ok(73);
             }
-            0x6 => {
+            Instruction::LdIVxVy(x_register, y_register) => {
+                // 5xy2 - LD [I], Vx - Vy (XO-CHIP)
+                // Store registers Vx through Vy in memory starting at location I. I is not
+                // changed. Stored in reverse order if x > y.
+                let registers = register_range(x_register, y_register);
+                self.check_memory_range(opcode, self.i_register as usize, registers.len())?;
+                for (i, reg) in registers.iter().enumerate() {
+                    self.memory[self.i_register as usize + i] = self.data_registers[*reg as usize];
+                }
+                self.increment_pc();
+                return Ok(605);
+            }
+            Instruction::LdVxVyI(x_register, y_register) => {
+                // 5xy3 - LD Vx - Vy, [I] (XO-CHIP)
+                // Read registers Vx through Vy from memory starting at location I. I is not
+                // changed. Read in reverse order if x > y.
+                let registers = register_range(x_register, y_register);
+                self.check_memory_range(opcode, self.i_register as usize, registers.len())?;
+                for (i, reg) in registers.iter().enumerate() {
+                    self.data_registers[*reg as usize] = self.memory[self.i_register as usize + i];
+                }
+                self.increment_pc();
+                return Ok(605);
+            }
+            Instruction::LdVxByte(register, val_to_load) => {
                 // 6xkk - Load (LD) Vx, byte
                 // Set Vx = kk.
-                let register = second_nibble_first_byte;
-                let val_to_load = last_byte(opcode);
                 self.data_registers[register as usize] = val_to_load;
                 self.increment_pc();
-                return 27;
+                return Ok(27);
             }
-            0x7 => {
+            Instruction::AddVxByte(register, val_to_add) => {
                 // 7xkk - ADD Vx, byte
                 // Set Vx = Vx + kk.
-                let register = second_nibble_first_byte;
                 let register_val = self.data_registers[register as usize];
-                let val_to_add = last_byte(opcode);
                 self.data_registers[register as usize] = val_to_add.wrapping_add(register_val);
                 self.increment_pc();
-                return 45;
+                return Ok(45);
             }
-            0x8 => {
+            Instruction::LdVxVy(x_register, y_register)
+            | Instruction::Or(x_register, y_register)
+            | Instruction::And(x_register, y_register)
+            | Instruction::Xor(x_register, y_register)
+            | Instruction::AddVxVy(x_register, y_register)
+            | Instruction::Sub(x_register, y_register)
+            | Instruction::Shr(x_register, y_register)
+            | Instruction::Subn(x_register, y_register)
+            | Instruction::Shl(x_register, y_register) => {
                 // Always gonna use register_x and register_y here
-                match last_nibble(last_byte(opcode)) {
-                    0x0 => {
+                let x = self.data_registers[x_register as usize];
+                let y = self.data_registers[y_register as usize];
+                match instruction {
+                    Instruction::LdVxVy(..) => {
                         // 8xy0 - LD Vx, Vy
                         // Set Vx = Vy.
-                        self.data_registers[x_register as usize] =
-                            self.data_registers[y_register as usize];
-                        self.increment_pc();
+                        self.data_registers[x_register as usize] = y;
                     }
-                    0x1 => {
+                    Instruction::Or(..) => {
                         // 8xy1 - OR Vx, Vy
                         // Set Vx = Vx OR Vy.
                         self.data_registers[x_register as usize] = x | y;
-                        self.increment_pc();
+                        if self.quirks.logic_ops_reset_vf {
+                            self.data_registers[0xF] = 0;
+                        }
                     }
-                    0x2 => {
+                    Instruction::And(..) => {
                         // 8xy2 - AND Vx, Vy
                         // Set Vx = Vx AND Vy.
                         self.data_registers[x_register as usize] = x & y;
-                        self.increment_pc();
+                        if self.quirks.logic_ops_reset_vf {
+                            self.data_registers[0xF] = 0;
+                        }
                     }
-                    0x3 => {
+                    Instruction::Xor(..) => {
                         // 8xy3 - XOR Vx, Vy
                         // Set Vx = Vx XOR Vy.
                         self.data_registers[x_register as usize] = x ^ y;
-                        self.increment_pc();
+                        if self.quirks.logic_ops_reset_vf {
+                            self.data_registers[0xF] = 0;
+                        }
                     }
-                    0x4 => {
+                    Instruction::AddVxVy(..) => {
                         // 8xy4 - ADD Vx, Vy
                         // Set Vx = Vx + Vy, set VF = carry.
                         let (new_val, overflow_happened) = x.overflowing_add(y);
                         self.data_registers[x_register as usize] = new_val;
                         self.data_registers[0xF] = overflow_happened as u8;
-                        self.increment_pc();
                     }
-                    0x5 => {
+                    Instruction::Sub(..) => {
                         // 8xy5 - SUB Vx, Vy
                         // Set Vx = Vx - Vy, set VF = NOT borrow. (VF = Vx > Vy)
                         let new_val = x.wrapping_sub(y);
@@ -268,17 +880,16 @@ impl Chip8 {
                         } else {
                             self.data_registers[0xF] = 0;
                         }
-                        self.increment_pc();
                     }
-                    0x6 => {
+                    Instruction::Shr(..) => {
                         // 8xy6 - SHR Vx {, Vy}
                         // Set Vx = Vx SHR 1.
                         // VF is set if LSB is set on Vx
-                        self.data_registers[x_register as usize] = x >> 1;
-                        self.data_registers[0xF] = x & 0x1;
-                        self.increment_pc();
+                        let to_shift = if self.quirks.shift_uses_vy { y } else { x };
+                        self.data_registers[x_register as usize] = to_shift >> 1;
+                        self.data_registers[0xF] = to_shift & 0x1;
                     }
-                    0x7 => {
+                    Instruction::Subn(..) => {
                         // 8xy7 - SUBN Vx, Vy
                         // Set Vx = Vy - Vx, set VF = NOT borrow. (VF = Vx < Vy)
                         let new_val = y.wrapping_sub(x);
@@ -288,84 +899,120 @@ impl Chip8 {
                         } else {
                             self.data_registers[0xF] = 0;
                         }
-                        self.increment_pc();
                     }
-                    0xE => {
+                    Instruction::Shl(..) => {
                         // 8xyE - SHL Vx {, Vy}
                         // Set Vx = Vx SHL 1.
                         // VF is set if MSB is set on Vx
-                        self.data_registers[x_register as usize] = x << 1;
-                        self.data_registers[0xF] = x >> 7;
-                        self.increment_pc();
+                        let to_shift = if self.quirks.shift_uses_vy { y } else { x };
+                        self.data_registers[x_register as usize] = to_shift << 1;
+                        self.data_registers[0xF] = to_shift >> 7;
                     }
-                    _ => unimplemented_opcode(
-                        opcode,
-                        first_nibble_first_byte,
-                        second_nibble_first_byte,
-                        self.program_counter,
-                    ),
+                    _ => unreachable!(),
                 }
+                self.increment_pc();
                 // A hard 200 microseconds for all 0x8xxx opcodes, handy!
-                return 200;
+                return Ok(200);
             }
-            0x9 => {
+            Instruction::SneVxVy(x_register, y_register) => {
                 // 9xy0 - Skip Not Equal (SNE) Vx, Vy
                 // Skip next instruction if Vx != Vy.
 
                 self.increment_pc();
 
-                if x != y {
-                    self.increment_pc();
+                if self.data_registers[x_register as usize]
+                    != self.data_registers[y_register as usize]
+                {
+                    self.skip_next_instruction();
                 }
-                return 73;
+                return Ok(73);
             }
-            0xA => {
+            Instruction::LdIAddr(val_to_load) => {
                 // Annn - Load (LD) I, addr
                 // Set I = nnn.
-                let val_to_load = opcode & 0x0FFF;
                 self.i_register = val_to_load;
                 self.increment_pc();
-                return 55;
+                return Ok(55);
+            }
+            Instruction::JpV0(address) => {
+                // Bnnn - JP V0, addr
+                // Jump to location nnn + V0.
+                // With the jump_with_offset_uses_vx quirk this is Bxnn instead, jumping to
+                // xnn + Vx.
+                let offset = if self.quirks.jump_with_offset_uses_vx {
+                    self.data_registers[(address >> 8) as usize]
+                } else {
+                    self.data_registers[0]
+                };
+                self.program_counter = address as usize + offset as usize;
+                return Ok(105);
             }
-            // 0xB - Timing of 105 microseconds
-            0xC => {
+            Instruction::Rnd(x_register, val_to_and) => {
                 // Cxkk - RND Vx, byte
                 // Set Vx = random byte AND kk.
-                let val_to_and = last_byte(opcode);
-                let rand_val: u8 = rand::random();
+                let rand_val = self.rng.next_u8();
                 self.data_registers[x_register as usize] = rand_val & val_to_and;
                 self.increment_pc();
-                return 164;
+                return Ok(164);
             }
-            0xD => {
+            Instruction::Drw(x_register, y_register, n_bytes) => {
                 // Draw (DRW) Vx, Vy, nibble
                 // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
                 // Sprites are XOR'd onto the screen.
                 // If ANY pixel set to 0 due to the XOR, then a collision has happened.
-                let n_bytes = last_nibble(last_byte(opcode));
+                // Dxy0 (SUPER-CHIP) draws a 16x16 sprite instead, 2 bytes per row.
+                // When XO-CHIP has both planes selected, the second plane's sprite follows straight
+                // after the first's.
+                let x = self.data_registers[x_register as usize];
+                let y = self.data_registers[y_register as usize];
+                let (sprite_width, sprite_height) = if n_bytes == 0 {
+                    (16, 16)
+                } else {
+                    (8, n_bytes as usize)
+                };
+                let bytes_per_row = sprite_width / 8;
+                let sprite_length = sprite_height * bytes_per_row;
+                let planes: Vec<u8> = [FIRST_PLANE, SECOND_PLANE]
+                    .into_iter()
+                    .filter(|plane| self.selected_planes & plane != 0)
+                    .collect();
 
-                // Read n bytes from memory at position I
+                // Read the sprite from memory at position I
                 let memory_location = self.i_register as usize;
-                let bytes_to_draw =
-                    &self.memory[memory_location..(memory_location + n_bytes as usize)];
+                self.check_memory_range(opcode, memory_location, sprite_length * planes.len())?;
 
                 // Display those bytes as sprites at Vx, Vy
                 // Sprites should be XOR'd into the display buffer
                 let mut was_collision = false;
 
-                // TODO(reece) The collision logic with wrap
-                for (i, byte) in bytes_to_draw.iter().enumerate() {
-                    let byte = *byte;
-                    for bit_position in 0..8 {
-                        let bit_is_set = ((byte >> 7 - bit_position) & 0x1) > 0;
-
-                        if Chip8::set_pixel(
-                            self.display_buffer.as_mut_slice(),
-                            (x + bit_position) as usize,
-                            y as usize + i,
-                            bit_is_set,
-                        ) {
-                            was_collision = true;
+                // The starting position always wraps, regardless of clipping
+                let start_x = x as usize % self.display_width();
+                let start_y = y as usize % self.display_height();
+
+                for (plane_idx, plane) in planes.into_iter().enumerate() {
+                    let sprite_location = memory_location + plane_idx * sprite_length;
+                    for row in 0..sprite_height {
+                        let pixel_y = start_y + row;
+                        if self.quirks.clip_sprites && pixel_y >= self.display_height() {
+                            break;
+                        }
+                        // Left align the row so 8 and 16 pixel wide sprites read the same way
+                        let row_location = sprite_location + row * bytes_per_row;
+                        let mut row_bits = (self.memory[row_location] as u16) << 8;
+                        if bytes_per_row == 2 {
+                            row_bits |= self.memory[row_location + 1] as u16;
+                        }
+
+                        for bit_position in 0..sprite_width {
+                            let bit_is_set = ((row_bits >> (15 - bit_position)) & 0x1) > 0;
+                            let pixel_x = start_x + bit_position;
+                            if self.quirks.clip_sprites && pixel_x >= self.display_width() {
+                                break;
+                            }
+
+                            if self.set_pixel(pixel_x, pixel_y, plane, bit_is_set) {
+                                was_collision = true;
+                            }
                         }
                     }
                 }
@@ -376,193 +1023,321 @@ impl Chip8 {
                 }
 
                 self.increment_pc();
-                return 22734;
+                return Ok(22734);
             }
-            0xE => match last_byte(opcode) {
-                0x9E => {
-                    // Ex9E - SKP Vx
-                    // Skip next instruction if key with the value of Vx is pressed.
-                    let key_value = self.data_registers[x_register as usize];
-                    if self.is_key_pressed(key_value) {
-                        self.increment_pc();
-                    }
-                    self.increment_pc();
-                    return 73;
+            Instruction::Skp(x_register) => {
+                // Ex9E - SKP Vx
+                // Skip next instruction if key with the value of Vx is pressed.
+                let key_value = self.data_registers[x_register as usize];
+                self.increment_pc();
+                if self.is_key_pressed(key_value) {
+                    self.skip_next_instruction();
+                }
+                return Ok(73);
+            }
+            Instruction::Sknp(x_register) => {
+                // ExA1 - SKNP Vx
+                // Skip next instruction if key with the value of Vx is not pressed.
+                let key_value = self.data_registers[x_register as usize];
+                self.increment_pc();
+                if !self.is_key_pressed(key_value) {
+                    self.skip_next_instruction();
                 }
-                0xA1 => {
-                    // ExA1 - SKNP Vx
-                    // Skip next instruction if key with the value of Vx is not pressed.
-                    let key_value = self.data_registers[x_register as usize];
-                    if !self.is_key_pressed(key_value) {
-                        self.increment_pc();
+                return Ok(73);
+            }
+            Instruction::LdILong(address) => {
+                // F000 nnnn - LD I, long addr (XO-CHIP)
+                // Set I = the 16 bit address in the following 2 bytes.
+                self.i_register = address;
+                self.increment_pc();
+                self.increment_pc();
+                return Ok(55);
+            }
+            Instruction::Plane(planes) => {
+                // Fn01 - PLANE n (XO-CHIP)
+                // Select the planes affected by drawing, clearing and scrolling.
+                self.selected_planes = planes & (FIRST_PLANE | SECOND_PLANE);
+                self.increment_pc();
+                return Ok(27);
+            }
+            Instruction::Audio => {
+                // F002 - AUDIO (XO-CHIP)
+                // Load the 16 byte audio pattern starting at location I.
+                let start_address = self.i_register as usize;
+                let pattern_length = self.audio_pattern.len();
+                self.check_memory_range(opcode, start_address, pattern_length)?;
+                self.audio_pattern
+                    .copy_from_slice(&self.memory[start_address..start_address + pattern_length]);
+                self.increment_pc();
+                return Ok(605);
+            }
+            Instruction::LdVxDt(x_register) => {
+                // Fx07 - LD Vx, DT
+                // Set Vx = delay timer value.
+                self.data_registers[x_register as usize] = self.delay_timer;
+                self.increment_pc();
+                return Ok(45);
+            }
+            Instruction::LdVxK(x_register) => {
+                // Fx0A - LD Vx, K
+                // Wait for a key press, store the value of the key in Vx.
+                // The key events move key_wait along, see apply_due_key_events
+                let pressed_key = match self.key_wait {
+                    KeyWait::NotWaiting => {
+                        self.key_wait = KeyWait::ForPress;
+                        None
                     }
+                    KeyWait::Finished { key } => Some(key),
+                    KeyWait::ForPress | KeyWait::ForRelease { .. } => None,
+                };
+
+                if let Some(key) = pressed_key {
+                    self.data_registers[x_register as usize] = key;
+                    self.key_wait = KeyWait::NotWaiting;
                     self.increment_pc();
-                    return 73;
                 }
-                _ => unimplemented_opcode(
-                    opcode,
-                    first_nibble_first_byte,
-                    second_nibble_first_byte,
-                    self.program_counter,
-                ),
-            },
-            0xF => match last_byte(opcode) {
-                0x07 => {
-                    // Fx07 - LD Vx, DT
-                    // Set Vx = delay timer value.
-                    self.data_registers[x_register as usize] = self.delay_timer;
-                    self.increment_pc();
-                    return 45;
+
+                // Return max value here so we can break out the processing loop while waiting.
+                // Technically not accurate timing, but it works!
+                return Ok(TimeTakenInMicroSeconds::MAX);
+            }
+            Instruction::LdDtVx(x_register) => {
+                // Fx15 - LD DT, Vx
+                // Set delay timer = Vx.
+                self.delay_timer = self.data_registers[x_register as usize];
+                self.increment_pc();
+                return Ok(45);
+            }
+            Instruction::LdStVx(x_register) => {
+                // Fx18 - LD ST, Vx
+                // Set sound timer = Vx.
+                self.sound_timer = self.data_registers[x_register as usize];
+                if self.sound_timer > 0 {
+                    self.should_play_sound = true;
                 }
-                0x0A => {
-                    let mut pressed_key: Option<u8> = None;
-                    for (i, key) in self.keys.iter().enumerate() {
-                        if *key == true {
-                            pressed_key = Some(i as u8);
-                        }
-                    }
+                self.increment_pc();
+                return Ok(45);
+            }
+            Instruction::AddIVx(x_register) => {
+                // Fx1E - ADD I, Vx
+                // Set I = I + Vx.
+                let x = self.data_registers[x_register as usize];
+                self.i_register = self.i_register.wrapping_add(x as u16);
+                self.increment_pc();
+                return Ok(86);
+            }
+            Instruction::LdFVx(x_register) => {
+                // Fx29 - LD F, Vx
+                // Set I = location of sprite for digit Vx.
+                // The value of I is set to the location for the hexadecimal sprite corresponding to the value of Vx
+                let x = self.data_registers[x_register as usize];
+                let sprite_location =
+                    ((FONT_SPRITE_LENGTH_IN_BYTES * x as usize) + FONT_START_LOCATION) as u16;
+                self.i_register = sprite_location;
+                self.increment_pc();
+                return Ok(91);
+            }
+            Instruction::LdBVx(x_register) => {
+                // Fx33 - LD B, Vx
+                // Store BCD (Binary Coded Decimal) representation of Vx in memory locations I, I+1, and I+2.
+                // Store hundreds at position I
+                // Store tens at position I + 1
+                // Store ones at position I + 2
 
-                    if let Some(key) = pressed_key {
-                        self.memory[x_register as usize] = key;
-                        self.increment_pc();
-                    }
+                let mut x_val = self.data_registers[x_register as usize];
+                let ones = x_val % 10;
+                x_val /= 10;
+                let tens = x_val % 10;
+                x_val /= 10;
+                let hundreds = x_val;
 
-                    // We don't increment the program counter here because we want to wait, but
-                    // still need input from the input loop
-                    // Return max value here so we can break out the processing loop. Technically
-                    // not accurate timing, but it works!
-                    return TimeTakenInMicroSeconds::MAX;
-                }
-                0x15 => {
-                    // Fx15 - LD DT, Vx
-                    // Set delay timer = Vx.
-                    self.delay_timer = x;
-                    self.increment_pc();
-                    return 45;
-                }
-                0x18 => {
-                    // Fx18 - LD ST, Vx
-                    // Set sound timer = Vx.
-                    self.sound_timer = x;
-                    if self.sound_timer > 0 {
-                        self.should_play_sound = true;
+                self.check_memory_range(opcode, self.i_register as usize, 3)?;
+
+                self.memory[self.i_register as usize] = hundreds;
+                self.memory[self.i_register as usize + 1] = tens;
+                self.memory[self.i_register as usize + 2] = ones;
+
+                self.increment_pc();
+
+                return Ok(927);
+            }
+            Instruction::LdHfVx(x_register) => {
+                // Fx30 - LD HF, Vx (SUPER-CHIP)
+                // Set I = location of the 10 byte high resolution sprite for digit Vx.
+                let x = self.data_registers[x_register as usize];
+                let sprite_location = ((BIG_FONT_SPRITE_LENGTH_IN_BYTES * x as usize)
+                    + BIG_FONT_START_LOCATION) as u16;
+                self.i_register = sprite_location;
+                self.increment_pc();
+                return Ok(91);
+            }
+            Instruction::Pitch(x_register) => {
+                // Fx3A - PITCH Vx (XO-CHIP)
+                // Set the pitch register = Vx.
+                self.pitch = self.data_registers[x_register as usize];
+                self.increment_pc();
+                return Ok(45);
+            }
+            Instruction::LdIVx(x_register) => {
+                // Fx55 - LD [I], Vx
+                // Store registers V0 through Vx in memory starting at location I.
+                let start_address = self.i_register as usize;
+                let x = x_register;
+                self.check_memory_range(opcode, start_address, x as usize + 1)?;
+                for reg in 0..=x {
+                    if reg >= 0xF {
+                        break;
                     }
-                    self.increment_pc();
-                    return 45;
-                }
-                0x1E => {
-                    // Fx1E - ADD I, Vx
-                    // Set I = I + Vx.
-                    self.i_register = self.i_register.wrapping_add(x as u16);
-                    self.increment_pc();
-                    return 86;
+                    self.memory[start_address + reg as usize] = self.data_registers[reg as usize];
                 }
-                0x29 => {
-                    // Fx29 - LD F, Vx
-                    // Set I = location of sprite for digit Vx.
-                    // The value of I is set to the location for the hexadecimal sprite corresponding to the value of Vx
-
-                    let sprite_location =
-                        ((FONT_SPRITE_LENGTH_IN_BYTES * x as usize) + FONT_START_LOCATION) as u16;
-                    self.i_register = sprite_location;
-                    self.increment_pc();
-                    return 91;
-                }
-                0x33 => {
-                    // Fx33 - LD B, Vx
-                    // Store BCD (Binary Coded Decimal) representation of Vx in memory locations I, I+1, and I+2.
-                    // Store hundreds at position I
-                    // Store tens at position I + 1
-                    // Store ones at position I + 2
-
-                    let mut x_val = x;
-                    let ones = x_val % 10;
-                    x_val /= 10;
-                    let tens = x_val % 10;
-                    x_val /= 10;
-                    let hundreds = x_val;
-
-                    self.memory[self.i_register as usize] = hundreds;
-                    self.memory[self.i_register as usize + 1] = tens;
-                    self.memory[self.i_register as usize + 2] = ones;
-
-                    self.increment_pc();
+                self.increment_i_after_load_store(x_register);
+                self.increment_pc();
+                return Ok(605);
+            }
+            Instruction::LdVxI(x_register) => {
+                // Fx65 - LD Vx, [I]
+                // Read registers V0 through Vx from memory starting at location I.
+                let start_address = self.i_register as usize;
+                let x = x_register;
+                self.check_memory_range(opcode, start_address, x as usize + 1)?;
 
-                    return 927;
+                for reg in 0..=x {
+                    self.data_registers[reg as usize] = self.memory[start_address + reg as usize];
                 }
-                0x55 => {
-                    // Fx55 - LD [I], Vx
-                    // Store registers V0 through Vx in memory starting at location I.
-                    let start_address = self.i_register as usize;
-                    let x = x_register;
-                    for reg in 0..=x {
-                        if reg >= 0xF {
-                            break;
-                        }
-                        self.memory[start_address + reg as usize] =
-                            self.data_registers[reg as usize];
-                    }
-                    self.increment_pc();
-                    return 605;
+                self.increment_i_after_load_store(x_register);
+                self.increment_pc();
+                return Ok(605);
+            }
+            Instruction::LdRVx(x_register) => {
+                // Fx75 - LD R, Vx (SUPER-CHIP)
+                // Store registers V0 through Vx in the RPL user flags.
+                for reg in 0..=x_register as usize {
+                    self.rpl_flags[reg] = self.data_registers[reg];
                 }
-                0x65 => {
-                    // Fx65 - LD Vx, [I]
-                    // Read registers V0 through Vx from memory starting at location I.
-                    let start_address = self.i_register as usize;
-                    let x = x_register;
-
-                    for reg in 0..=x {
-                        self.data_registers[reg as usize] =
-                            self.memory[start_address + reg as usize];
-                    }
-                    self.increment_pc();
-                    return 605;
+                self.increment_pc();
+                return Ok(605);
+            }
+            Instruction::LdVxR(x_register) => {
+                // Fx85 - LD Vx, R (SUPER-CHIP)
+                // Read registers V0 through Vx from the RPL user flags.
+                for reg in 0..=x_register as usize {
+                    self.data_registers[reg] = self.rpl_flags[reg];
                 }
-                _ => unimplemented_opcode(
-                    opcode,
-                    first_nibble_first_byte,
-                    second_nibble_first_byte,
-                    self.program_counter,
-                ),
-            },
+                self.increment_pc();
+                return Ok(605);
+            }
+        }
+    }
 
-            _ => unimplemented_opcode(
-                opcode,
-                first_nibble_first_byte,
-                second_nibble_first_byte,
-                self.program_counter,
-            ),
+    pub fn display_width(&self) -> usize {
+        if self.high_resolution {
+            return SUPER_CHIP_DISPLAY_WIDTH_IN_PIXELS;
+        }
+        return CHIP_DISPLAY_WIDTH_IN_PIXELS;
+    }
+
+    pub fn display_height(&self) -> usize {
+        if self.high_resolution {
+            return SUPER_CHIP_DISPLAY_HEIGHT_IN_PIXELS;
         }
-        return 0;
+        return CHIP_DISPLAY_HEIGHT_IN_PIXELS;
     }
 
-    /// XOR's the pixel at x,y with value.
+    /// Index into display_buffer for x,y at the current resolution. Wraps around the edges.
+    pub fn idx_for_display(&self, x: usize, y: usize) -> usize {
+        let width = self.display_width();
+        return (x % width) + ((y % self.display_height()) * width);
+    }
+
+    /// XOR's the pixel at x,y on the given plane with value.
     /// Returns true if the pixel was set to 0 as a result of the XOR, false otherwise
-    pub fn set_pixel(display_buffer: &mut [bool], x: usize, y: usize, value: bool) -> bool {
-        let idx = idx_for_display(x as u8, y as u8);
-        let previous_pixel = display_buffer[idx];
-        display_buffer[idx] ^= value;
+    pub fn set_pixel(&mut self, x: usize, y: usize, plane: u8, value: bool) -> bool {
+        let idx = self.idx_for_display(x, y);
+        let previous_pixel = self.display_buffer[idx] & plane != 0;
+        if value {
+            self.display_buffer[idx] ^= plane;
+        }
         if previous_pixel == true && value {
             return true;
         }
         return false;
     }
 
+    /// Moves everything on the selected planes by dx,dy pixels. Anything scrolled off the edge is
+    /// lost and the space left behind is cleared.
+    fn scroll_display(&mut self, dx: isize, dy: isize) {
+        let width = self.display_width() as isize;
+        let height = self.display_height() as isize;
+        let mut scrolled = self.display_buffer;
+        for y in 0..height {
+            for x in 0..width {
+                let idx = (x + y * width) as usize;
+                scrolled[idx] &= !self.selected_planes;
+
+                let from_x = x - dx;
+                let from_y = y - dy;
+                if from_x < 0 || from_x >= width || from_y < 0 || from_y >= height {
+                    continue;
+                }
+                scrolled[idx] |=
+                    self.display_buffer[(from_x + from_y * width) as usize] & self.selected_planes;
+            }
+        }
+        self.display_buffer = scrolled;
+    }
+
+    /// Moves the program counter past the instruction it's pointing at. Needed because XO-CHIP's
+    /// F000 nnnn is twice as long as everything else.
+    fn skip_next_instruction(&mut self) {
+        let instruction_length = match self.fetch_instruction() {
+            Ok(instruction) if self.quirks.xo_chip => instruction.length_in_bytes(),
+            _ => 2,
+        };
+        self.program_counter += instruction_length;
+    }
+
+    /// Samples per second the audio pattern should be played back at, going by the pitch register
+    pub fn audio_playback_rate(&self) -> f32 {
+        return 4000.0 * 2.0_f32.powf((self.pitch as f32 - 64.0) / 48.0);
+    }
+
+    /// Only the low nibble of Vx picks the key, like the COSMAC VIP's keypad latch
     fn is_key_pressed(&self, key_value: u8) -> bool {
-        return self.keys[key_value as usize];
+        return self.keys[(key_value & 0xF) as usize];
     }
-}
 
-pub fn idx_for_display(x: u8, y: u8) -> usize {
-    (x as usize % CHIP_DISPLAY_WIDTH_IN_PIXELS)
-        + ((y as usize % CHIP_DISPLAY_HEIGHT_IN_PIXELS) * CHIP_DISPLAY_WIDTH_IN_PIXELS)
-}
+    fn invalid_opcode(&self, opcode: u16) -> Chip8Error {
+        return Chip8Error::InvalidOpcode {
+            opcode,
+            program_counter: self.program_counter,
+        };
+    }
+
+    /// Errors if any of the `length` bytes starting at `address` fall outside of memory
+    fn check_memory_range(
+        &self,
+        opcode: u16,
+        address: usize,
+        length: usize,
+    ) -> Result<(), Chip8Error> {
+        if address + length > self.memory.len() {
+            return Err(Chip8Error::MemoryOutOfRange {
+                opcode,
+                program_counter: self.program_counter,
+                address: address + length - 1,
+            });
+        }
+        return Ok(());
+    }
 
-fn unimplemented_opcode(opcode: u16, first_nibble: u8, second_nibble: u8, program_counter: usize) {
-    panic!(
-        "Unimplemented opcode {:X}, first nibble: {:X}, second nibble: {:X}, PC: {:X}",
-        opcode, first_nibble, second_nibble, program_counter
-    );
+    fn increment_i_after_load_store(&mut self, x_register: u8) {
+        let increment = match self.quirks.load_store_i_increment {
+            LoadStoreIncrement::Unchanged => 0,
+            LoadStoreIncrement::X => x_register as u16,
+            LoadStoreIncrement::XPlusOne => x_register as u16 + 1,
+        };
+        self.i_register = self.i_register.wrapping_add(increment);
+    }
 }
 
 const FONT_SPRITE_LENGTH_IN_BYTES: usize = 5;
@@ -670,3 +1445,689 @@ const FONT_SPRITES: [u8; FONT_SPRITE_LENGTH_IN_BYTES * NUMBER_OF_FONT_SPRITES] =
     0b10000000,
     0b10000000,
 ];
+
+const BIG_FONT_SPRITE_LENGTH_IN_BYTES: usize = 10;
+const BIG_FONT_START_LOCATION: usize =
+    FONT_START_LOCATION + FONT_SPRITE_LENGTH_IN_BYTES * NUMBER_OF_FONT_SPRITES;
+const NUMBER_OF_BIG_FONT_SPRITES: usize = 10; // 0 - 9, SUPER-CHIP 1.1 has no big A - F
+
+#[rustfmt::skip]
+const BIG_FONT_SPRITES: [u8; BIG_FONT_SPRITE_LENGTH_IN_BYTES * NUMBER_OF_BIG_FONT_SPRITES] = [
+//0
+    0b00111100,
+    0b01111110,
+    0b11100111,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11000011,
+    0b11100111,
+    0b01111110,
+    0b00111100,
+//1
+    0b00011000,
+    0b00111000,
+    0b01011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00011000,
+    0b00111100,
+//2
+    0b00111110,
+    0b01111111,
+    0b11000011,
+    0b00000110,
+    0b00001100,
+    0b00011000,
+    0b00110000,
+    0b01100000,
+    0b11111111,
+    0b11111111,
+//3
+    0b00111100,
+    0b01111110,
+    0b11000011,
+    0b00000011,
+    0b00001110,
+    0b00001110,
+    0b00000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+//4
+    0b00000110,
+    0b00001110,
+    0b00011110,
+    0b00110110,
+    0b01100110,
+    0b11000110,
+    0b11111111,
+    0b11111111,
+    0b00000110,
+    0b00000110,
+//5
+    0b11111111,
+    0b11111111,
+    0b11000000,
+    0b11000000,
+    0b11111100,
+    0b11111110,
+    0b00000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+//6
+    0b00111110,
+    0b01111100,
+    0b11100000,
+    0b11000000,
+    0b11111100,
+    0b11111110,
+    0b11000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+//7
+    0b11111111,
+    0b11111111,
+    0b00000011,
+    0b00000110,
+    0b00001100,
+    0b00011000,
+    0b00110000,
+    0b01100000,
+    0b01100000,
+    0b01100000,
+//8
+    0b00111100,
+    0b01111110,
+    0b11000011,
+    0b11000011,
+    0b01111110,
+    0b01111110,
+    0b11000011,
+    0b11000011,
+    0b01111110,
+    0b00111100,
+//9
+    0b00111100,
+    0b01111110,
+    0b11000011,
+    0b11000011,
+    0b01111111,
+    0b00111111,
+    0b00000011,
+    0b00000011,
+    0b00111110,
+    0b01111100,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_instructions(program: &[u8], quirks: Quirks, count: usize) -> Chip8 {
+        let mut chip = Chip8::new(program, quirks);
+        for _ in 0..count {
+            chip.process_next_instruction().unwrap();
+        }
+        return chip;
+    }
+
+    /// Runs one instruction per entry, holding down the listed keys for it
+    fn run_with_held_keys(chip: &mut Chip8, script: &[&[u8]]) {
+        for held_keys in script {
+            let mut keys = [false; 16];
+            for key in held_keys.iter() {
+                keys[*key as usize] = true;
+            }
+            chip.set_keys(keys);
+            chip.process_next_instruction().unwrap();
+        }
+    }
+
+    #[test]
+    fn key_wait_stores_the_key_in_vx_once_released() {
+        // LD V3, K, LD V4, 0x1
+        let program = [0xF3, 0x0A, 0x64, 0x01];
+        let mut chip = Chip8::new(&program, Quirks::cosmac_vip());
+        run_with_held_keys(&mut chip, &[&[], &[0x5], &[0x5]]);
+        assert!(chip.waiting_for_key());
+        assert_eq!(chip.key_wait, KeyWait::ForRelease { key: 0x5 });
+        assert_eq!(chip.program_counter, 0x200);
+
+        run_with_held_keys(&mut chip, &[&[]]);
+        assert!(!chip.waiting_for_key());
+        assert_eq!(chip.data_registers[3], 0x5);
+        assert_eq!(
+            chip.memory,
+            Chip8::new(&program, Quirks::cosmac_vip()).memory
+        );
+        assert_eq!(chip.program_counter, 0x202);
+    }
+
+    #[test]
+    fn key_wait_can_finish_on_press() {
+        // LD V3, K
+        let program = [0xF3, 0x0A];
+        let mut quirks = Quirks::cosmac_vip();
+        quirks.key_wait_on_release = false;
+        let mut chip = Chip8::new(&program, quirks);
+        run_with_held_keys(&mut chip, &[&[], &[0xA]]);
+        assert!(!chip.waiting_for_key());
+        assert_eq!(chip.data_registers[3], 0xA);
+        assert_eq!(chip.program_counter, 0x202);
+    }
+
+    #[test]
+    fn key_wait_ignores_keys_held_from_before() {
+        // LD V3, K
+        let program = [0xF3, 0x0A];
+        let mut chip = Chip8::new(&program, Quirks::cosmac_vip());
+        // Still holding 5 from the last menu, so it has to be let go and pressed again
+        run_with_held_keys(&mut chip, &[&[0x5], &[0x5], &[]]);
+        assert_eq!(chip.program_counter, 0x200);
+        run_with_held_keys(&mut chip, &[&[0x5], &[]]);
+        assert_eq!(chip.data_registers[3], 0x5);
+
+        // A different key pressed while one is still held does count
+        let mut chip = Chip8::new(&program, Quirks::cosmac_vip());
+        run_with_held_keys(&mut chip, &[&[0x5], &[0x5, 0x7], &[0x5]]);
+        assert_eq!(chip.data_registers[3], 0x7);
+        assert_eq!(chip.program_counter, 0x202);
+    }
+
+    #[test]
+    fn key_wait_sees_a_tap_between_instructions() {
+        // LD V3, K
+        let program = [0xF3, 0x0A];
+        let mut chip = Chip8::new(&program, Quirks::cosmac_vip());
+        chip.process_next_instruction().unwrap();
+        chip.key_down(0x9);
+        chip.key_up(0x9);
+        chip.process_next_instruction().unwrap();
+        // The release waits a cycle so the press is seen
+        assert!(chip.keys[0x9]);
+        assert_eq!(chip.key_wait, KeyWait::ForRelease { key: 0x9 });
+        chip.process_next_instruction().unwrap();
+        assert!(!chip.keys[0x9]);
+        assert_eq!(chip.data_registers[3], 0x9);
+        assert_eq!(chip.program_counter, 0x202);
+    }
+
+    #[test]
+    fn key_events_wait_for_their_cycle() {
+        // SKP V0, JP 0x200, JP 0x200
+        let program = [0xE0, 0x9E, 0x12, 0x00, 0x12, 0x00];
+        let mut chip = Chip8::new(&program, Quirks::cosmac_vip());
+        chip.queue_key_event(KeypadEvent {
+            key: 0x0,
+            pressed: false,
+            cycle: 6,
+        });
+        chip.queue_key_event(KeypadEvent {
+            key: 0x0,
+            pressed: true,
+            cycle: 4,
+        });
+        for _ in 0..4 {
+            chip.process_next_instruction().unwrap();
+        }
+        assert_eq!(chip.cycles, 4);
+        assert!(!chip.keys[0x0]);
+        assert_eq!(chip.program_counter, 0x200);
+
+        // Pressed as SKP runs, so it skips the jump
+        chip.process_next_instruction().unwrap();
+        assert!(chip.keys[0x0]);
+        assert_eq!(chip.program_counter, 0x204);
+        assert_eq!(chip.key_events.len(), 1);
+
+        // Pressing a key that's already down changes nothing
+        chip.key_down(0x0);
+        chip.process_next_instruction().unwrap();
+        assert!(chip.keys[0x0]);
+
+        chip.process_next_instruction().unwrap();
+        assert!(!chip.keys[0x0]);
+        assert!(chip.key_events.is_empty());
+    }
+
+    #[test]
+    fn skipping_on_keys_above_f_uses_the_low_nibble() {
+        // LD V0, 0x21, SKP V0, JP 0x200, SKNP V0
+        let program = [0x60, 0x21, 0xE0, 0x9E, 0x12, 0x00, 0xE0, 0xA1];
+        let mut chip = Chip8::new(&program, Quirks::cosmac_vip());
+        chip.key_down(0x1);
+        for _ in 0..3 {
+            chip.process_next_instruction().unwrap();
+        }
+        // SKP V0 saw key 1 down and skipped the jump, then SKNP V0 didn't skip
+        assert_eq!(chip.program_counter, 0x208);
+    }
+
+    #[test]
+    fn shift_uses_vx_or_vy() {
+        // LD V0, 0x1, LD V1, 0x6, SHR V0, V1
+        let program = [0x60, 0x01, 0x61, 0x06, 0x80, 0x16];
+
+        let chip = run_instructions(&program, Quirks::cosmac_vip(), 3);
+        assert_eq!(chip.data_registers[0], 0x3);
+        assert_eq!(chip.data_registers[0xF], 0);
+
+        let chip = run_instructions(&program, Quirks::superchip(), 3);
+        assert_eq!(chip.data_registers[0], 0x0);
+        assert_eq!(chip.data_registers[0xF], 1);
+    }
+
+    #[test]
+    fn load_store_increments_i() {
+        // LD I, 0x300, LD [I], V2
+        let program = [0xA3, 0x00, 0xF2, 0x55];
+
+        let chip = run_instructions(&program, Quirks::cosmac_vip(), 2);
+        assert_eq!(chip.i_register, 0x303);
+
+        let chip = run_instructions(&program, Quirks::chip48(), 2);
+        assert_eq!(chip.i_register, 0x302);
+
+        let chip = run_instructions(&program, Quirks::superchip(), 2);
+        assert_eq!(chip.i_register, 0x300);
+    }
+
+    #[test]
+    fn logic_ops_reset_vf() {
+        // LD VF, 0x5, OR V0, V1
+        let program = [0x6F, 0x05, 0x80, 0x11];
+
+        let chip = run_instructions(&program, Quirks::cosmac_vip(), 2);
+        assert_eq!(chip.data_registers[0xF], 0);
+
+        let chip = run_instructions(&program, Quirks::superchip(), 2);
+        assert_eq!(chip.data_registers[0xF], 5);
+    }
+
+    #[test]
+    fn sprites_clip_or_wrap() {
+        // LD V0, 0x3E, LD I, 0x0, DRW V0, V1, 0x1
+        // The top row of the 0 font sprite is 0b01100000, so the second lit pixel lands past the
+        // right edge of the display
+        let program = [0x60, 0x3E, 0xA0, 0x00, 0xD0, 0x15];
+
+        let chip = run_instructions(&program, Quirks::cosmac_vip(), 3);
+        assert_eq!(chip.display_buffer[63], FIRST_PLANE);
+        assert_eq!(chip.display_buffer[0], 0);
+
+        let mut wrapping = Quirks::cosmac_vip();
+        wrapping.clip_sprites = false;
+        let chip = run_instructions(&program, wrapping, 3);
+        assert_eq!(chip.display_buffer[63], FIRST_PLANE);
+        assert_eq!(chip.display_buffer[0], FIRST_PLANE);
+    }
+
+    #[test]
+    fn display_wait_ends_the_frame_after_drawing() {
+        // DRW V0, V0, 0x1, LD V1, 0x1, JP 0x204
+        let program = [0xD0, 0x01, 0x61, 0x01, 0x12, 0x04];
+
+        let mut chip = Chip8::new(&program, Quirks::cosmac_vip());
+        chip.process_a_frame(100_000).unwrap();
+        assert_eq!(chip.data_registers[1], 0);
+
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.process_a_frame(100_000).unwrap();
+        assert_eq!(chip.data_registers[1], 1);
+    }
+
+    #[test]
+    fn display_wait_draws_once_per_frame_under_every_execution_model() {
+        // Counts the sprites drawn in the 10 frames it takes the delay timer to run out, the same
+        // way the quirks test ROM does:
+        // LD V0, 10, LD DT, V0
+        // loop: DRW V1, V1, 1, ADD V2, 1, LD V3, DT, SE V3, 0, JP loop
+        // done: JP done
+        let program = [
+            0x60, 0x0A, 0xF0, 0x15, 0xD1, 0x11, 0x72, 0x01, 0xF3, 0x07, 0x33, 0x00, 0x12, 0x04,
+            0x12, 0x0E,
+        ];
+        let sprites_drawn = |display_wait: bool, execution_model: ExecutionModel| {
+            let mut quirks = Quirks::cosmac_vip();
+            quirks.display_wait = display_wait;
+            let mut chip = Chip8::new(&program, quirks);
+            chip.execution_model = execution_model;
+            for _ in 0..20 {
+                chip.run_a_frame().unwrap();
+            }
+            return chip.data_registers[2];
+        };
+
+        for execution_model in [
+            ExecutionModel::VipTiming,
+            ExecutionModel::InstructionsPerFrame(15),
+            ExecutionModel::InstructionsPerFrame(1000),
+        ] {
+            assert_eq!(sprites_drawn(true, execution_model), 10);
+        }
+        assert!(sprites_drawn(false, ExecutionModel::InstructionsPerFrame(15)) > 10);
+    }
+
+    #[test]
+    fn invalid_opcode_is_an_error() {
+        // LD V0, 0x1, 0xFFFF
+        let program = [0x60, 0x01, 0xFF, 0xFF];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.process_next_instruction().unwrap();
+        assert_eq!(
+            chip.process_next_instruction(),
+            Err(Chip8Error::InvalidOpcode {
+                opcode: 0xFFFF,
+                program_counter: 0x202
+            })
+        );
+        assert_eq!(chip.program_counter, 0x202);
+    }
+
+    #[test]
+    fn return_with_empty_stack_is_an_error() {
+        let program = [0x00, 0xEE];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        assert_eq!(
+            chip.process_next_instruction(),
+            Err(Chip8Error::StackUnderflow {
+                opcode: 0x00EE,
+                program_counter: 0x200
+            })
+        );
+    }
+
+    #[test]
+    fn recursing_forever_is_a_stack_overflow() {
+        // CALL 0x200
+        let program = [0x22, 0x00];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        let error = chip.process_a_frame(100_000).unwrap_err();
+        assert_eq!(
+            error,
+            Chip8Error::StackOverflow {
+                opcode: 0x2200,
+                program_counter: 0x200
+            }
+        );
+    }
+
+    #[test]
+    fn reading_past_the_end_of_memory_is_an_error() {
+        // LD I, 0xFFE, LD V3, [I]
+        let program = [0xAF, 0xFE, 0xF3, 0x65];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.process_next_instruction().unwrap();
+        assert_eq!(
+            chip.process_next_instruction(),
+            Err(Chip8Error::MemoryOutOfRange {
+                opcode: 0xF365,
+                program_counter: 0x202,
+                address: 0x1001
+            })
+        );
+    }
+
+    #[test]
+    fn running_off_the_end_of_memory_is_an_error() {
+        // JP 0xFFF
+        let program = [0x1F, 0xFF];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.process_next_instruction().unwrap();
+        assert_eq!(
+            chip.process_next_instruction(),
+            Err(Chip8Error::ProgramCounterOutOfRange {
+                program_counter: 0xFFF
+            })
+        );
+    }
+
+    #[test]
+    fn switching_resolution_changes_display_size() {
+        // HIGH, LOW
+        let program = [0x00, 0xFF, 0x00, 0xFE];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.process_next_instruction().unwrap();
+        assert_eq!(chip.display_width(), 128);
+        assert_eq!(chip.display_height(), 64);
+        chip.process_next_instruction().unwrap();
+        assert_eq!(chip.display_width(), 64);
+        assert_eq!(chip.display_height(), 32);
+    }
+
+    #[test]
+    fn draws_16x16_sprites() {
+        // HIGH, LD V0, 0x70, LD I, 0x20A, DRW V0, V0, 0x0, JP 0x208, then the sprite data
+        let mut program = vec![0x00, 0xFF, 0x60, 0x70, 0xA2, 0x0A, 0xD0, 0x00, 0x12, 0x08];
+        program.extend([0xFF; 32]);
+        let chip = run_instructions(&program, Quirks::superchip(), 4);
+
+        let lit_pixels = chip
+            .display_buffer
+            .iter()
+            .filter(|pixel| **pixel != 0)
+            .count();
+        assert_eq!(lit_pixels, 16 * 16);
+        assert_eq!(
+            chip.display_buffer[chip.idx_for_display(0x70, 0x70)],
+            FIRST_PLANE
+        );
+        assert_eq!(
+            chip.display_buffer[chip.idx_for_display(0x7F, 0x7F)],
+            FIRST_PLANE
+        );
+        // Clipped at the right edge
+        assert_eq!(chip.display_buffer[chip.idx_for_display(0x80, 0x70)], 0);
+    }
+
+    #[test]
+    fn scrolls_the_display() {
+        // LD I, 0x0, DRW V0, V0, 0x1, SCD 0x2, SCR, SCL, SCL
+        let program = [
+            0xA0, 0x00, 0xD0, 0x01, 0x00, 0xC2, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC,
+        ];
+        // Top row of the 0 font sprite is 0b01100000
+        let chip = run_instructions(&program, Quirks::superchip(), 3);
+        assert_eq!(chip.display_buffer[chip.idx_for_display(1, 2)], FIRST_PLANE);
+        assert_eq!(chip.display_buffer[chip.idx_for_display(2, 2)], FIRST_PLANE);
+        assert_eq!(chip.display_buffer[chip.idx_for_display(1, 0)], 0);
+
+        let chip = run_instructions(&program, Quirks::superchip(), 4);
+        assert_eq!(chip.display_buffer[chip.idx_for_display(5, 2)], FIRST_PLANE);
+        assert_eq!(chip.display_buffer[chip.idx_for_display(1, 2)], 0);
+
+        // Scrolling back left past the edge loses those pixels
+        let chip = run_instructions(&program, Quirks::superchip(), 6);
+        let lit_pixels = chip
+            .display_buffer
+            .iter()
+            .filter(|pixel| **pixel != 0)
+            .count();
+        assert_eq!(lit_pixels, 0);
+    }
+
+    #[test]
+    fn points_i_at_big_font() {
+        // LD V0, 0x2, LD HF, V0
+        let program = [0x60, 0x02, 0xF0, 0x30];
+        let chip = run_instructions(&program, Quirks::superchip(), 2);
+        let i = chip.i_register as usize;
+        assert_eq!(chip.memory[i..i + 10], BIG_FONT_SPRITES[20..30]);
+    }
+
+    #[test]
+    fn saves_and_loads_rpl_flags() {
+        // LD V0, 0x1, LD V1, 0x2, LD R, V1, LD V0, 0x0, LD V1, 0x0, LD V1, R
+        let program = [
+            0x60, 0x01, 0x61, 0x02, 0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0xF1, 0x85,
+        ];
+        let chip = run_instructions(&program, Quirks::superchip(), 6);
+        assert_eq!(chip.data_registers[0], 1);
+        assert_eq!(chip.data_registers[1], 2);
+    }
+
+    #[test]
+    fn executes_decoded_instructions() {
+        let mut chip = Chip8::new(&[], Quirks::superchip());
+        chip.execute(Instruction::LdVxByte(0x3, 0x42)).unwrap();
+        chip.execute(Instruction::AddVxByte(0x3, 0x1)).unwrap();
+        assert_eq!(chip.data_registers[0x3], 0x43);
+        assert_eq!(chip.program_counter, PROGRAM_OFFSET + 4);
+
+        // XO-CHIP instructions aren't available outside XO-CHIP mode
+        assert_eq!(
+            chip.execute(Instruction::Plane(0x3)),
+            Err(Chip8Error::InvalidOpcode {
+                opcode: 0xF301,
+                program_counter: PROGRAM_OFFSET + 4,
+            })
+        );
+    }
+
+    #[test]
+    fn same_seed_draws_the_same_maze() {
+        let rom = std::fs::read("roms/Maze [David Winter, 199x].ch8").unwrap();
+        let run_maze = |seed: u64| {
+            let mut chip = Chip8::with_seed(&rom, Quirks::cosmac_vip(), seed);
+            for _ in 0..30 {
+                chip.process_a_frame(FRAME_TIME_IN_MICROSECONDS).unwrap();
+            }
+            return chip.display_buffer;
+        };
+
+        assert_eq!(run_maze(1), run_maze(1));
+        assert_ne!(run_maze(1), run_maze(2));
+    }
+
+    #[test]
+    fn runs_a_fixed_number_of_instructions_per_frame() {
+        // ADD V0, 1 then jump back to it
+        let program = [0x70, 0x01, 0x12, 0x00];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.delay_timer = 10;
+        chip.process_a_frame_of_instructions(10).unwrap();
+        assert_eq!(chip.data_registers[0], 5);
+        assert_eq!(chip.delay_timer, 9);
+    }
+
+    #[test]
+    fn run_a_frame_follows_the_execution_model() {
+        // ADD V0, 1 then jump back to it
+        let program = [0x70, 0x01, 0x12, 0x00];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.execution_model = ExecutionModel::InstructionsPerFrame(20);
+        chip.run_a_frame().unwrap();
+        assert_eq!(chip.data_registers[0], 10);
+
+        chip.execution_model = ExecutionModel::VipTiming;
+        chip.run_a_frame().unwrap();
+        let after_vip_frame = chip.data_registers[0];
+        assert!(after_vip_frame > 10);
+
+        let instructions_run = chip
+            .process_a_frame_unlimited(std::time::Duration::ZERO)
+            .unwrap();
+        // Always gets at least one batch in, no matter how small the budget
+        assert_eq!(
+            instructions_run,
+            UNLIMITED_INSTRUCTIONS_BETWEEN_CLOCK_CHECKS as u64
+        );
+        chip.execution_model = ExecutionModel::Unlimited;
+        chip.run_a_frame().unwrap();
+    }
+
+    #[test]
+    fn exit_stops_execution() {
+        // EXIT, LD V0, 0x1
+        let program = [0x00, 0xFD, 0x60, 0x01];
+        let mut chip = Chip8::new(&program, Quirks::superchip());
+        chip.process_a_frame(100_000).unwrap();
+        chip.process_a_frame(100_000).unwrap();
+        assert!(chip.exited);
+        assert_eq!(chip.program_counter, 0x200);
+        assert_eq!(chip.data_registers[0], 0);
+    }
+
+    #[test]
+    fn xo_chip_has_64kb_of_memory() {
+        let chip = Chip8::new(&[], Quirks::xo_chip());
+        assert_eq!(chip.memory.len(), 65536);
+        let chip = Chip8::new(&[], Quirks::superchip());
+        assert_eq!(chip.memory.len(), 4096);
+    }
+
+    #[test]
+    fn long_load_sets_i_to_16_bit_address() {
+        // LD I, long 0xABCD, LD V0, 0x1
+        let program = [0xF0, 0x00, 0xAB, 0xCD, 0x60, 0x01];
+        let chip = run_instructions(&program, Quirks::xo_chip(), 2);
+        assert_eq!(chip.i_register, 0xABCD);
+        assert_eq!(chip.data_registers[0], 1);
+    }
+
+    #[test]
+    fn skips_over_long_load() {
+        // SE V0, 0x0, LD I, long 0xABCD, LD V1, 0x1
+        let program = [0x30, 0x00, 0xF0, 0x00, 0xAB, 0xCD, 0x61, 0x01];
+        let chip = run_instructions(&program, Quirks::xo_chip(), 2);
+        assert_eq!(chip.i_register, 0);
+        assert_eq!(chip.data_registers[1], 1);
+    }
+
+    #[test]
+    fn saves_and_loads_register_ranges() {
+        // LD V2, 0x2, LD V3, 0x3, LD I, 0x300, LD [I], V3 - V2, LD V1 - V2, [I]
+        let program = [0x62, 0x02, 0x63, 0x03, 0xA3, 0x00, 0x53, 0x22, 0x51, 0x23];
+        let chip = run_instructions(&program, Quirks::xo_chip(), 5);
+        assert_eq!(chip.memory[0x300..0x302], [3, 2]);
+        assert_eq!(chip.data_registers[1], 3);
+        assert_eq!(chip.data_registers[2], 2);
+        assert_eq!(chip.i_register, 0x300);
+    }
+
+    #[test]
+    fn draws_to_selected_planes() {
+        // PLANE 3, LD I, 0x20A, DRW V0, V0, 0x1, PLANE 2, CLS, then 2 bytes of sprite data
+        let program = [
+            0xF3, 0x01, 0xA2, 0x0A, 0xD0, 0x01, 0xF2, 0x01, 0x00, 0xE0, 0x80, 0xC0,
+        ];
+        let chip = run_instructions(&program, Quirks::xo_chip(), 3);
+        assert_eq!(chip.display_buffer[0], FIRST_PLANE | SECOND_PLANE);
+        assert_eq!(chip.display_buffer[1], SECOND_PLANE);
+
+        // Clearing only clears the selected plane
+        let chip = run_instructions(&program, Quirks::xo_chip(), 5);
+        assert_eq!(chip.display_buffer[0], FIRST_PLANE);
+        assert_eq!(chip.display_buffer[1], 0);
+    }
+
+    #[test]
+    fn loads_audio_pattern_and_pitch() {
+        // LD I, 0x208, AUDIO, LD V0, 0x70, PITCH V0, then the pattern
+        let mut program = vec![0xA2, 0x08, 0xF0, 0x02, 0x60, 0x70, 0xF0, 0x3A];
+        program.extend(0..16);
+        let chip = run_instructions(&program, Quirks::xo_chip(), 4);
+        assert_eq!(chip.audio_pattern, core::array::from_fn(|i| i as u8));
+        assert_eq!(chip.pitch, 0x70);
+        assert_eq!(chip.audio_playback_rate(), 4000.0 * 2.0_f32.powf(1.0));
+    }
+
+    #[test]
+    fn jump_with_offset_uses_v0_or_vx() {
+        // Jumps into a table at 0x208 with V0 = 4 and V2 = 2, each entry setting V1 to a
+        // different value. See test_programs/jump_table.asm
+        let program = std::fs::read("test_programs/jump_table.ch8").unwrap();
+
+        let chip = run_instructions(&program, Quirks::cosmac_vip(), 5);
+        assert_eq!(chip.data_registers[1], 3);
+
+        let chip = run_instructions(&program, Quirks::superchip(), 5);
+        assert_eq!(chip.data_registers[1], 2);
+    }
+}
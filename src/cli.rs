@@ -0,0 +1,311 @@
+use crate::chip::{ExecutionModel, Quirks};
+use crate::headless::{parse_key_schedule, KeyEvent};
+
+pub const USAGE: &str = "\
+Usage: chip-8-emulator [OPTIONS] [ROM]
+
+Runs ROM, or roms/test_opcode.ch8 if no ROM is given.
+
+Speed:
+    --ips <N>                 Run N instructions per second
+    --cycles-per-frame <N>    Run N instructions per 60Hz frame
+    --vip-timing              Instructions take roughly as long as on a COSMAC VIP (the default)
+    --unlimited               Run as many instructions as possible, for benchmarking
+    --quirks <PRESET>         Interpreter to act like: vip, chip48, schip or xochip (default schip)
+    --display-wait            DRW waits for the next frame, like the COSMAC VIP (on for vip)
+    --no-display-wait         DRW doesn't wait, whatever the quirks preset says
+
+Display and sound:
+    --scale <N>               Window is N times the size of the 64x32 display
+    --fg <RRGGBB>             Colour of lit pixels
+    --bg <RRGGBB>             Colour of unlit pixels
+    --fullscreen              Start fullscreen
+    --no-vsync                Don't wait for the display to refresh before drawing the next frame
+    --mute                    Never play sound
+    --paused                  Start paused (p to unpause, n to step)
+
+Input:
+    --keymap <FILE>           Load key bindings from FILE, one `<key> = \"<command>\"` per line,
+                              on top of the [keymap] from the config file
+    --rewind-memory <MB>      Memory to keep for rewinding (default 32)
+
+Config:
+    --config <FILE>           Read settings from FILE instead of
+                              $XDG_CONFIG_HOME/chip-8-emulator/config.toml
+    --print-config            Print the settings that would be used, as a config file, then exit
+
+Testing:
+    --seed <N>                Seed for the random number generator, to make runs repeatable
+    --headless                Run without a window, then print the final screen and its hash.
+                              Only --config is read, not your config file or ROM database
+    --frames <N>              Frames to run with --headless (default 60)
+    --keys <SCHEDULE>         Keys to press with --headless, e.g 30+5,35-5
+    --dump-screen <FILE>      Write the final screen to FILE with --headless (.pbm or text)
+
+    -h, --help                Print this message
+";
+
+pub const HEADLESS_USAGE: &str = "\
+Usage: chip8-headless [OPTIONS] <ROM>
+
+Runs ROM without a window or audio, then prints the final screen and its hash. The same as
+chip-8-emulator --headless, but without needing SDL. Only the bundled ROM database is used, and a
+config file is only read if --config is given.
+
+    --frames <N>              Frames to run (default 60)
+    --keys <SCHEDULE>         Keys to press, e.g 30+5,35-5 holds 5 from frame 30 to frame 35
+    --dump-screen <FILE>      Write the final screen to FILE (.pbm or text)
+    --seed <N>                Seed for the random number generator, to make runs repeatable
+
+Also takes the speed, quirks and config options of chip-8-emulator (see chip-8-emulator --help).
+
+    -h, --help                Print this message
+";
+
+pub type Rgb = (u8, u8, u8);
+
+/// Everything that can be set from the command line. Anything not given is None, so the frontend
+/// can fall back to the config file. See Config::apply_options
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    pub rom_path: Option<String>,
+    pub execution_model: Option<ExecutionModel>,
+    /// Name of a Quirks preset, already checked to be one from_preset_name knows
+    pub quirks: Option<String>,
+    /// Overrides the display_wait quirk from the preset
+    pub display_wait: Option<bool>,
+    pub scale: Option<u32>,
+    pub foreground: Option<Rgb>,
+    pub background: Option<Rgb>,
+    pub fullscreen: bool,
+    pub no_vsync: bool,
+    pub mute: bool,
+    pub paused: bool,
+    pub seed: Option<u64>,
+    /// Always on for chip8-headless
+    pub headless: bool,
+    pub frames: Option<u32>,
+    pub key_schedule: Vec<KeyEvent>,
+    pub dump_screen_path: Option<String>,
+    pub keymap_path: Option<String>,
+    pub rewind_memory_in_megabytes: Option<usize>,
+    pub config_path: Option<String>,
+    pub print_config: bool,
+    pub show_help: bool,
+}
+
+/// Parses the arguments after the program name. Errors are meant to be shown to the user as is,
+/// followed by USAGE.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let mut value_for = |flag: &str| {
+            return args.next().ok_or_else(|| format!("{} needs a value", flag));
+        };
+        match arg.as_str() {
+            "-h" | "--help" => options.show_help = true,
+            "--ips" => {
+                let instructions_per_second: u32 = parse_number(&arg, &value_for(&arg)?)?;
+                // Timers and drawing happen at 60Hz, so that's the smallest slice we can run
+                options.execution_model = Some(ExecutionModel::InstructionsPerFrame(
+                    (instructions_per_second / 60).max(1),
+                ));
+            }
+            "--cycles-per-frame" => {
+                options.execution_model = Some(ExecutionModel::InstructionsPerFrame(parse_number(
+                    &arg,
+                    &value_for(&arg)?,
+                )?));
+            }
+            "--vip-timing" => options.execution_model = Some(ExecutionModel::VipTiming),
+            "--unlimited" => options.execution_model = Some(ExecutionModel::Unlimited),
+            "--quirks" => {
+                let preset = value_for(&arg)?;
+                if Quirks::from_preset_name(&preset).is_none() {
+                    return Err(format!(
+                        "--quirks doesn't know '{}'. Use vip, chip48, schip or xochip",
+                        preset
+                    ));
+                }
+                options.quirks = Some(preset);
+            }
+            "--display-wait" => options.display_wait = Some(true),
+            "--no-display-wait" => options.display_wait = Some(false),
+            "--scale" => {
+                let scale = parse_number(&arg, &value_for(&arg)?)?;
+                if scale == 0 {
+                    return Err("--scale needs to be at least 1".to_string());
+                }
+                options.scale = Some(scale);
+            }
+            "--fg" => options.foreground = Some(parse_colour(&arg, &value_for(&arg)?)?),
+            "--bg" => options.background = Some(parse_colour(&arg, &value_for(&arg)?)?),
+            "--fullscreen" => options.fullscreen = true,
+            "--no-vsync" => options.no_vsync = true,
+            "--mute" => options.mute = true,
+            "--paused" => options.paused = true,
+            "--seed" => options.seed = Some(parse_number(&arg, &value_for(&arg)?)?),
+            "--headless" => options.headless = true,
+            "--frames" => options.frames = Some(parse_number(&arg, &value_for(&arg)?)?),
+            "--keys" => {
+                options.key_schedule =
+                    parse_key_schedule(&value_for(&arg)?).map_err(|e| format!("--keys: {}", e))?;
+            }
+            "--dump-screen" => options.dump_screen_path = Some(value_for(&arg)?),
+            "--keymap" => options.keymap_path = Some(value_for(&arg)?),
+            "--rewind-memory" => {
+                options.rewind_memory_in_megabytes = Some(parse_number(&arg, &value_for(&arg)?)?);
+            }
+            "--config" => options.config_path = Some(value_for(&arg)?),
+            "--print-config" => options.print_config = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => {
+                if let Some(rom_path) = &options.rom_path {
+                    return Err(format!(
+                        "Only one ROM can be run at a time, got {} and {}",
+                        rom_path, arg
+                    ));
+                }
+                options.rom_path = Some(arg);
+            }
+        }
+    }
+
+    return Ok(options);
+}
+
+/// The first flag given that only works with --headless, for the windowed frontend to reject
+pub fn headless_only_flag(options: &Options) -> Option<&'static str> {
+    if options.frames.is_some() {
+        return Some("--frames");
+    } else if !options.key_schedule.is_empty() {
+        return Some("--keys");
+    } else if options.dump_screen_path.is_some() {
+        return Some("--dump-screen");
+    }
+    return None;
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    return value
+        .parse()
+        .map_err(|_| format!("{} needs a whole number, got '{}'", flag, value));
+}
+
+/// RRGGBB, with or without a leading #
+pub fn parse_colour(flag: &str, value: &str) -> Result<Rgb, String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let error = || format!("{} needs a colour like ff8800, got '{}'", flag, value);
+    if hex.len() != 6 || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return Err(error());
+    }
+    let colour = u32::from_str_radix(hex, 16).map_err(|_| error())?;
+    return Ok(((colour >> 16) as u8, (colour >> 8) as u8, colour as u8));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        return parse_args(args.iter().map(|arg| arg.to_string()));
+    }
+
+    #[test]
+    fn parses_every_flag() {
+        let options = parse(&[
+            "--cycles-per-frame",
+            "15",
+            "--quirks",
+            "vip",
+            "--no-display-wait",
+            "--scale",
+            "10",
+            "--fg",
+            "#ff8800",
+            "--bg",
+            "102030",
+            "--fullscreen",
+            "--no-vsync",
+            "--mute",
+            "--paused",
+            "--seed",
+            "42",
+            "--keymap",
+            "keys.toml",
+            "--rewind-memory",
+            "8",
+            "--config",
+            "chip8.toml",
+            "--print-config",
+            "pong.ch8",
+        ])
+        .unwrap();
+
+        assert_eq!(options.rom_path.as_deref(), Some("pong.ch8"));
+        assert_eq!(
+            options.execution_model,
+            Some(ExecutionModel::InstructionsPerFrame(15))
+        );
+        assert_eq!(options.quirks.as_deref(), Some("vip"));
+        assert_eq!(options.display_wait, Some(false));
+        assert_eq!(options.scale, Some(10));
+        assert_eq!(options.foreground, Some((0xff, 0x88, 0x00)));
+        assert_eq!(options.background, Some((0x10, 0x20, 0x30)));
+        assert!(options.fullscreen && options.no_vsync && options.mute && options.paused);
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(options.keymap_path.as_deref(), Some("keys.toml"));
+        assert_eq!(options.rewind_memory_in_megabytes, Some(8));
+        assert_eq!(options.config_path.as_deref(), Some("chip8.toml"));
+        assert!(options.print_config);
+
+        let headless = parse(&[
+            "--headless",
+            "--frames",
+            "100",
+            "--keys",
+            "1+2",
+            "--dump-screen",
+            "out.pbm",
+            "--ips",
+            "700",
+        ])
+        .unwrap();
+        assert!(headless.headless);
+        assert_eq!(headless_only_flag(&headless), Some("--frames"));
+        assert_eq!(headless.frames, Some(100));
+        assert_eq!(headless.key_schedule.len(), 1);
+        assert_eq!(headless.dump_screen_path.as_deref(), Some("out.pbm"));
+        assert_eq!(
+            headless.execution_model,
+            Some(ExecutionModel::InstructionsPerFrame(11))
+        );
+        assert_eq!(
+            parse(&["--unlimited"]).unwrap().execution_model,
+            Some(ExecutionModel::Unlimited)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(
+            parse(&["--scale"]),
+            Err("--scale needs a value".to_string())
+        );
+        assert_eq!(
+            parse(&["--seed", "abc"]),
+            Err("--seed needs a whole number, got 'abc'".to_string())
+        );
+        assert!(parse(&["--quirks", "gameboy"]).is_err());
+        assert!(parse(&["--fg", "red"]).is_err());
+        assert!(parse(&["--fg", "+12345"]).is_err());
+        assert!(parse(&["--fg", "##ff8800"]).is_err());
+        assert!(parse(&["--scale", "0"]).is_err());
+        assert!(parse(&["--turbo"]).is_err());
+        assert!(parse(&["a.ch8", "b.ch8"]).is_err());
+        assert!(parse(&["--help"]).unwrap().show_help);
+        assert_eq!(headless_only_flag(&parse(&["--seed", "1"]).unwrap()), None);
+    }
+}
@@ -0,0 +1,857 @@
+use crate::chip::{Chip8, ExecutionModel, LoadStoreIncrement, Quirks};
+use crate::cli::{parse_colour, Options, Rgb};
+use crate::database::RomSettings;
+use crate::rewind::DEFAULT_REWIND_BUFFER_SIZE_IN_BYTES;
+
+/// Something the frontend can bind a key to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Command {
+    Step,
+    Pause,
+    PressKeyOnKeypad(u8),
+    SaveState(u8),
+    LoadState(u8),
+    /// Held down to step backwards a frame at a time
+    Rewind,
+    /// Switches to the next ExecutionModel
+    CycleExecutionModel,
+}
+
+impl Command {
+    /// pause, step, rewind, speed, key <0-F>, save <slot>, load <slot>, or none for a key that
+    /// does nothing
+    pub fn parse(text: &str) -> Result<Option<Command>, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let parse_slot = |slot: &str| {
+            return slot
+                .parse::<u8>()
+                .map_err(|_| format!("'{}' is not a save slot number", slot));
+        };
+        let command = match words.as_slice() {
+            ["none"] => None,
+            ["pause"] => Some(Command::Pause),
+            ["step"] => Some(Command::Step),
+            ["rewind"] => Some(Command::Rewind),
+            ["speed"] => Some(Command::CycleExecutionModel),
+            ["key", key] => match u8::from_str_radix(key, 16) {
+                Ok(key) if key <= 0xF => Some(Command::PressKeyOnKeypad(key)),
+                _ => return Err(format!("'{}' is not a keypad key from 0 to F", key)),
+            },
+            ["save", slot] => Some(Command::SaveState(parse_slot(slot)?)),
+            ["load", slot] => Some(Command::LoadState(parse_slot(slot)?)),
+            _ => return Err(format!("unknown command '{}'", text)),
+        };
+        return Ok(command);
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Step => write!(f, "step"),
+            Command::Pause => write!(f, "pause"),
+            Command::PressKeyOnKeypad(key) => write!(f, "key {:X}", key),
+            Command::SaveState(slot) => write!(f, "save {}", slot),
+            Command::LoadState(slot) => write!(f, "load {}", slot),
+            Command::Rewind => write!(f, "rewind"),
+            Command::CycleExecutionModel => write!(f, "speed"),
+        }
+    }
+}
+
+/// Keyboard keys, by their SDL name, to what they do
+pub type Keymap = Vec<(String, Command)>;
+
+pub fn default_keymap() -> Keymap {
+    let mut keymap = vec![
+        ("P".to_string(), Command::Pause),
+        ("N".to_string(), Command::Step),
+        ("R".to_string(), Command::Rewind),
+        ("M".to_string(), Command::CycleExecutionModel),
+    ];
+    for key in 0x0..=0xF {
+        keymap.push((format!("{:X}", key), Command::PressKeyOnKeypad(key)));
+    }
+    for slot in 1..=4 {
+        keymap.push((format!("F{}", slot), Command::SaveState(slot)));
+        keymap.push((format!("F{}", slot + 4), Command::LoadState(slot)));
+    }
+    return keymap;
+}
+
+/// Binds key to command, replacing whatever it did before. None unbinds it.
+pub fn bind_key(keymap: &mut Keymap, key: &str, command: Option<Command>) {
+    keymap.retain(|(bound_key, _)| !bound_key.eq_ignore_ascii_case(key));
+    if let Some(command) = command {
+        keymap.push((key.to_string(), command));
+    }
+}
+
+/// Where the config file and the user's ROM database live:
+/// $XDG_CONFIG_HOME/chip-8-emulator, or ~/.config/chip-8-emulator
+pub fn config_dir() -> Option<std::path::PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    return Some(config_home.join("chip-8-emulator"));
+}
+
+/// Every setting the frontend has, from the defaults, then the config file, then the ROM
+/// database, then the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub quirks: Quirks,
+    /// Set with the speed key
+    pub execution_model: ExecutionModel,
+    /// Window is this times the size of the 64x32 display. None is a 1024x768 window
+    pub scale: Option<u32>,
+    pub foreground: Rgb,
+    pub background: Rgb,
+    /// XO-CHIP's second plane, and where it overlaps the first (foreground)
+    pub second_plane: Rgb,
+    pub both_planes: Rgb,
+    /// Wait for the display to refresh before drawing. The emulator runs at the same speed either
+    /// way, see FrameScheduler
+    pub vsync: bool,
+    /// 0 to 100
+    pub volume: u8,
+    pub rewind_memory_in_megabytes: usize,
+    pub keymap: Keymap,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        return Config {
+            quirks: Quirks::superchip(),
+            execution_model: ExecutionModel::VipTiming,
+            scale: None,
+            foreground: (255, 255, 255),
+            background: (0, 0, 0),
+            second_plane: (170, 170, 170),
+            both_planes: (85, 85, 85),
+            vsync: true,
+            volume: 25,
+            rewind_memory_in_megabytes: DEFAULT_REWIND_BUFFER_SIZE_IN_BYTES / 1024 / 1024,
+            keymap: default_keymap(),
+        };
+    }
+}
+
+impl Config {
+    /// Where the config file lives if --config isn't given
+    pub fn default_path() -> Option<std::path::PathBuf> {
+        return Some(config_dir()?.join("config.toml"));
+    }
+
+    /// The defaults, then the config file (--config, or default_path if it exists), then what
+    /// the ROM database knows about the ROM, then the --keymap file, then the rest of the
+    /// command line
+    pub fn load(options: &Options, rom_settings: Option<&RomSettings>) -> Result<Config, String> {
+        let config_path = match &options.config_path {
+            Some(path) => Some(std::path::PathBuf::from(path)),
+            None => Config::default_path().filter(|path| path.exists()),
+        };
+        return Config::load_from(config_path, options, rom_settings);
+    }
+
+    /// Like load, but only reads a config file if --config names one, so the same ROM and
+    /// command line give the same settings on every machine
+    pub fn load_reproducible(
+        options: &Options,
+        rom_settings: Option<&RomSettings>,
+    ) -> Result<Config, String> {
+        let config_path = options.config_path.as_ref().map(std::path::PathBuf::from);
+        return Config::load_from(config_path, options, rom_settings);
+    }
+
+    fn load_from(
+        config_path: Option<std::path::PathBuf>,
+        options: &Options,
+        rom_settings: Option<&RomSettings>,
+    ) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        if let Some(path) = config_path {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Couldn't read config {}: {}", path.display(), e))?;
+            config
+                .apply_toml(&text)
+                .map_err(|e| format!("Couldn't load config {}: {}", path.display(), e))?;
+        }
+
+        if let Some(rom_settings) = rom_settings {
+            config.apply_rom_settings(rom_settings);
+        }
+
+        if let Some(path) = &options.keymap_path {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("Couldn't read keymap {}: {}", path, e))?;
+            config
+                .apply_keymap_toml(&text)
+                .map_err(|e| format!("Couldn't load keymap {}: {}", path, e))?;
+        }
+
+        config.apply_options(options);
+        return Ok(config);
+    }
+
+    /// A chip running the ROM with these quirks and speed. Random numbers are only reproducible
+    /// between runs when given a seed
+    pub fn new_chip(&self, rom: &[u8], seed: Option<u64>) -> Chip8 {
+        let mut chip = match seed {
+            Some(seed) => Chip8::with_seed(rom, self.quirks, seed),
+            None => Chip8::new(rom, self.quirks),
+        };
+        chip.execution_model = self.execution_model;
+        return chip;
+    }
+
+    /// Applies the settings in a config file on top of this one
+    pub fn apply_toml(&mut self, text: &str) -> Result<(), String> {
+        for entry in parse_toml(text)? {
+            let line_error = |message: String| format!("line {}: {}", entry.line, message);
+            match (entry.table.as_str(), entry.key.as_str(), &entry.value) {
+                ("", "quirks", Value::String(preset)) => match Quirks::from_preset_name(preset) {
+                    Some(quirks) => self.quirks = quirks,
+                    None => {
+                        return Err(line_error(format!(
+                            "quirks doesn't know '{}'. Use vip, chip48, schip or xochip",
+                            preset
+                        )))
+                    }
+                },
+                ("quirks", quirk, value) => {
+                    self.apply_quirk(quirk, value).map_err(line_error)?;
+                }
+                ("", "speed", Value::String(speed)) if speed == "vip" => {
+                    self.execution_model = ExecutionModel::VipTiming;
+                }
+                ("", "speed", Value::String(speed)) if speed == "unlimited" => {
+                    self.execution_model = ExecutionModel::Unlimited;
+                }
+                ("", "speed", Value::Integer(instructions_per_frame)) => {
+                    let instructions_per_frame = u32::try_from(*instructions_per_frame)
+                        .map_err(|_| line_error("speed can't be negative".to_string()))?;
+                    self.execution_model =
+                        ExecutionModel::InstructionsPerFrame(instructions_per_frame);
+                }
+                ("", "scale", Value::Integer(scale)) => match u32::try_from(*scale) {
+                    Ok(scale) if scale > 0 => self.scale = Some(scale),
+                    _ => return Err(line_error("scale needs to be at least 1".to_string())),
+                },
+                ("", "foreground", Value::String(colour)) => {
+                    self.foreground = parse_colour("foreground", colour).map_err(line_error)?;
+                }
+                ("", "background", Value::String(colour)) => {
+                    self.background = parse_colour("background", colour).map_err(line_error)?;
+                }
+                ("", "second_plane", Value::String(colour)) => {
+                    self.second_plane = parse_colour("second_plane", colour).map_err(line_error)?;
+                }
+                ("", "both_planes", Value::String(colour)) => {
+                    self.both_planes = parse_colour("both_planes", colour).map_err(line_error)?;
+                }
+                ("", "vsync", Value::Boolean(vsync)) => self.vsync = *vsync,
+                ("", "volume", Value::Integer(volume)) => match u8::try_from(*volume) {
+                    Ok(volume) if volume <= 100 => self.volume = volume,
+                    _ => return Err(line_error("volume goes from 0 to 100".to_string())),
+                },
+                ("", "rewind_memory", Value::Integer(megabytes)) => {
+                    self.rewind_memory_in_megabytes = usize::try_from(*megabytes)
+                        .map_err(|_| line_error("rewind_memory can't be negative".to_string()))?;
+                }
+                ("keymap", key, Value::String(command)) => {
+                    let command = Command::parse(command).map_err(line_error)?;
+                    bind_key(&mut self.keymap, key, command);
+                }
+                _ => {
+                    let name = if entry.table.is_empty() {
+                        entry.key.clone()
+                    } else {
+                        format!("{}.{}", entry.table, entry.key)
+                    };
+                    return Err(line_error(format!(
+                        "{} can't be set to {}",
+                        name, entry.value
+                    )));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Quirks are set one by one in the [quirks] table, named after the fields of Quirks
+    fn apply_quirk(&mut self, quirk: &str, value: &Value) -> Result<(), String> {
+        let quirks = &mut self.quirks;
+        match (quirk, value) {
+            ("load_store_i_increment", Value::String(increment)) => {
+                quirks.load_store_i_increment = match increment.as_str() {
+                    "unchanged" => LoadStoreIncrement::Unchanged,
+                    "x" => LoadStoreIncrement::X,
+                    "x+1" => LoadStoreIncrement::XPlusOne,
+                    _ => {
+                        return Err(format!(
+                            "load_store_i_increment is unchanged, x or x+1, not '{}'",
+                            increment
+                        ))
+                    }
+                };
+            }
+            (_, Value::Boolean(enabled)) => {
+                let field = match quirk {
+                    "shift_uses_vy" => &mut quirks.shift_uses_vy,
+                    "logic_ops_reset_vf" => &mut quirks.logic_ops_reset_vf,
+                    "clip_sprites" => &mut quirks.clip_sprites,
+                    "jump_with_offset_uses_vx" => &mut quirks.jump_with_offset_uses_vx,
+                    "display_wait" => &mut quirks.display_wait,
+                    "key_wait_on_release" => &mut quirks.key_wait_on_release,
+                    "xo_chip" => &mut quirks.xo_chip,
+                    _ => return Err(format!("unknown quirk '{}'", quirk)),
+                };
+                *field = *enabled;
+            }
+            _ => return Err(format!("quirks.{} can't be set to {}", quirk, value)),
+        }
+        return Ok(());
+    }
+
+    /// The ROM database wins over the config file, since it knows what this particular ROM needs
+    pub fn apply_rom_settings(&mut self, rom_settings: &RomSettings) {
+        if let Some(tickrate) = rom_settings.tickrate {
+            self.execution_model = ExecutionModel::InstructionsPerFrame(tickrate);
+        }
+        if let Some(quirks) = rom_settings.quirks {
+            self.quirks = quirks;
+        }
+        if let Some(foreground) = rom_settings.foreground {
+            self.foreground = foreground;
+        }
+        if let Some(background) = rom_settings.background {
+            self.background = background;
+        }
+        if let Some(second_plane) = rom_settings.second_plane {
+            self.second_plane = second_plane;
+        }
+        if let Some(both_planes) = rom_settings.both_planes {
+            self.both_planes = both_planes;
+        }
+        for (key, keypad_key) in &rom_settings.keys {
+            bind_key(
+                &mut self.keymap,
+                key,
+                Some(Command::PressKeyOnKeypad(*keypad_key)),
+            );
+        }
+    }
+
+    /// Keymap files are just the [keymap] table of a config file, without the header
+    pub fn apply_keymap_toml(&mut self, text: &str) -> Result<(), String> {
+        return self
+            .apply_toml(&format!("[keymap]\n{}", text))
+            .map_err(|e| {
+                // Don't count the line we added
+                match e.strip_prefix("line ").and_then(|e| e.split_once(':')) {
+                    Some((line, message)) => match line.parse::<usize>() {
+                        Ok(line) => format!("line {}:{}", line - 1, message),
+                        Err(_) => e,
+                    },
+                    None => e,
+                }
+            });
+    }
+
+    /// The command line wins over everything else
+    pub fn apply_options(&mut self, options: &Options) {
+        if let Some(preset) = &options.quirks {
+            // Checked by parse_args
+            self.quirks = Quirks::from_preset_name(preset).unwrap();
+        }
+        if let Some(display_wait) = options.display_wait {
+            self.quirks.display_wait = display_wait;
+        }
+        if let Some(execution_model) = options.execution_model {
+            self.execution_model = execution_model;
+        }
+        if let Some(scale) = options.scale {
+            self.scale = Some(scale);
+        }
+        if let Some(foreground) = options.foreground {
+            self.foreground = foreground;
+        }
+        if let Some(background) = options.background {
+            self.background = background;
+        }
+        if options.no_vsync {
+            self.vsync = false;
+        }
+        if options.mute {
+            self.volume = 0;
+        }
+        if let Some(megabytes) = options.rewind_memory_in_megabytes {
+            self.rewind_memory_in_megabytes = megabytes;
+        }
+    }
+
+    /// Written so it can be used as a config file as is
+    pub fn to_toml(&self) -> String {
+        let mut toml = String::new();
+        if let Some(preset) = self.quirks.preset_name() {
+            toml.push_str("# vip, chip48, schip or xochip\n");
+            toml.push_str(&format!("quirks = \"{}\"\n", preset));
+        }
+        toml.push_str(
+            "# \"vip\" to time instructions like the COSMAC VIP, instructions per frame, or\n# \"unlimited\"\n",
+        );
+        match self.execution_model {
+            ExecutionModel::VipTiming => toml.push_str("speed = \"vip\"\n"),
+            ExecutionModel::Unlimited => toml.push_str("speed = \"unlimited\"\n"),
+            ExecutionModel::InstructionsPerFrame(instructions_per_frame) => {
+                toml.push_str(&format!("speed = {}\n", instructions_per_frame))
+            }
+        }
+        match self.scale {
+            Some(scale) => toml.push_str(&format!("scale = {}\n", scale)),
+            None => toml.push_str("# scale isn't set, so the window is 1024x768\n"),
+        }
+        let (r, g, b) = self.foreground;
+        toml.push_str(&format!("foreground = \"{:02x}{:02x}{:02x}\"\n", r, g, b));
+        let (r, g, b) = self.background;
+        toml.push_str(&format!("background = \"{:02x}{:02x}{:02x}\"\n", r, g, b));
+        toml.push_str("# XO-CHIP's second plane, and where it overlaps the first\n");
+        let (r, g, b) = self.second_plane;
+        toml.push_str(&format!("second_plane = \"{:02x}{:02x}{:02x}\"\n", r, g, b));
+        let (r, g, b) = self.both_planes;
+        toml.push_str(&format!("both_planes = \"{:02x}{:02x}{:02x}\"\n", r, g, b));
+        toml.push_str(&format!("vsync = {}\n", self.vsync));
+        toml.push_str(&format!("volume = {}\n", self.volume));
+        toml.push_str("# In megabytes\n");
+        toml.push_str(&format!(
+            "rewind_memory = {}\n",
+            self.rewind_memory_in_megabytes
+        ));
+        if self.quirks.preset_name().is_none() {
+            let quirks = &self.quirks;
+            toml.push_str("\n[quirks]\n");
+            toml.push_str(&format!("shift_uses_vy = {}\n", quirks.shift_uses_vy));
+            let increment = match quirks.load_store_i_increment {
+                LoadStoreIncrement::Unchanged => "unchanged",
+                LoadStoreIncrement::X => "x",
+                LoadStoreIncrement::XPlusOne => "x+1",
+            };
+            toml.push_str(&format!("load_store_i_increment = \"{}\"\n", increment));
+            toml.push_str(&format!(
+                "logic_ops_reset_vf = {}\n",
+                quirks.logic_ops_reset_vf
+            ));
+            toml.push_str(&format!("clip_sprites = {}\n", quirks.clip_sprites));
+            toml.push_str(&format!(
+                "jump_with_offset_uses_vx = {}\n",
+                quirks.jump_with_offset_uses_vx
+            ));
+            toml.push_str(&format!("display_wait = {}\n", quirks.display_wait));
+            toml.push_str(&format!(
+                "key_wait_on_release = {}\n",
+                quirks.key_wait_on_release
+            ));
+            toml.push_str(&format!("xo_chip = {}\n", quirks.xo_chip));
+        }
+        toml.push_str("\n[keymap]\n");
+        for (key, command) in &self.keymap {
+            toml.push_str(&format!(
+                "{} = {}\n",
+                toml_key(key),
+                toml_string(&command.to_string())
+            ));
+        }
+        // Reading the config starts from the default keymap, so keys that have been unbound need
+        // saying so or they'd come back
+        for (default_key, _) in default_keymap() {
+            let is_bound = self
+                .keymap
+                .iter()
+                .any(|(key, _)| key.eq_ignore_ascii_case(&default_key));
+            if !is_bound {
+                toml.push_str(&format!("{} = \"none\"\n", toml_key(&default_key)));
+            }
+        }
+        return toml;
+    }
+}
+
+/// Quotes keys that can't be bare, like "Left Shift"
+fn toml_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(is_bare_key_char) {
+        return key.to_string();
+    }
+    return toml_string(key);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(string) => write!(f, "{}", toml_string(string)),
+            Value::Integer(integer) => write!(f, "{}", integer),
+            Value::Boolean(boolean) => write!(f, "{}", boolean),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Empty for keys before the first [table]
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// The bit of TOML we need: [tables], key = value with string, integer and boolean values, and
+/// comments. Keys can be bare or quoted, and strings can be "basic" (with escapes) or 'literal'.
+/// No arrays, inline tables or dotted keys.
+pub fn parse_toml(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut table = String::new();
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let line_error = |e: String| format!("line {}: {}", line_number, e);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let Some((name, rest)) = name.split_once(']') else {
+                return Err(line_error("expected ] after the table name".to_string()));
+            };
+            expect_end_of_line(rest).map_err(line_error)?;
+            table = name.trim().to_string();
+            continue;
+        }
+
+        let (key, rest) = parse_key(line).map_err(line_error)?;
+        let Some(rest) = rest.trim_start().strip_prefix('=') else {
+            return Err(line_error("expected key = value".to_string()));
+        };
+        let (value, rest) = parse_value(rest.trim_start()).map_err(line_error)?;
+        expect_end_of_line(rest).map_err(line_error)?;
+
+        entries.push(Entry {
+            table: table.clone(),
+            key,
+            value,
+            line: line_number,
+        });
+    }
+    return Ok(entries);
+}
+
+/// A bare or quoted key, and what's left of the line after it
+fn parse_key(text: &str) -> Result<(String, &str), String> {
+    if text.starts_with('"') || text.starts_with('\'') {
+        let (key, rest) = parse_string(text)?;
+        if key.is_empty() {
+            return Err("missing a key before =".to_string());
+        }
+        return Ok((key, rest));
+    }
+    let end = text
+        .find(|ch: char| !is_bare_key_char(ch))
+        .unwrap_or(text.len());
+    if end == 0 {
+        return Err("missing a key before =".to_string());
+    }
+    return Ok((text[..end].to_string(), &text[end..]));
+}
+
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if text.starts_with('"') || text.starts_with('\'') {
+        let (string, rest) = parse_string(text)?;
+        return Ok((Value::String(string), rest));
+    }
+    let end = text.find(['#', ' ', '\t']).unwrap_or(text.len());
+    let (value, rest) = text.split_at(end);
+    if value == "true" || value == "false" {
+        return Ok((Value::Boolean(value == "true"), rest));
+    }
+    return match value.replace('_', "").parse() {
+        Ok(integer) => Ok((Value::Integer(integer), rest)),
+        Err(_) => Err(format!(
+            "'{}' should be a \"string\", number, true or false",
+            value
+        )),
+    };
+}
+
+/// A "basic" string with escapes, or a 'literal' one without, starting at the first character of
+/// text. Returns the string and what's left of the line after the closing quote.
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    let mut chars = text.char_indices();
+    let Some((_, quote)) = chars.next() else {
+        return Err("expected a string".to_string());
+    };
+    let mut string = String::new();
+    while let Some((idx, ch)) = chars.next() {
+        match ch {
+            _ if ch == quote => return Ok((string, &text[idx + 1..])),
+            '\\' if quote == '"' => {
+                let escaped = match chars.next() {
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    Some((_, 'r')) => '\r',
+                    Some((_, 'b')) => '\u{8}',
+                    Some((_, 'f')) => '\u{c}',
+                    Some((_, 'u')) => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, ch)| ch).collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| hex.len() == 4)
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("'\\u{}' is not a character", hex))?
+                    }
+                    Some((_, other)) => return Err(format!("unknown escape '\\{}'", other)),
+                    None => break,
+                };
+                string.push(escaped);
+            }
+            _ => string.push(ch),
+        }
+    }
+    return Err("unterminated string".to_string());
+}
+
+/// Only a comment can come after a value or table name
+fn expect_end_of_line(rest: &str) -> Result<(), String> {
+    let rest = rest.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        return Ok(());
+    }
+    return Err(format!("unexpected '{}' at the end of the line", rest));
+}
+
+fn is_bare_key_char(ch: char) -> bool {
+    return ch.is_ascii_alphanumeric() || ch == '_' || ch == '-';
+}
+
+/// A "basic" string, escaping anything that would end it early or can't be written as is
+fn toml_string(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in string.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            _ if ch.is_control() => quoted.push_str(&format!("\\u{:04X}", ch as u32)),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    return quoted;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_args;
+
+    #[test]
+    fn parses_a_config_file() {
+        let mut config = Config::default();
+        config
+            .apply_toml(
+                r##"
+                # Comments are fine
+                quirks = "vip"
+                speed = 15 # so are trailing comments
+                scale = 10
+                foreground = "#ff8800"
+                background = "102030"
+                vsync = false
+                volume = 50
+                rewind_memory = 8
+
+                [keymap]
+                Q = "key 4"
+                "Left Shift" = "rewind"
+                R = "none"
+                "##,
+            )
+            .unwrap();
+
+        assert_eq!(config.quirks, Quirks::cosmac_vip());
+        assert_eq!(
+            config.execution_model,
+            ExecutionModel::InstructionsPerFrame(15)
+        );
+        assert_eq!(config.scale, Some(10));
+        assert_eq!(config.foreground, (0xff, 0x88, 0x00));
+        assert_eq!(config.background, (0x10, 0x20, 0x30));
+        assert!(!config.vsync);
+        assert_eq!(config.volume, 50);
+        assert_eq!(config.rewind_memory_in_megabytes, 8);
+        assert!(config
+            .keymap
+            .contains(&("Q".to_string(), Command::PressKeyOnKeypad(4))));
+        assert!(config
+            .keymap
+            .contains(&("Left Shift".to_string(), Command::Rewind)));
+        assert!(!config.keymap.iter().any(|(key, _)| key == "R"));
+    }
+
+    #[test]
+    fn reports_bad_config_files() {
+        let error = |text: &str| Config::default().apply_toml(text).unwrap_err();
+        assert_eq!(
+            error("quirks = \"gameboy\""),
+            "line 1: quirks doesn't know 'gameboy'. Use vip, chip48, schip or xochip"
+        );
+        assert_eq!(error("\nvolume = 101"), "line 2: volume goes from 0 to 100");
+        assert_eq!(
+            error("scale = \"big\""),
+            "line 1: scale can't be set to \"big\""
+        );
+        assert_eq!(
+            error("[keymap]\nQ = \"jump\""),
+            "line 2: unknown command 'jump'"
+        );
+        assert_eq!(error("volume"), "line 1: expected key = value");
+        assert_eq!(
+            Config::default()
+                .apply_keymap_toml("Q = \"key 4\"\nW = \"key 10\"")
+                .unwrap_err(),
+            "line 2: '10' is not a keypad key from 0 to F"
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_file() {
+        let mut config = Config::default();
+        config.apply_toml("quirks = \"vip\"\nvolume = 50").unwrap();
+        let options = parse_args(["--quirks", "xochip", "--mute"].map(String::from)).unwrap();
+        config.apply_options(&options);
+        assert_eq!(config.quirks, Quirks::xo_chip());
+
+        let options = parse_args(["--display-wait"].map(String::from)).unwrap();
+        config.apply_options(&options);
+        assert!(config.quirks.display_wait);
+        assert!(!Quirks::xo_chip().display_wait);
+        assert_eq!(config.volume, 0);
+    }
+
+    #[test]
+    fn rom_settings_win_over_the_config_file() {
+        let mut config = Config::default();
+        config
+            .apply_toml("speed = 20\nforeground = \"ff0000\"\n[keymap]\nUp = \"pause\"")
+            .unwrap();
+        config.apply_rom_settings(&RomSettings {
+            title: "Game".to_string(),
+            tickrate: Some(9),
+            quirks: Some(Quirks::cosmac_vip()),
+            foreground: None,
+            background: Some((0x10, 0x10, 0x10)),
+            second_plane: Some((0x20, 0x20, 0x20)),
+            both_planes: None,
+            keys: vec![("Up".to_string(), 1)],
+        });
+
+        assert_eq!(
+            config.execution_model,
+            ExecutionModel::InstructionsPerFrame(9)
+        );
+        assert_eq!(config.quirks, Quirks::cosmac_vip());
+        assert_eq!(config.foreground, (0xff, 0x00, 0x00));
+        assert_eq!(config.background, (0x10, 0x10, 0x10));
+        assert_eq!(config.second_plane, (0x20, 0x20, 0x20));
+        assert_eq!(config.both_planes, Config::default().both_planes);
+        assert!(config
+            .keymap
+            .contains(&("Up".to_string(), Command::PressKeyOnKeypad(1))));
+        assert!(!config.keymap.contains(&("Up".to_string(), Command::Pause)));
+    }
+
+    #[test]
+    fn printed_config_reads_back_the_same() {
+        let mut config = Config::default();
+        config
+            .apply_toml(
+                "speed = 20\nscale = 8\nsecond_plane = \"123456\"\n[quirks]\nclip_sprites = false\nload_store_i_increment = \"x\"\n[keymap]\n\"Left Shift\" = \"save 9\"\nR = \"none\"",
+            )
+            .unwrap();
+        assert_eq!(config.quirks.preset_name(), None);
+        assert!(config.to_toml().contains("R = \"none\"\n"));
+
+        let mut reread = Config::default();
+        reread.apply_toml(&config.to_toml()).unwrap();
+        assert_eq!(reread, config);
+    }
+    #[test]
+    fn every_bindable_key_reads_back() {
+        // SDL names every printable key after its character, and names the rest with words,
+        // some of which have spaces and symbols of their own
+        let mut key_names: Vec<String> = ('!'..='~').map(String::from).collect();
+        key_names.extend(
+            [
+                "Space",
+                "Return",
+                "Left Shift",
+                "Right Ctrl",
+                "Keypad =",
+                "Keypad #",
+                "Keypad \"",
+                "Keypad &&",
+                "Keypad {",
+                "Keypad Enter",
+                "F12",
+            ]
+            .map(String::from),
+        );
+
+        for key in key_names {
+            let mut config = Config::default();
+            bind_key(&mut config.keymap, &key, Some(Command::Pause));
+            let mut reread = Config::default();
+            reread.apply_toml(&config.to_toml()).unwrap_or_else(|e| {
+                panic!("{} didn't read back: {}\n{}", key, e, config.to_toml())
+            });
+            assert_eq!(reread, config, "{} didn't read back", key);
+        }
+    }
+
+    #[test]
+    fn parses_quoted_keys_and_escapes() {
+        let entries =
+            parse_toml("\"=\" = \"a \\\"b\\\" \\u00e9\" # = \"c\"\n'\\' = 'x # y'").unwrap();
+        assert_eq!(entries[0].key, "=");
+        assert_eq!(
+            entries[0].value,
+            Value::String("a \"b\" \u{e9}".to_string())
+        );
+        assert_eq!(entries[1].key, "\\");
+        assert_eq!(entries[1].value, Value::String("x # y".to_string()));
+
+        assert_eq!(
+            parse_toml("a = \"\\q\"").unwrap_err(),
+            "line 1: unknown escape '\\q'"
+        );
+        assert_eq!(
+            parse_toml("\"a = 1").unwrap_err(),
+            "line 1: unterminated string"
+        );
+        assert_eq!(
+            parse_toml("a = 1 2").unwrap_err(),
+            "line 1: unexpected '2' at the end of the line"
+        );
+    }
+}
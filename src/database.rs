@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+
+use crate::chip::{LoadStoreIncrement, Quirks};
+use crate::cli::{parse_colour, Rgb};
+use crate::json::{parse_json, Json};
+use crate::sha1::sha1_hex;
+
+/// Settings for the ROMs in roms/, in the same format as the community chip-8-database's
+/// programs.json. See <https://github.com/chip-8/chip-8-database>
+const BUNDLED_DATABASE: &str = include_str!("../roms/chip-8-database.json");
+
+/// What the database knows about one ROM. Anything it doesn't know is left as None, so the
+/// config file's setting is used instead.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RomSettings {
+    pub title: String,
+    /// Instructions per frame
+    pub tickrate: Option<u32>,
+    pub quirks: Option<Quirks>,
+    pub foreground: Option<Rgb>,
+    pub background: Option<Rgb>,
+    /// XO-CHIP's second plane, and where it overlaps the first
+    pub second_plane: Option<Rgb>,
+    pub both_planes: Option<Rgb>,
+    /// Keyboard keys, by their SDL name, to the keypad keys the game uses for them. e.g the arrow
+    /// keys to whatever the game uses for up, down, left and right.
+    pub keys: Vec<(String, u8)>,
+}
+
+impl RomSettings {
+    /// Anything the other settings know replaces what these ones say
+    fn layer(&mut self, other: &RomSettings) {
+        if !other.title.is_empty() {
+            self.title = other.title.clone();
+        }
+        if other.tickrate.is_some() {
+            self.tickrate = other.tickrate;
+        }
+        if other.quirks.is_some() {
+            self.quirks = other.quirks;
+        }
+        if other.foreground.is_some() {
+            self.foreground = other.foreground;
+        }
+        if other.background.is_some() {
+            self.background = other.background;
+        }
+        if other.second_plane.is_some() {
+            self.second_plane = other.second_plane;
+        }
+        if other.both_planes.is_some() {
+            self.both_planes = other.both_planes;
+        }
+        // Applied in order, so later keys win
+        self.keys.extend(other.keys.iter().cloned());
+    }
+}
+
+/// ROM settings keyed by the SHA-1 of the ROM, as lowercase hex
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RomDatabase {
+    roms: HashMap<String, RomSettings>,
+}
+
+impl RomDatabase {
+    /// The bundled database, with the user's database.json from the config directory on top if
+    /// there is one
+    pub fn load() -> Result<RomDatabase, String> {
+        let mut database = RomDatabase::bundled();
+        let Some(override_path) = crate::config::config_dir().map(|dir| dir.join("database.json"))
+        else {
+            return Ok(database);
+        };
+        if !override_path.exists() {
+            return Ok(database);
+        }
+        let text = std::fs::read_to_string(&override_path).map_err(|e| {
+            format!(
+                "Couldn't read ROM database {}: {}",
+                override_path.display(),
+                e
+            )
+        })?;
+        let overrides = RomDatabase::from_json(&text).map_err(|e| {
+            format!(
+                "Couldn't load ROM database {}: {}",
+                override_path.display(),
+                e
+            )
+        })?;
+        database.layer(&overrides);
+        return Ok(database);
+    }
+
+    pub fn bundled() -> RomDatabase {
+        // Checked by the tests, so this can't fail at runtime
+        return RomDatabase::from_json(BUNDLED_DATABASE).unwrap();
+    }
+
+    /// Reads a chip-8-database programs.json: a list of programs, each with a title and its
+    /// ROMs keyed by hash. Fields we don't use are ignored.
+    pub fn from_json(text: &str) -> Result<RomDatabase, String> {
+        let json = parse_json(text)?;
+        let Some(programs) = json.as_array() else {
+            return Err("expected a list of programs".to_string());
+        };
+
+        let mut roms = HashMap::new();
+        for program in programs {
+            let title = program
+                .get("title")
+                .and_then(Json::as_str)
+                .unwrap_or_default();
+            let Some(program_roms) = program.get("roms").and_then(Json::as_object) else {
+                return Err(format!("'{}' has no roms", title));
+            };
+            for (hash, rom) in program_roms {
+                let settings = rom_settings(title, rom)
+                    .map_err(|e| format!("'{}' ({}): {}", title, hash, e))?;
+                roms.insert(hash.to_lowercase(), settings);
+            }
+        }
+        return Ok(RomDatabase { roms });
+    }
+
+    /// Settings in other replace the ones here, field by field
+    pub fn layer(&mut self, other: &RomDatabase) {
+        for (hash, settings) in &other.roms {
+            self.roms.entry(hash.clone()).or_default().layer(settings);
+        }
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomSettings> {
+        return self.roms.get(&sha1_hex(rom));
+    }
+}
+
+fn rom_settings(title: &str, rom: &Json) -> Result<RomSettings, String> {
+    let mut settings = RomSettings {
+        title: title.to_string(),
+        ..Default::default()
+    };
+
+    if let Some(tickrate) = rom.get("tickrate") {
+        settings.tickrate = Some(
+            tickrate
+                .as_u32()
+                .ok_or_else(|| "tickrate should be a whole number".to_string())?,
+        );
+    }
+
+    if let Some(platforms) = rom.get("platforms") {
+        let platforms = platforms
+            .as_array()
+            .ok_or_else(|| "platforms should be a list".to_string())?;
+        // The first platform is the one the ROM was written for, but skip any we can't emulate
+        let platform = platforms
+            .iter()
+            .filter_map(Json::as_str)
+            .find_map(|platform| Some((platform, quirks_for_platform(platform)?)));
+        if let Some((platform, mut quirks)) = platform {
+            if let Some(quirky) = rom.get("quirkyPlatforms").and_then(|q| q.get(platform)) {
+                apply_quirky_platform(&mut quirks, quirky)?;
+            }
+            settings.quirks = Some(quirks);
+        }
+    }
+
+    if let Some(pixels) = rom.get("colors").and_then(|colors| colors.get("pixels")) {
+        let pixels = pixels
+            .as_array()
+            .ok_or_else(|| "colors.pixels should be a list".to_string())?;
+        // One for each combination of planes, like the palette
+        let colour = |idx: usize| -> Result<Option<Rgb>, String> {
+            return match pixels.get(idx) {
+                None => Ok(None),
+                Some(colour) => {
+                    let colour = colour
+                        .as_str()
+                        .ok_or_else(|| "colors.pixels should be strings".to_string())?;
+                    Ok(Some(parse_colour("colors.pixels", colour)?))
+                }
+            };
+        };
+        settings.background = colour(0)?;
+        settings.foreground = colour(1)?;
+        settings.second_plane = colour(2)?;
+        settings.both_planes = colour(3)?;
+    }
+
+    if let Some(keys) = rom.get("keys") {
+        let keys = keys
+            .as_object()
+            .ok_or_else(|| "keys should be an object".to_string())?;
+        for (name, keypad_key) in keys {
+            let keypad_key = match keypad_key.as_u32() {
+                Some(key) if key <= 0xF => key as u8,
+                _ => return Err(format!("keys.{} should be a keypad key from 0 to 15", name)),
+            };
+            // Player 2's keys are left unbound, there's nowhere obvious to put them that doesn't
+            // clash with the keypad
+            let key_name = match name.as_str() {
+                "up" => "Up",
+                "down" => "Down",
+                "left" => "Left",
+                "right" => "Right",
+                "a" => "Space",
+                "b" => "Return",
+                _ => continue,
+            };
+            settings.keys.push((key_name.to_string(), keypad_key));
+        }
+    }
+
+    return Ok(settings);
+}
+
+/// The chip-8-database platform IDs we have quirks for. See its platforms.json
+fn quirks_for_platform(platform: &str) -> Option<Quirks> {
+    return match platform {
+        "originalChip8" | "hybridVIP" => Some(Quirks::cosmac_vip()),
+        "modernChip8" => Some(Quirks {
+            logic_ops_reset_vf: false,
+            display_wait: false,
+            ..Quirks::cosmac_vip()
+        }),
+        "chip48" => Some(Quirks::chip48()),
+        "superchip1" | "superchip" => Some(Quirks::superchip()),
+        "xochip" => Some(Quirks::xo_chip()),
+        _ => None,
+    };
+}
+
+/// quirkyPlatforms says how the ROM differs from the platform's usual quirks
+fn apply_quirky_platform(quirks: &mut Quirks, quirky: &Json) -> Result<(), String> {
+    let Some(quirky) = quirky.as_object() else {
+        return Err("quirkyPlatforms should be an object".to_string());
+    };
+    // Both of these decide load_store_i_increment, so they're worked out together at the end
+    let mut increment_by_x = None;
+    let mut leave_i_unchanged = None;
+    for (quirk, enabled) in quirky {
+        let Some(enabled) = enabled.as_bool() else {
+            return Err(format!("quirk {} should be true or false", quirk));
+        };
+        match quirk.as_str() {
+            "shift" => quirks.shift_uses_vy = !enabled,
+            "memoryIncrementByX" => increment_by_x = Some(enabled),
+            "memoryLeaveIUnchanged" => leave_i_unchanged = Some(enabled),
+            "wrap" => quirks.clip_sprites = !enabled,
+            "jump" => quirks.jump_with_offset_uses_vx = enabled,
+            "vblank" => quirks.display_wait = enabled,
+            "logic" => quirks.logic_ops_reset_vf = enabled,
+            // e.g lores DXY0 behaviour, which we don't emulate differently
+            _ => {}
+        }
+    }
+    match (leave_i_unchanged, increment_by_x) {
+        (Some(true), _) => quirks.load_store_i_increment = LoadStoreIncrement::Unchanged,
+        (_, Some(true)) => quirks.load_store_i_increment = LoadStoreIncrement::X,
+        // Turned off without the other one turned on, so back to the VIP's behaviour
+        (Some(false), _) | (_, Some(false)) => {
+            quirks.load_store_i_increment = LoadStoreIncrement::XPlusOne
+        }
+        (None, None) => {}
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_database_matches_the_bundled_roms() {
+        let json = parse_json(BUNDLED_DATABASE).unwrap();
+        for program in json.as_array().unwrap() {
+            for (hash, rom) in program.get("roms").unwrap().as_object().unwrap() {
+                let file = rom.get("file").unwrap().as_str().unwrap();
+                let rom_bytes = std::fs::read(format!("roms/{}", file)).unwrap();
+                assert_eq!(&sha1_hex(&rom_bytes), hash, "{}", file);
+            }
+        }
+
+        let database = RomDatabase::bundled();
+        let space_invaders = std::fs::read("roms/Space Invaders [David Winter].ch8").unwrap();
+        let settings = database.lookup(&space_invaders).unwrap();
+        assert_eq!(settings.title, "Space Invaders");
+        assert_eq!(settings.tickrate, Some(15));
+        assert!(!settings.quirks.unwrap().shift_uses_vy);
+        assert_eq!(settings.foreground, Some((0x55, 0xff, 0x55)));
+        assert!(settings.keys.contains(&("Left".to_string(), 4)));
+
+        assert_eq!(database.lookup(b"not a rom in the database"), None);
+    }
+
+    #[test]
+    fn overrides_replace_bundled_settings() {
+        let mut database = RomDatabase::from_json(
+            r#"[{"title": "Game", "roms": {"ABCD": {
+                "platforms": ["megachip8", "superchip"], "tickrate": 10, "keys": {"a": 5}
+            }}}]"#,
+        )
+        .unwrap();
+        let overrides = RomDatabase::from_json(
+            r##"[{"title": "Game", "roms": {"abcd": {
+                "tickrate": 20,
+                "keys": {"a": 6},
+                "colors": {"pixels": ["#101010", "#f0f0f0", "#ff0000", "#00ff00"]}
+            }}}]"##,
+        )
+        .unwrap();
+        database.layer(&overrides);
+
+        let settings = &database.roms["abcd"];
+        assert_eq!(settings.tickrate, Some(20));
+        assert_eq!(settings.quirks, Some(Quirks::superchip()));
+        assert_eq!(settings.background, Some((0x10, 0x10, 0x10)));
+        assert_eq!(settings.second_plane, Some((0xff, 0x00, 0x00)));
+        assert_eq!(settings.both_planes, Some((0x00, 0xff, 0x00)));
+        assert_eq!(settings.keys.last(), Some(&("Space".to_string(), 6)));
+    }
+
+    #[test]
+    fn memory_quirks_dont_depend_on_their_order() {
+        for quirky in [
+            r#"{"memoryIncrementByX": true, "memoryLeaveIUnchanged": false}"#,
+            r#"{"memoryLeaveIUnchanged": false, "memoryIncrementByX": true}"#,
+        ] {
+            let mut quirks = Quirks::superchip();
+            apply_quirky_platform(&mut quirks, &parse_json(quirky).unwrap()).unwrap();
+            assert_eq!(
+                quirks.load_store_i_increment,
+                LoadStoreIncrement::X,
+                "{}",
+                quirky
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_broken_entries() {
+        let error = |text: &str| RomDatabase::from_json(text).unwrap_err();
+        assert_eq!(error("{}"), "expected a list of programs");
+        assert_eq!(error(r#"[{"title": "Game"}]"#), "'Game' has no roms");
+        assert_eq!(
+            error(r#"[{"title": "Game", "roms": {"abcd": {"tickrate": "fast"}}}]"#),
+            "'Game' (abcd): tickrate should be a whole number"
+        );
+        assert_eq!(
+            error(r#"[{"title": "Game", "roms": {"abcd": {"keys": {"up": 16}}}}]"#),
+            "'Game' (abcd): keys.up should be a keypad key from 0 to 15"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+/// Where a token came from in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Index of the first char, counting chars rather than bytes
+    pub start: usize,
+    /// Index one past the last char
+    pub end: usize,
+    /// Starts from 1
+    pub line: usize,
+    /// Starts from 1, counting chars
+    pub column: usize,
+}
+
+impl Span {
+    /// From the start of this span to the end of the other one
+    pub fn to(&self, other: Span) -> Span {
+        return Span {
+            end: other.end,
+            ..*self
+        };
+    }
+}
+
+/// Something wrong with the source, and where it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        return Diagnostic {
+            message: message.into(),
+            span,
+        };
+    }
+
+    /// The message with the line it's about, and carets under the part that's wrong. e.g
+    ///
+    /// ```text
+    /// game.asm:3:4: error: Label 'nowhere' is not defined
+    /// JP nowhere
+    ///    ^^^^^^^
+    /// ```
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let line = source.lines().nth(self.span.line - 1).unwrap_or_default();
+        let line_length = line.chars().count();
+        let padding = (self.span.column - 1).min(line_length);
+        // Spans that run past the end of the line (like a missing newline) still get one caret
+        let underline_length = (self.span.end - self.span.start)
+            .min(line_length.saturating_sub(padding))
+            .max(1);
+        return format!(
+            "{}:{}:{}: error: {}\n{}\n{}{}",
+            file_name,
+            self.span.line,
+            self.span.column,
+            self.message,
+            line,
+            " ".repeat(padding),
+            "^".repeat(underline_length)
+        );
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        );
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_line_with_carets() {
+        let source = "CLS\nJP nowhere\nRET\n";
+        let diagnostic = Diagnostic::new(
+            "Label 'nowhere' is not defined",
+            Span {
+                start: 7,
+                end: 14,
+                line: 2,
+                column: 4,
+            },
+        );
+        assert_eq!(
+            diagnostic.render("game.asm", source),
+            "game.asm:2:4: error: Label 'nowhere' is not defined\n\
+             JP nowhere\n   \
+             ^^^^^^^"
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            "2:4: Label 'nowhere' is not defined"
+        );
+    }
+}
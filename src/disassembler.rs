@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::instruction::{Address, Instruction};
+
+/// Where ROMs are loaded, and where execution starts
+const PROGRAM_START: usize = 0x200;
+/// How many bytes go on each DB line of data
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// Turns a ROM back into source the assembler accepts, so that assemble(disassemble(rom)) == rom.
+///
+/// Code is found by following every path the program can take from 0x200 (jumps, calls, both
+/// sides of skips), so data sitting between routines isn't mistaken for instructions. Anything
+/// that isn't reached is written out as DB bytes. Jump, call and LD I targets inside the ROM get
+/// labels named after their address.
+pub fn disassemble(rom: &[u8]) -> String {
+    let code = find_code(rom);
+    let labels = find_labels(rom, &code);
+
+    let mut source = String::new();
+    let mut offset = 0;
+    let mut data = Vec::new();
+    while offset < rom.len() {
+        let address = PROGRAM_START + offset;
+        if let Some(label) = labels.get(&address) {
+            write_data(&mut source, &mut data);
+            source.push_str(&format!("{}:\n", label));
+        }
+
+        if let Some(instruction) = code.get(&offset) {
+            write_data(&mut source, &mut data);
+            source.push_str(&instruction_source(*instruction, &labels));
+            source.push('\n');
+            offset += instruction.length_in_bytes();
+        } else {
+            data.push(rom[offset]);
+            if data.len() == DATA_BYTES_PER_LINE {
+                write_data(&mut source, &mut data);
+            }
+            offset += 1;
+        }
+    }
+    write_data(&mut source, &mut data);
+
+    return source;
+}
+
+/// Instructions reachable from the start of the ROM, by their offset into it
+fn find_code(rom: &[u8]) -> BTreeMap<usize, Instruction> {
+    let mut code = BTreeMap::new();
+    // Bytes that are part of an instruction we've already found, so overlapping instructions
+    // (jumping into the middle of another one) are left as data
+    let mut covered = vec![false; rom.len()];
+    let mut to_visit = vec![PROGRAM_START];
+
+    while let Some(address) = to_visit.pop() {
+        let Some(offset) = address.checked_sub(PROGRAM_START) else {
+            continue;
+        };
+        if offset + 1 >= rom.len() || covered[offset] || covered[offset + 1] {
+            continue;
+        }
+        let bytes = &rom[offset..(offset + 4).min(rom.len())];
+        let Ok(instruction) = Instruction::decode_bytes(bytes) else {
+            continue;
+        };
+        let length = instruction.length_in_bytes();
+        if offset + length > rom.len() || covered[offset..offset + length].contains(&true) {
+            continue;
+        }
+        covered[offset..offset + length].fill(true);
+        code.insert(offset, instruction);
+
+        let next = address + length;
+        match instruction {
+            Instruction::Jp(target) => to_visit.push(target as usize),
+            Instruction::Call(target) => {
+                to_visit.push(target as usize);
+                to_visit.push(next);
+            }
+            // Where this goes depends on V0, so all we know is the start of the jump table
+            Instruction::JpV0(target) => to_visit.push(target as usize),
+            // The end of a routine, or the program. SYS would run machine code we can't follow
+            Instruction::Ret | Instruction::Exit | Instruction::Sys(_) => {}
+            Instruction::SeVxByte(..)
+            | Instruction::SneVxByte(..)
+            | Instruction::SeVxVy(..)
+            | Instruction::SneVxVy(..)
+            | Instruction::Skp(_)
+            | Instruction::Sknp(_) => {
+                to_visit.push(next);
+                // Skips over the whole of the next instruction, which might be F000 nnnn
+                let next_offset = next - PROGRAM_START;
+                let skipped_length = match rom.get(next_offset..next_offset + 2) {
+                    Some(&[0xF0, 0x00]) => 4,
+                    _ => 2,
+                };
+                to_visit.push(next + skipped_length);
+            }
+            _ => to_visit.push(next),
+        }
+    }
+    return code;
+}
+
+/// Names for the addresses the code refers to. Only addresses inside the ROM get one, and not
+/// ones in the middle of an instruction, since there's nowhere to put the label
+fn find_labels(rom: &[u8], code: &BTreeMap<usize, Instruction>) -> BTreeMap<usize, String> {
+    let mut can_label = vec![true; rom.len()];
+    for (offset, instruction) in code {
+        can_label[offset + 1..offset + instruction.length_in_bytes()].fill(false);
+    }
+
+    let mut subroutines = BTreeSet::new();
+    let mut jumps = BTreeSet::new();
+    let mut data = BTreeSet::new();
+    for instruction in code.values() {
+        match *instruction {
+            Instruction::Call(target) => subroutines.insert(target as usize),
+            Instruction::Jp(target) | Instruction::JpV0(target) => jumps.insert(target as usize),
+            Instruction::LdIAddr(target) => data.insert(target as usize),
+            _ => continue,
+        };
+    }
+
+    let mut labels = BTreeMap::new();
+    // A routine that's also jumped to is still named after being a routine
+    for (prefix, addresses) in [("data", data), ("label", jumps), ("sub", subroutines)] {
+        for address in addresses {
+            let can_label = address
+                .checked_sub(PROGRAM_START)
+                .and_then(|offset| can_label.get(offset));
+            if can_label == Some(&true) {
+                labels.insert(address, format!("{}_{:03X}", prefix, address));
+            }
+        }
+    }
+    return labels;
+}
+
+/// The line of source for the instruction
+fn instruction_source(instruction: Instruction, labels: &BTreeMap<usize, String>) -> String {
+    let target = |address: Address| match labels.get(&(address as usize)) {
+        Some(label) => label.clone(),
+        None => format!("0x{:03X}", address),
+    };
+
+    return match instruction {
+        Instruction::Sys(address) => format!("SYS 0x{:03X}", address),
+        Instruction::Cls => "CLS".to_string(),
+        Instruction::Ret => "RET".to_string(),
+        Instruction::ScrollDown(n) => format!("SCD 0x{:X}", n),
+        Instruction::ScrollRight => "SCR".to_string(),
+        Instruction::ScrollLeft => "SCL".to_string(),
+        Instruction::Exit => "EXIT".to_string(),
+        Instruction::LowRes => "LOW".to_string(),
+        Instruction::HighRes => "HIGH".to_string(),
+        Instruction::Jp(address) => format!("JP {}", target(address)),
+        Instruction::Call(address) => format!("CALL {}", target(address)),
+        Instruction::SeVxByte(x, kk) => format!("SE V{:X}, 0x{:02X}", x, kk),
+        Instruction::SneVxByte(x, kk) => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        Instruction::SeVxVy(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        Instruction::LdIVxVy(x, y) => format!("LD [I], V{:X} - V{:X}", x, y),
+        Instruction::LdVxVyI(x, y) => format!("LD V{:X} - V{:X}, [I]", x, y),
+        Instruction::LdVxByte(x, kk) => format!("LD V{:X}, 0x{:02X}", x, kk),
+        Instruction::AddVxByte(x, kk) => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        Instruction::LdVxVy(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Instruction::Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        Instruction::And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Instruction::Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Instruction::AddVxVy(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Instruction::Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        Instruction::Shr(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        Instruction::Subn(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        Instruction::Shl(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        Instruction::SneVxVy(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        Instruction::LdIAddr(address) => format!("LD I, {}", target(address)),
+        Instruction::JpV0(address) => format!("JP V0, {}", target(address)),
+        Instruction::Rnd(x, kk) => format!("RND V{:X}, 0x{:02X}", x, kk),
+        Instruction::Drw(x, y, n) => format!("DRW V{:X}, V{:X}, 0x{:X}", x, y, n),
+        Instruction::Skp(x) => format!("SKP V{:X}", x),
+        Instruction::Sknp(x) => format!("SKNP V{:X}", x),
+        Instruction::LdILong(address) => format!("LD I, long 0x{:04X}", address),
+        Instruction::Plane(n) => format!("PLANE 0x{:X}", n),
+        Instruction::Audio => "AUDIO".to_string(),
+        Instruction::LdVxDt(x) => format!("LD V{:X}, DT", x),
+        Instruction::LdVxK(x) => format!("LD V{:X}, K", x),
+        Instruction::LdDtVx(x) => format!("LD DT, V{:X}", x),
+        Instruction::LdStVx(x) => format!("LD ST, V{:X}", x),
+        Instruction::AddIVx(x) => format!("ADD I, V{:X}", x),
+        Instruction::LdFVx(x) => format!("LD F, V{:X}", x),
+        Instruction::LdHfVx(x) => format!("LD HF, V{:X}", x),
+        Instruction::LdBVx(x) => format!("LD B, V{:X}", x),
+        Instruction::Pitch(x) => format!("PITCH V{:X}", x),
+        Instruction::LdIVx(x) => format!("LD [I], V{:X}", x),
+        Instruction::LdVxI(x) => format!("LD V{:X}, [I]", x),
+        Instruction::LdRVx(x) => format!("LD R, V{:X}", x),
+        Instruction::LdVxR(x) => format!("LD V{:X}, R", x),
+    };
+}
+
+fn data_source(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+    return format!("DB {}", bytes.join(", "));
+}
+
+/// Writes out any data bytes waiting to go on a line
+fn write_data(source: &mut String, data: &mut Vec<u8>) {
+    if data.is_empty() {
+        return;
+    }
+    source.push_str(&data_source(data));
+    source.push('\n');
+    data.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn separates_code_from_data() {
+        // CALL 0x206, JP 0x204, RET, then sprite data that's never run
+        let rom = [
+            0x22, 0x06, 0x12, 0x04, 0x00, 0xEE, 0xA2, 0x0A, 0x00, 0xEE, 0xF0, 0x90,
+        ];
+        assert_eq!(
+            disassemble(&rom),
+            "CALL sub_206\n\
+             JP label_204\n\
+             label_204:\n\
+             RET\n\
+             sub_206:\n\
+             LD I, data_20A\n\
+             RET\n\
+             data_20A:\n\
+             DB 0xF0, 0x90\n"
+        );
+    }
+
+    #[test]
+    fn every_bundled_rom_assembles_back_to_itself() {
+        for entry in std::fs::read_dir("roms").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|extension| extension != "ch8") {
+                continue;
+            }
+            let rom = std::fs::read(&path).unwrap();
+            let source = disassemble(&rom);
+            assert_eq!(assemble(source), Ok(rom), "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::chip::*;
+use crate::cli::Options;
+use crate::config::Config;
+use crate::database::RomDatabase;
+
+/// Characters used by screen_as_text for each combination of lit planes. See Palette in main.rs
+const TEXT_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+/// A keypad key going down or up at the start of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u32,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Parses a comma separated key schedule, where each entry is the frame number, + or - for press
+/// or release, then the hex key. e.g "30+5,35-5" holds 5 down from frame 30 up to frame 35.
+pub fn parse_key_schedule(schedule: &str) -> Result<Vec<KeyEvent>, String> {
+    let mut events = Vec::new();
+    for entry in schedule
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let Some(sign_idx) = entry.find(['+', '-']) else {
+            return Err(format!(
+                "'{}' should be a frame number, + or -, then a key (e.g 30+5)",
+                entry
+            ));
+        };
+        let (frame, key) = entry.split_at(sign_idx);
+        let frame = frame
+            .parse()
+            .map_err(|_| format!("'{}' in '{}' is not a frame number", frame, entry))?;
+        let pressed = key.starts_with('+');
+        let key = &key[1..];
+        let key = match u8::from_str_radix(key, 16) {
+            Ok(key) if key <= 0xF => key,
+            _ => return Err(format!("'{}' in '{}' is not a key from 0 to F", key, entry)),
+        };
+        events.push(KeyEvent {
+            frame,
+            key,
+            pressed,
+        });
+    }
+    events.sort_by_key(|event| event.frame);
+    return Ok(events);
+}
+
+/// Runs the chip for the given number of frames, pressing and releasing keys as scheduled.
+/// Stops at the first error.
+pub fn run_frames(chip: &mut Chip8, frames: u32, schedule: &[KeyEvent]) -> Result<(), Chip8Error> {
+    for frame in 0..frames {
+        for event in schedule.iter().filter(|event| event.frame == frame) {
+            if event.pressed {
+                chip.key_down(event.key);
+            } else {
+                chip.key_up(event.key);
+            }
+        }
+        chip.run_a_frame()?;
+    }
+    return Ok(());
+}
+
+/// Runs the ROM without a window or audio, for --headless and chip8-headless. Prints the final
+/// screen and its hash, and writes the screen to --dump-screen if given (PBM if it ends in .pbm,
+/// text otherwise). Only the bundled ROM database is used, and a config file only if --config
+/// names one, so a ROM gives the same screen on every machine.
+/// Returns the exit code: nonzero if the ROM couldn't be run.
+pub fn run(rom: &[u8], options: &Options) -> i32 {
+    let database = RomDatabase::bundled();
+    let config = match Config::load_reproducible(options, database.lookup(rom)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let mut chip = config.new_chip(rom, options.seed);
+    let result = run_frames(
+        &mut chip,
+        options.frames.unwrap_or(60),
+        &options.key_schedule,
+    );
+
+    print!("{}", screen_as_text(&chip));
+    println!("hash: {:016x}", screen_hash(&chip));
+
+    if let Some(path) = &options.dump_screen_path {
+        let dump = if path.ends_with(".pbm") {
+            screen_as_pbm(&chip)
+        } else {
+            screen_as_text(&chip)
+        };
+        if let Err(e) = std::fs::write(path, dump) {
+            eprintln!("Couldn't write screen to {}: {}", path, e);
+            return 1;
+        }
+    }
+
+    if let Err(error) = result {
+        eprintln!("Halted: {}", error);
+        chip.print_registers();
+        return 1;
+    }
+    return 0;
+}
+
+/// The visible part of the display, one line per row
+pub fn screen_as_text(chip: &Chip8) -> String {
+    let mut text = String::with_capacity((chip.display_width() + 1) * chip.display_height());
+    for y in 0..chip.display_height() {
+        for x in 0..chip.display_width() {
+            let pixel = chip.display_buffer[chip.idx_for_display(x, y)];
+            text.push(TEXT_PIXELS[pixel as usize]);
+        }
+        text.push('\n');
+    }
+    return text;
+}
+
+/// The visible part of the display as a plain (P1) PBM image. Pixels lit on any plane are black.
+pub fn screen_as_pbm(chip: &Chip8) -> String {
+    let mut pbm = format!("P1\n{} {}\n", chip.display_width(), chip.display_height());
+    for y in 0..chip.display_height() {
+        let row: Vec<&str> = (0..chip.display_width())
+            .map(|x| {
+                if chip.display_buffer[chip.idx_for_display(x, y)] != 0 {
+                    "1"
+                } else {
+                    "0"
+                }
+            })
+            .collect();
+        pbm.push_str(&row.join(" "));
+        pbm.push('\n');
+    }
+    return pbm;
+}
+
+/// fnv1a_hash of the visible part of the display, so a whole screen can be compared at a glance
+pub fn screen_hash(chip: &Chip8) -> u64 {
+    let visible_pixels = chip.display_width() * chip.display_height();
+    return fnv1a_hash(&chip.display_buffer[..visible_pixels]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ROM, frames to run, key schedule. Each has a matching golden screen in
+    /// test_programs/golden_screens/<ROM name>.txt
+    const GOLDEN_SCREEN_ROMS: [(&str, u32, &str); 6] = [
+        ("1-chip8-logo.ch8", 60, ""),
+        ("2-ibm-logo.ch8", 60, ""),
+        ("3-corax+.ch8", 240, ""),
+        ("4-flags.ch8", 240, ""),
+        // Picks the Fx0A test from the menu once it's drawn, then presses and releases 5
+        ("6-keypad.ch8", 240, "100+3,105-3,150+5,155-5"),
+        ("test_opcode.ch8", 240, ""),
+    ];
+
+    /// Shows the rows that differ, expected above actual
+    fn screen_diff(expected: &str, actual: &str) -> String {
+        let mut diff = String::new();
+        let expected_rows: Vec<&str> = expected.lines().collect();
+        let actual_rows: Vec<&str> = actual.lines().collect();
+        for row in 0..expected_rows.len().max(actual_rows.len()) {
+            let expected_row = expected_rows.get(row).copied().unwrap_or("<missing>");
+            let actual_row = actual_rows.get(row).copied().unwrap_or("<missing>");
+            if expected_row != actual_row {
+                diff.push_str(&format!(
+                    "row {:2} expected: {}\n       actual:   {}\n",
+                    row, expected_row, actual_row
+                ));
+            }
+        }
+        return diff;
+    }
+
+    /// What's wrong with the screen, if it doesn't match the golden one. Set
+    /// UPDATE_GOLDEN_SCREENS=1 to overwrite the golden screens with whatever gets drawn now, after
+    /// checking the new screens are actually right!
+    fn check_golden_screen(chip: &Chip8, golden_path: &str) -> Option<String> {
+        let actual = screen_as_text(chip);
+        if std::env::var_os("UPDATE_GOLDEN_SCREENS").is_some() {
+            std::fs::write(golden_path, &actual).unwrap();
+            return None;
+        }
+        let expected = std::fs::read_to_string(golden_path).unwrap_or_default();
+        if expected == actual {
+            return None;
+        }
+        return Some(format!(
+            "doesn't match {}\n{}",
+            golden_path,
+            screen_diff(&expected, &actual)
+        ));
+    }
+
+    #[test]
+    fn bundled_test_roms_match_golden_screens() {
+        let mut failures = Vec::new();
+
+        for (rom_name, frames, keys) in GOLDEN_SCREEN_ROMS {
+            let rom = std::fs::read(format!("roms/{}", rom_name)).unwrap();
+            let mut chip = Chip8::with_seed(&rom, Quirks::cosmac_vip(), 0);
+            let schedule = parse_key_schedule(keys).unwrap();
+            run_frames(&mut chip, frames, &schedule).unwrap();
+
+            let golden_path = format!(
+                "test_programs/golden_screens/{}.txt",
+                rom_name.trim_end_matches(".ch8")
+            );
+            if let Some(failure) = check_golden_screen(&chip, &golden_path) {
+                failures.push(format!("{} {}", rom_name, failure));
+            }
+        }
+
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
+    /// The flag test ROMs don't depend on any quirk, so they should draw the same golden screen
+    /// under every preset
+    #[test]
+    fn flag_roms_match_golden_screens_under_every_preset() {
+        let mut failures = Vec::new();
+
+        for quirks in [Quirks::cosmac_vip(), Quirks::chip48(), Quirks::superchip()] {
+            let preset = quirks.preset_name().unwrap();
+            for rom_name in ["3-corax+.ch8", "4-flags.ch8"] {
+                let rom = std::fs::read(format!("roms/{}", rom_name)).unwrap();
+                let mut chip = Chip8::with_seed(&rom, quirks, 0);
+                run_frames(&mut chip, 240, &[]).unwrap();
+
+                let golden_path = format!(
+                    "test_programs/golden_screens/{}.txt",
+                    rom_name.trim_end_matches(".ch8")
+                );
+                if let Some(failure) = check_golden_screen(&chip, &golden_path) {
+                    failures.push(format!("{} under {} {}", rom_name, preset, failure));
+                }
+            }
+        }
+
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
+    /// test_programs/quirks.asm draws a digit for what it found each quirk doing, so each preset
+    /// has its own golden screen in test_programs/golden_screens/quirks.<preset>.txt
+    #[test]
+    fn quirks_program_matches_golden_screens_under_every_preset() {
+        let rom = std::fs::read("test_programs/quirks.ch8").unwrap();
+        let mut failures = Vec::new();
+        let mut screens = Vec::new();
+
+        for quirks in [
+            Quirks::cosmac_vip(),
+            Quirks::chip48(),
+            Quirks::superchip(),
+            Quirks::xo_chip(),
+        ] {
+            let preset = quirks.preset_name().unwrap();
+            let mut chip = Chip8::with_seed(&rom, quirks, 0);
+            // With VIP timing DRW takes about a frame anyway, so display wait wouldn't show
+            chip.execution_model = ExecutionModel::InstructionsPerFrame(15);
+            run_frames(&mut chip, 120, &[]).unwrap();
+
+            let golden_path = format!("test_programs/golden_screens/quirks.{}.txt", preset);
+            if let Some(failure) = check_golden_screen(&chip, &golden_path) {
+                failures.push(format!("quirks.ch8 under {} {}", preset, failure));
+            }
+            screens.push(screen_as_text(&chip));
+        }
+
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+        for (i, screen) in screens.iter().enumerate() {
+            assert!(
+                !screens[i + 1..].contains(screen),
+                "Two presets drew the same quirks"
+            );
+        }
+    }
+
+    #[test]
+    fn quirks_program_sees_display_wait() {
+        // test_programs/quirks.asm leaves what it found in V9: 1 if DRW waited for the next frame
+        let rom = std::fs::read("test_programs/quirks.ch8").unwrap();
+        let saw_display_wait = |display_wait: bool, execution_model: ExecutionModel| {
+            let mut quirks = Quirks::cosmac_vip();
+            quirks.display_wait = display_wait;
+            let mut chip = Chip8::with_seed(&rom, quirks, 0);
+            chip.execution_model = execution_model;
+            run_frames(&mut chip, 60, &[]).unwrap();
+            return chip.data_registers[0x9] == 1;
+        };
+
+        for execution_model in [
+            ExecutionModel::VipTiming,
+            ExecutionModel::InstructionsPerFrame(15),
+            ExecutionModel::InstructionsPerFrame(1000),
+        ] {
+            assert!(
+                saw_display_wait(true, execution_model),
+                "{:?}",
+                execution_model
+            );
+        }
+        // Under VIP timing a DRW takes about a frame anyway, like on the VIP itself, so turning
+        // display wait off only shows with the other execution models
+        for execution_model in [
+            ExecutionModel::InstructionsPerFrame(15),
+            ExecutionModel::InstructionsPerFrame(1000),
+        ] {
+            assert!(
+                !saw_display_wait(false, execution_model),
+                "{:?}",
+                execution_model
+            );
+        }
+    }
+
+    #[test]
+    fn flag_and_quirk_roms_pass_with_and_without_display_wait() {
+        // Display wait only changes how fast these draw, so given long enough they should end up
+        // on the same screen as the golden one. Neither ROM checks for vblank, so this only shows
+        // that display wait doesn't break them. See quirks_program_sees_display_wait for that
+        for rom_name in ["3-corax+.ch8", "4-flags.ch8"] {
+            let rom = std::fs::read(format!("roms/{}", rom_name)).unwrap();
+            let golden_path = format!(
+                "test_programs/golden_screens/{}.txt",
+                rom_name.trim_end_matches(".ch8")
+            );
+            let expected = std::fs::read_to_string(&golden_path).unwrap();
+            for display_wait in [true, false] {
+                for execution_model in [
+                    ExecutionModel::VipTiming,
+                    ExecutionModel::InstructionsPerFrame(15),
+                ] {
+                    let mut quirks = Quirks::cosmac_vip();
+                    quirks.display_wait = display_wait;
+                    let mut chip = Chip8::with_seed(&rom, quirks, 0);
+                    chip.execution_model = execution_model;
+                    run_frames(&mut chip, 600, &[]).unwrap();
+                    let actual = screen_as_text(&chip);
+                    assert!(
+                        expected == actual,
+                        "{} with display_wait {} and {:?}\n{}",
+                        rom_name,
+                        display_wait,
+                        execution_model,
+                        screen_diff(&expected, &actual)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_key_schedules() {
+        assert_eq!(
+            parse_key_schedule("35-a, 30+A"),
+            Ok(vec![
+                KeyEvent {
+                    frame: 30,
+                    key: 0xA,
+                    pressed: true
+                },
+                KeyEvent {
+                    frame: 35,
+                    key: 0xA,
+                    pressed: false
+                },
+            ])
+        );
+        assert_eq!(parse_key_schedule(""), Ok(vec![]));
+        assert!(parse_key_schedule("30").is_err());
+        assert!(parse_key_schedule("x+1").is_err());
+        assert!(parse_key_schedule("30+10").is_err());
+    }
+
+    #[test]
+    fn dumps_the_visible_screen() {
+        let mut chip = Chip8::new(&[], Quirks::cosmac_vip());
+        chip.set_pixel(1, 0, FIRST_PLANE, true);
+
+        let text = screen_as_text(&chip);
+        assert_eq!(text.lines().count(), CHIP_DISPLAY_HEIGHT_IN_PIXELS);
+        assert!(text.starts_with(".#..."));
+
+        let pbm = screen_as_pbm(&chip);
+        assert!(pbm.starts_with("P1\n64 32\n0 1 0 0"));
+
+        let blank_hash = screen_hash(&Chip8::new(&[], Quirks::cosmac_vip()));
+        assert_ne!(screen_hash(&chip), blank_hash);
+    }
+}
@@ -0,0 +1,391 @@
+/// Index of a data register, V0 - VF
+pub type Register = u8;
+/// 12 bit memory address
+pub type Address = u16;
+
+/// A single decoded instruction. Variants are named after the mnemonic followed by the operand
+/// forms, so `LdVxByte` is `LD Vx, byte` and `LdVxVy` is `LD Vx, Vy`.
+///
+/// Opcodes and most documentation taken from http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#3.1
+/// SUPER-CHIP and XO-CHIP extensions are marked as such.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0nnn - SYS addr
+    /// Jump to a machine code routine at nnn. Only decoded so it can be disassembled, we can't run
+    /// these.
+    Sys(Address),
+    /// 00E0 - CLS
+    Cls,
+    /// 00EE - RET
+    Ret,
+    /// 00Cn - SCD nibble (SUPER-CHIP)
+    ScrollDown(u8),
+    /// 00FB - SCR (SUPER-CHIP)
+    ScrollRight,
+    /// 00FC - SCL (SUPER-CHIP)
+    ScrollLeft,
+    /// 00FD - EXIT (SUPER-CHIP)
+    Exit,
+    /// 00FE - LOW (SUPER-CHIP)
+    LowRes,
+    /// 00FF - HIGH (SUPER-CHIP)
+    HighRes,
+    /// 1nnn - JP addr
+    Jp(Address),
+    /// 2nnn - CALL addr
+    Call(Address),
+    /// 3xkk - SE Vx, byte
+    SeVxByte(Register, u8),
+    /// 4xkk - SNE Vx, byte
+    SneVxByte(Register, u8),
+    /// 5xy0 - SE Vx, Vy
+    SeVxVy(Register, Register),
+    /// 5xy2 - LD [I], Vx - Vy (XO-CHIP)
+    LdIVxVy(Register, Register),
+    /// 5xy3 - LD Vx - Vy, [I] (XO-CHIP)
+    LdVxVyI(Register, Register),
+    /// 6xkk - LD Vx, byte
+    LdVxByte(Register, u8),
+    /// 7xkk - ADD Vx, byte
+    AddVxByte(Register, u8),
+    /// 8xy0 - LD Vx, Vy
+    LdVxVy(Register, Register),
+    /// 8xy1 - OR Vx, Vy
+    Or(Register, Register),
+    /// 8xy2 - AND Vx, Vy
+    And(Register, Register),
+    /// 8xy3 - XOR Vx, Vy
+    Xor(Register, Register),
+    /// 8xy4 - ADD Vx, Vy
+    AddVxVy(Register, Register),
+    /// 8xy5 - SUB Vx, Vy
+    Sub(Register, Register),
+    /// 8xy6 - SHR Vx {, Vy}
+    Shr(Register, Register),
+    /// 8xy7 - SUBN Vx, Vy
+    Subn(Register, Register),
+    /// 8xyE - SHL Vx {, Vy}
+    Shl(Register, Register),
+    /// 9xy0 - SNE Vx, Vy
+    SneVxVy(Register, Register),
+    /// Annn - LD I, addr
+    LdIAddr(Address),
+    /// Bnnn - JP V0, addr
+    JpV0(Address),
+    /// Cxkk - RND Vx, byte
+    Rnd(Register, u8),
+    /// Dxyn - DRW Vx, Vy, nibble
+    Drw(Register, Register, u8),
+    /// Ex9E - SKP Vx
+    Skp(Register),
+    /// ExA1 - SKNP Vx
+    Sknp(Register),
+    /// F000 nnnn - LD I, long addr (XO-CHIP)
+    /// The address is the 2 bytes following the opcode, which makes this the only 4 byte
+    /// instruction. See length_in_bytes.
+    LdILong(u16),
+    /// Fn01 - PLANE n (XO-CHIP)
+    Plane(u8),
+    /// F002 - AUDIO (XO-CHIP)
+    Audio,
+    /// Fx07 - LD Vx, DT
+    LdVxDt(Register),
+    /// Fx0A - LD Vx, K
+    LdVxK(Register),
+    /// Fx15 - LD DT, Vx
+    LdDtVx(Register),
+    /// Fx18 - LD ST, Vx
+    LdStVx(Register),
+    /// Fx1E - ADD I, Vx
+    AddIVx(Register),
+    /// Fx29 - LD F, Vx
+    LdFVx(Register),
+    /// Fx30 - LD HF, Vx (SUPER-CHIP)
+    LdHfVx(Register),
+    /// Fx33 - LD B, Vx
+    LdBVx(Register),
+    /// Fx3A - PITCH Vx (XO-CHIP)
+    Pitch(Register),
+    /// Fx55 - LD [I], Vx
+    LdIVx(Register),
+    /// Fx65 - LD Vx, [I]
+    LdVxI(Register),
+    /// Fx75 - LD R, Vx (SUPER-CHIP)
+    LdRVx(Register),
+    /// Fx85 - LD Vx, R (SUPER-CHIP)
+    LdVxR(Register),
+}
+
+/// The opcode doesn't match any instruction we know about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidOpcode(pub u16);
+
+impl std::fmt::Display for InvalidOpcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Invalid opcode {:04X}", self.0);
+    }
+}
+
+impl std::error::Error for InvalidOpcode {}
+
+impl Instruction {
+    /// Decodes the instruction at the start of bytes. F000 nnnn is the only one longer than the 2
+    /// bytes of the opcode, and is invalid without the address after it. Anything shorter than 2
+    /// bytes is invalid too, and the InvalidOpcode has the missing bytes as 0.
+    pub fn decode_bytes(bytes: &[u8]) -> Result<Instruction, InvalidOpcode> {
+        let opcode = match bytes {
+            [high, low, ..] => u16::from_be_bytes([*high, *low]),
+            [high] => return Err(InvalidOpcode(u16::from_be_bytes([*high, 0]))),
+            [] => return Err(InvalidOpcode(0)),
+        };
+        if opcode == 0xF000 {
+            return match bytes.get(2..4) {
+                Some(&[high, low]) => Ok(Instruction::LdILong(u16::from_be_bytes([high, low]))),
+                _ => Err(InvalidOpcode(opcode)),
+            };
+        }
+        return Instruction::decode(opcode);
+    }
+
+    /// Decodes a 2 byte opcode. F000 nnnn needs the address after it, so it's invalid here, see
+    /// decode_bytes
+    pub fn decode(opcode: u16) -> Result<Instruction, InvalidOpcode> {
+        let x = ((opcode & 0x0F00) >> 8) as Register;
+        let y = ((opcode & 0x00F0) >> 4) as Register;
+        let n = (opcode & 0x000F) as u8;
+        let kk = (opcode & 0x00FF) as u8;
+        let nnn = opcode & 0x0FFF;
+
+        let instruction = match (opcode & 0xF000) >> 12 {
+            0x0 => match nnn {
+                0x0E0 => Instruction::Cls,
+                0x0EE => Instruction::Ret,
+                0x0C0..=0x0CF => Instruction::ScrollDown(n),
+                0x0FB => Instruction::ScrollRight,
+                0x0FC => Instruction::ScrollLeft,
+                0x0FD => Instruction::Exit,
+                0x0FE => Instruction::LowRes,
+                0x0FF => Instruction::HighRes,
+                _ => Instruction::Sys(nnn),
+            },
+            0x1 => Instruction::Jp(nnn),
+            0x2 => Instruction::Call(nnn),
+            0x3 => Instruction::SeVxByte(x, kk),
+            0x4 => Instruction::SneVxByte(x, kk),
+            0x5 => match n {
+                0x0 => Instruction::SeVxVy(x, y),
+                0x2 => Instruction::LdIVxVy(x, y),
+                0x3 => Instruction::LdVxVyI(x, y),
+                _ => return Err(InvalidOpcode(opcode)),
+            },
+            0x6 => Instruction::LdVxByte(x, kk),
+            0x7 => Instruction::AddVxByte(x, kk),
+            0x8 => match n {
+                0x0 => Instruction::LdVxVy(x, y),
+                0x1 => Instruction::Or(x, y),
+                0x2 => Instruction::And(x, y),
+                0x3 => Instruction::Xor(x, y),
+                0x4 => Instruction::AddVxVy(x, y),
+                0x5 => Instruction::Sub(x, y),
+                0x6 => Instruction::Shr(x, y),
+                0x7 => Instruction::Subn(x, y),
+                0xE => Instruction::Shl(x, y),
+                _ => return Err(InvalidOpcode(opcode)),
+            },
+            0x9 if n == 0 => Instruction::SneVxVy(x, y),
+            0xA => Instruction::LdIAddr(nnn),
+            0xB => Instruction::JpV0(nnn),
+            0xC => Instruction::Rnd(x, kk),
+            0xD => Instruction::Drw(x, y, n),
+            0xE => match kk {
+                0x9E => Instruction::Skp(x),
+                0xA1 => Instruction::Sknp(x),
+                _ => return Err(InvalidOpcode(opcode)),
+            },
+            0xF => match kk {
+                0x01 => Instruction::Plane(x),
+                0x02 if x == 0 => Instruction::Audio,
+                0x07 => Instruction::LdVxDt(x),
+                0x0A => Instruction::LdVxK(x),
+                0x15 => Instruction::LdDtVx(x),
+                0x18 => Instruction::LdStVx(x),
+                0x1E => Instruction::AddIVx(x),
+                0x29 => Instruction::LdFVx(x),
+                0x30 => Instruction::LdHfVx(x),
+                0x33 => Instruction::LdBVx(x),
+                0x3A => Instruction::Pitch(x),
+                0x55 => Instruction::LdIVx(x),
+                0x65 => Instruction::LdVxI(x),
+                0x75 => Instruction::LdRVx(x),
+                0x85 => Instruction::LdVxR(x),
+                _ => return Err(InvalidOpcode(opcode)),
+            },
+            _ => return Err(InvalidOpcode(opcode)),
+        };
+        return Ok(instruction);
+    }
+
+    /// Every byte of the instruction, the opcode followed by any operands after it. Operands are
+    /// masked to the size of their field, so decode(encode()) is only guaranteed to round trip for
+    /// instructions that came from decode.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.opcode().to_be_bytes().to_vec();
+        if let Instruction::LdILong(address) = *self {
+            bytes.extend_from_slice(&address.to_be_bytes());
+        }
+        return bytes;
+    }
+
+    /// The first 2 bytes of the instruction. See encode for the whole thing
+    pub fn opcode(&self) -> u16 {
+        fn xkk(high: u16, x: Register, kk: u8) -> u16 {
+            return high << 12 | ((x as u16 & 0xF) << 8) | kk as u16;
+        }
+        fn xyn(high: u16, x: Register, y: Register, n: u8) -> u16 {
+            return high << 12
+                | ((x as u16 & 0xF) << 8)
+                | ((y as u16 & 0xF) << 4)
+                | (n as u16 & 0xF);
+        }
+        fn nnn(high: u16, addr: Address) -> u16 {
+            return high << 12 | (addr & 0x0FFF);
+        }
+
+        return match *self {
+            Instruction::Sys(addr) => nnn(0x0, addr),
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jp(addr) => nnn(0x1, addr),
+            Instruction::Call(addr) => nnn(0x2, addr),
+            Instruction::SeVxByte(x, kk) => xkk(0x3, x, kk),
+            Instruction::SneVxByte(x, kk) => xkk(0x4, x, kk),
+            Instruction::SeVxVy(x, y) => xyn(0x5, x, y, 0x0),
+            Instruction::LdIVxVy(x, y) => xyn(0x5, x, y, 0x2),
+            Instruction::LdVxVyI(x, y) => xyn(0x5, x, y, 0x3),
+            Instruction::LdVxByte(x, kk) => xkk(0x6, x, kk),
+            Instruction::AddVxByte(x, kk) => xkk(0x7, x, kk),
+            Instruction::LdVxVy(x, y) => xyn(0x8, x, y, 0x0),
+            Instruction::Or(x, y) => xyn(0x8, x, y, 0x1),
+            Instruction::And(x, y) => xyn(0x8, x, y, 0x2),
+            Instruction::Xor(x, y) => xyn(0x8, x, y, 0x3),
+            Instruction::AddVxVy(x, y) => xyn(0x8, x, y, 0x4),
+            Instruction::Sub(x, y) => xyn(0x8, x, y, 0x5),
+            Instruction::Shr(x, y) => xyn(0x8, x, y, 0x6),
+            Instruction::Subn(x, y) => xyn(0x8, x, y, 0x7),
+            Instruction::Shl(x, y) => xyn(0x8, x, y, 0xE),
+            Instruction::SneVxVy(x, y) => xyn(0x9, x, y, 0x0),
+            Instruction::LdIAddr(addr) => nnn(0xA, addr),
+            Instruction::JpV0(addr) => nnn(0xB, addr),
+            Instruction::Rnd(x, kk) => xkk(0xC, x, kk),
+            Instruction::Drw(x, y, n) => xyn(0xD, x, y, n),
+            Instruction::Skp(x) => xkk(0xE, x, 0x9E),
+            Instruction::Sknp(x) => xkk(0xE, x, 0xA1),
+            Instruction::LdILong(_) => 0xF000,
+            Instruction::Plane(n) => xkk(0xF, n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LdVxDt(x) => xkk(0xF, x, 0x07),
+            Instruction::LdVxK(x) => xkk(0xF, x, 0x0A),
+            Instruction::LdDtVx(x) => xkk(0xF, x, 0x15),
+            Instruction::LdStVx(x) => xkk(0xF, x, 0x18),
+            Instruction::AddIVx(x) => xkk(0xF, x, 0x1E),
+            Instruction::LdFVx(x) => xkk(0xF, x, 0x29),
+            Instruction::LdHfVx(x) => xkk(0xF, x, 0x30),
+            Instruction::LdBVx(x) => xkk(0xF, x, 0x33),
+            Instruction::Pitch(x) => xkk(0xF, x, 0x3A),
+            Instruction::LdIVx(x) => xkk(0xF, x, 0x55),
+            Instruction::LdVxI(x) => xkk(0xF, x, 0x65),
+            Instruction::LdRVx(x) => xkk(0xF, x, 0x75),
+            Instruction::LdVxR(x) => xkk(0xF, x, 0x85),
+        };
+    }
+
+    /// How many bytes of memory the instruction takes up, including any operands that follow the
+    /// opcode
+    pub fn length_in_bytes(&self) -> usize {
+        return match self {
+            Instruction::LdILong(_) => 4,
+            _ => 2,
+        };
+    }
+
+    /// True for the instructions that only exist in XO-CHIP
+    pub fn is_xo_chip_only(&self) -> bool {
+        return matches!(
+            self,
+            Instruction::LdIVxVy(..)
+                | Instruction::LdVxVyI(..)
+                | Instruction::LdILong(_)
+                | Instruction::Plane(_)
+                | Instruction::Audio
+                | Instruction::Pitch(_)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_and_encodes_every_valid_opcode() {
+        for opcode in 0..=u16::MAX {
+            // The operand for F000 nnnn
+            let bytes = [opcode.to_be_bytes(), [0x12, 0x34]].concat();
+            if let Ok(instruction) = Instruction::decode_bytes(&bytes) {
+                assert_eq!(
+                    instruction.encode(),
+                    bytes[..instruction.length_in_bytes()],
+                    "{:?} didn't encode back to {:04X}",
+                    instruction,
+                    opcode
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(Instruction::decode(0x00E0), Ok(Instruction::Cls));
+        assert_eq!(Instruction::decode(0x1234), Ok(Instruction::Jp(0x234)));
+        assert_eq!(
+            Instruction::decode(0x6A42),
+            Ok(Instruction::LdVxByte(0xA, 0x42))
+        );
+        assert_eq!(
+            Instruction::decode(0xD125),
+            Ok(Instruction::Drw(0x1, 0x2, 0x5))
+        );
+        assert_eq!(Instruction::decode(0xF365), Ok(Instruction::LdVxI(0x3)));
+        assert_eq!(
+            Instruction::decode_bytes(&[0xF0, 0x00, 0xAB, 0xCD]),
+            Ok(Instruction::LdILong(0xABCD))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_opcodes() {
+        assert_eq!(Instruction::decode(0x5121), Err(InvalidOpcode(0x5121)));
+        assert_eq!(Instruction::decode(0x8128), Err(InvalidOpcode(0x8128)));
+        assert_eq!(Instruction::decode(0x9121), Err(InvalidOpcode(0x9121)));
+        assert_eq!(Instruction::decode(0xE1FF), Err(InvalidOpcode(0xE1FF)));
+        assert_eq!(Instruction::decode(0xFFFF), Err(InvalidOpcode(0xFFFF)));
+        // Missing the address after it
+        assert_eq!(Instruction::decode(0xF000), Err(InvalidOpcode(0xF000)));
+        assert_eq!(
+            Instruction::decode_bytes(&[0xF0, 0x00, 0xAB]),
+            Err(InvalidOpcode(0xF000))
+        );
+        // Too short to have an opcode
+        assert_eq!(Instruction::decode_bytes(&[]), Err(InvalidOpcode(0x0000)));
+        assert_eq!(
+            Instruction::decode_bytes(&[0xF0]),
+            Err(InvalidOpcode(0xF000))
+        );
+    }
+}
@@ -0,0 +1,361 @@
+/// Just enough JSON to read the chip-8-database. Numbers are all f64, and objects keep their keys
+/// in the order they were written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        let Json::Object(members) = self else {
+            return None;
+        };
+        return members
+            .iter()
+            .find(|(member_key, _)| member_key == key)
+            .map(|(_, value)| value);
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        return match self {
+            Json::String(string) => Some(string),
+            _ => None,
+        };
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        return match self {
+            Json::Bool(boolean) => Some(*boolean),
+            _ => None,
+        };
+    }
+
+    /// Only whole numbers that fit in a u32
+    pub fn as_u32(&self) -> Option<u32> {
+        return match self {
+            Json::Number(number)
+                if number.fract() == 0.0 && *number >= 0.0 && *number <= u32::MAX as f64 =>
+            {
+                Some(*number as u32)
+            }
+            _ => None,
+        };
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        return match self {
+            Json::Array(elements) => Some(elements),
+            _ => None,
+        };
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Json)]> {
+        return match self {
+            Json::Object(members) => Some(members),
+            _ => None,
+        };
+    }
+}
+
+/// Arrays and objects nested deeper than this are an error rather than a stack overflow. The
+/// database only goes a few levels deep.
+const MAX_DEPTH: usize = 64;
+
+pub fn parse_json(text: &str) -> Result<Json, String> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        current: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.current != parser.text.len() {
+        return Err(parser.error("expected the end of the file"));
+    }
+    return Ok(value);
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    current: usize,
+    /// Arrays and objects we're inside of
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        let consumed = &self.text[..self.current.min(self.text.len())];
+        let line = consumed.iter().filter(|&&ch| ch == b'\n').count() + 1;
+        return format!("line {}: {}", line, message);
+    }
+
+    fn peek(&self) -> Option<u8> {
+        return self.text.get(self.current).copied();
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.current += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{}'", expected as char)));
+        }
+        self.current += 1;
+        return Ok(());
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.current..].starts_with(literal.as_bytes()) {
+            return Err(self.error("expected a value"));
+        }
+        self.current += literal.len();
+        return Ok(value);
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        return match self.peek() {
+            Some(b'{' | b'[') => {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.error("nested too deeply"));
+                }
+                self.depth += 1;
+                let value = if self.peek() == Some(b'{') {
+                    self.object()
+                } else {
+                    self.array()
+                };
+                self.depth -= 1;
+                value
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of file")),
+        };
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.current += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.current += 1,
+                Some(b'}') => {
+                    self.current += 1;
+                    return Ok(Json::Object(members));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect(b'[')?;
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.current += 1;
+            return Ok(Json::Array(elements));
+        }
+        loop {
+            elements.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.current += 1,
+                Some(b']') => {
+                    self.current += 1;
+                    return Ok(Json::Array(elements));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        // Opening quote
+        self.current += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(ch) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.current += 1;
+            match ch {
+                b'"' => break,
+                b'\\' => {
+                    let Some(escaped) = self.peek() else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.current += 1;
+                    let unescaped = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hex = self
+                                .text
+                                .get(self.current..self.current + 4)
+                                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+                            self.current += 4;
+                            // Surrogate pairs come out as the replacement character, which is
+                            // fine for titles and descriptions
+                            match hex {
+                                Some(code_point) => char::from_u32(code_point)
+                                    .unwrap_or(char::REPLACEMENT_CHARACTER),
+                                None => return Err(self.error("expected 4 hex digits after \\u")),
+                            }
+                        }
+                        _ => return Err(self.error("unknown escape in string")),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(unescaped.encode_utf8(&mut buffer).as_bytes());
+                }
+                _ => bytes.push(ch),
+            }
+        }
+        return String::from_utf8(bytes).map_err(|_| self.error("string isn't valid UTF-8"));
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.current;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.current += 1;
+        }
+        let number = std::str::from_utf8(&self.text[start..self.current])
+            .ok()
+            .and_then(|number| number.parse().ok());
+        return match number {
+            Some(number) => Ok(Json::Number(number)),
+            None => Err(self.error("invalid number")),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_json() {
+        let json = parse_json(
+            r#"{
+                "title": "Pong \"1\" é",
+                "tickrate": 15,
+                "platforms": ["originalChip8", "modernChip8"],
+                "wrap": false,
+                "colors": null,
+                "nested": {"empty": [], "also_empty": {}}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(json.get("title").unwrap().as_str(), Some("Pong \"1\" é"));
+        assert_eq!(json.get("tickrate").unwrap().as_u32(), Some(15));
+        assert_eq!(json.get("platforms").unwrap().as_array().unwrap().len(), 2);
+        assert_eq!(json.get("wrap").unwrap().as_bool(), Some(false));
+        assert_eq!(json.get("colors"), Some(&Json::Null));
+        assert_eq!(
+            json.get("nested").unwrap().get("empty"),
+            Some(&Json::Array(vec![]))
+        );
+        assert_eq!(json.get("missing"), None);
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        assert_eq!(
+            parse_json("{\n\"a\": 1,\n\"b\" 2}"),
+            Err("line 3: expected ':'".to_string())
+        );
+        assert_eq!(
+            parse_json("[1, 2"),
+            Err("line 1: expected ',' or ']'".to_string())
+        );
+        assert!(parse_json("\"unterminated").is_err());
+        assert!(parse_json("{} extra").is_err());
+        assert!(parse_json("").is_err());
+    }
+
+    #[test]
+    fn malformed_json_is_an_error_not_a_panic() {
+        let malformed = [
+            "{",
+            "}",
+            "[",
+            "]",
+            "[,]",
+            "[1,]",
+            "{\"a\"}",
+            "{\"a\":}",
+            "{1: 2}",
+            "\"\\",
+            "\"\\u12",
+            "\"\\u+123\"",
+            "\"\\x\"",
+            "tru",
+            "nul",
+            "-",
+            "1e",
+            "1.2.3",
+            "[1 2]",
+            "\"\u{1}",
+        ];
+        for text in malformed {
+            assert!(parse_json(text).is_err(), "{} should be an error", text);
+        }
+        // Every prefix of valid JSON, which cuts it off in every possible place
+        let valid = r#"{"a": [1, -2.5e3, true, false, null, "\u00e9\n"], "b": {"c": {}}}"#;
+        assert!(parse_json(valid).is_ok());
+        for end in 0..valid.len() {
+            assert!(parse_json(&valid[..end]).is_err());
+        }
+    }
+
+    #[test]
+    fn deeply_nested_json_is_an_error_not_a_stack_overflow() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse_json(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            parse_json(&nested(MAX_DEPTH + 1)),
+            Err("line 1: nested too deeply".to_string())
+        );
+        assert!(parse_json(&"{\"a\":".repeat(100_000)).is_err());
+        assert!(parse_json(&"[".repeat(1_000_000)).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+//! CHIP-8 interpreter (with the SUPER-CHIP and XO-CHIP extensions), plus an assembler and a
+//! disassembler for it. The SDL frontend is the binary in main.rs, behind the sdl-frontend
+//! feature.
+//!
+//! The library is chip (with instruction, rng and save_state), assembler (with diagnostic) and
+//! disassembler.
+
+pub mod assembler;
+pub mod chip;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod instruction;
+pub mod rng;
+pub mod save_state;
+
+mod json;
+mod scanner;
+mod sha1;
+
+// Shared by the binaries in this crate, and not part of the library. The command line, config
+// file, ROM database, headless runs, rewinding and frame timing can change at any time.
+#[doc(hidden)]
+pub mod cli;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod database;
+#[doc(hidden)]
+pub mod headless;
+#[doc(hidden)]
+pub mod rewind;
+#[doc(hidden)]
+pub mod scheduler;
@@ -1,10 +1,12 @@
-mod assembler;
-mod chip;
-mod scanner;
-
-use chip::*;
-
-use std::{collections::HashMap, time::Duration};
+use chip_8_emulator::chip::*;
+use chip_8_emulator::cli;
+use chip_8_emulator::config::{Command, Config};
+use chip_8_emulator::database::RomDatabase;
+use chip_8_emulator::headless;
+use chip_8_emulator::rewind::*;
+use chip_8_emulator::scheduler::*;
+
+use std::collections::HashMap;
 // bunch of useful ROMs https://github.com/kripod/chip8-roms
 
 use sdl2::{
@@ -12,43 +14,113 @@ use sdl2::{
     event::Event,
     keyboard::Keycode,
     pixels::{Color, PixelFormatEnum},
+    rect::Rect,
     render::{Canvas, Texture},
 };
 
-#[derive(PartialEq, Eq, Hash)]
-enum Command {
-    Step,
-    Pause,
-    PressKeyOnKeypad(u8),
+type Keymap = HashMap<Keycode, Command>;
+
+/// Colour for each combination of lit planes. Index 0 is the background, 1 the first plane, 2 the
+/// second plane (XO-CHIP only) and 3 where both overlap
+type Palette = [Color; 4];
+
+const HALTED_PALETTE: Palette = [
+    Color::RGB(0, 0, 0),
+    Color::RGB(255, 0, 0),
+    Color::RGB(170, 0, 0),
+    Color::RGB(85, 0, 0),
+];
+
+/// SDL's names for the keys in the config (e.g "Q", "1", "F1", "Space") to keycodes
+fn keymap_from_config(config: &Config) -> Result<Keymap, String> {
+    let mut keymap = Keymap::new();
+    for (key_name, command) in &config.keymap {
+        let Some(keycode) = Keycode::from_name(key_name) else {
+            return Err(format!("unknown key '{}'", key_name));
+        };
+        keymap.insert(keycode, *command);
+    }
+    return Ok(keymap);
 }
 
-type Keymap = HashMap<Keycode, Command>;
+fn palette_from_config(config: &Config) -> Palette {
+    let colours = [
+        config.background,
+        config.foreground,
+        config.second_plane,
+        config.both_planes,
+    ];
+    return colours.map(|(r, g, b)| Color::RGB(r, g, b));
+}
 
-fn default_keymap() -> Keymap {
-    return Keymap::from([
-        (Keycode::P, Command::Pause),
-        (Keycode::N, Command::Step),
-        (Keycode::Num0, Command::PressKeyOnKeypad(0x0)),
-        (Keycode::Num1, Command::PressKeyOnKeypad(0x1)),
-        (Keycode::Num2, Command::PressKeyOnKeypad(0x2)),
-        (Keycode::Num3, Command::PressKeyOnKeypad(0x3)),
-        (Keycode::Num4, Command::PressKeyOnKeypad(0x4)),
-        (Keycode::Num5, Command::PressKeyOnKeypad(0x5)),
-        (Keycode::Num6, Command::PressKeyOnKeypad(0x6)),
-        (Keycode::Num7, Command::PressKeyOnKeypad(0x7)),
-        (Keycode::Num8, Command::PressKeyOnKeypad(0x8)),
-        (Keycode::Num9, Command::PressKeyOnKeypad(0x9)),
-        (Keycode::A, Command::PressKeyOnKeypad(0xA)),
-        (Keycode::B, Command::PressKeyOnKeypad(0xB)),
-        (Keycode::C, Command::PressKeyOnKeypad(0xC)),
-        (Keycode::D, Command::PressKeyOnKeypad(0xD)),
-        (Keycode::E, Command::PressKeyOnKeypad(0xE)),
-        (Keycode::F, Command::PressKeyOnKeypad(0xF)),
-    ]);
+/// Save states live next to the ROM, one file per slot
+fn save_state_path(rom_path: &str, slot: u8) -> String {
+    return format!("{}.state{}", rom_path, slot);
 }
 
 fn main() {
-    let keymap = default_keymap();
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.show_help {
+        print!("{}", cli::USAGE);
+        return;
+    }
+    if !options.headless {
+        if let Some(flag) = cli::headless_only_flag(&options) {
+            eprintln!("{} only works with --headless\n\n{}", flag, cli::USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    // Test ROM from https://github.com/corax89/chip8-test-rom
+    // More test ROMS from https://github.com/Timendus/chip8-test-suite#chip-8-splash-screen
+    let mut file_path = options
+        .rom_path
+        .clone()
+        .unwrap_or("roms/test_opcode.ch8".to_string());
+    let rom_bytes = match std::fs::read(&file_path) {
+        Ok(rom_bytes) => rom_bytes,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    };
+    if options.headless {
+        std::process::exit(headless::run(&rom_bytes, &options));
+    }
+    let database = match RomDatabase::load() {
+        Ok(database) => database,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut config = match Config::load(&options, database.lookup(&rom_bytes)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if options.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+    // Random numbers are only reproducible between runs when given a seed
+    let seed = options.seed;
+
+    let mut keymap = match keymap_from_config(&config) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            eprintln!("Couldn't use keymap: {}", e);
+            std::process::exit(2);
+        }
+    };
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -61,65 +133,75 @@ fn main() {
         samples: None,     // default sample size
     };
 
-    let device = audio_subsystem
+    let mut device = audio_subsystem
         .open_playback(None, &desired_spec, |spec| {
             // initialize the audio callback
-            SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
+            PatternWave {
+                pattern: [0; 16],
+                playback_rate: 4000.0,
+                sample_rate: spec.freq as f32,
+                position: 0.0,
+                volume: config.volume as f32 / 100.0,
             }
         })
         .unwrap();
     const DISPLAY_WIDTH_IN_PIXELS: usize = 1024;
     const DISPLAY_HEIGHT_IN_PIXELS: usize = 768;
-    let window = video_subsystem
-        .window(
-            "Chip 8 Emulator",
+    let (window_width, window_height) = match config.scale {
+        Some(scale) => (
+            CHIP_DISPLAY_WIDTH_IN_PIXELS as u32 * scale,
+            CHIP_DISPLAY_HEIGHT_IN_PIXELS as u32 * scale,
+        ),
+        None => (
             DISPLAY_WIDTH_IN_PIXELS as u32,
             DISPLAY_HEIGHT_IN_PIXELS as u32,
-        )
-        .opengl()
-        .build()
-        .unwrap();
+        ),
+    };
+    let mut window_builder = video_subsystem.window("Chip 8 Emulator", window_width, window_height);
+    if options.fullscreen {
+        window_builder.fullscreen_desktop();
+    }
+    let window = window_builder.opengl().build().unwrap();
 
-    let mut canvas = window.into_canvas().build().unwrap();
+    let mut canvas_builder = window.into_canvas();
+    if config.vsync {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build().unwrap();
 
     canvas.set_draw_color(Color::RGB(0, 255, 255));
     canvas.clear();
     canvas.present();
 
     let texture_creator = canvas.texture_creator();
+    // Big enough for the SUPER-CHIP high resolution mode. Only the top left corner is used when in
+    // low resolution mode
     let mut texture = texture_creator
         .create_texture_streaming(
             PixelFormatEnum::RGB24,
-            chip::CHIP_DISPLAY_WIDTH_IN_PIXELS as u32,
-            chip::CHIP_DISPLAY_HEIGHT_IN_PIXELS as u32,
+            SUPER_CHIP_DISPLAY_WIDTH_IN_PIXELS as u32,
+            SUPER_CHIP_DISPLAY_HEIGHT_IN_PIXELS as u32,
         )
         .unwrap();
 
-    let args: Vec<String> = std::env::args().collect();
+    let mut palette = palette_from_config(&config);
 
-    let file_path = if args.len() >= 2 {
-        &args[1]
-    } else {
-        "roms/test_opcode.ch8"
-    };
-
-    // Test ROM from https://github.com/corax89/chip8-test-rom
-    // More test ROMS from https://github.com/Timendus/chip8-test-suite#chip-8-splash-screen
-    let rom_bytes = std::fs::read(file_path).unwrap();
-
-    let mut chip = Chip8::new(&rom_bytes);
+    let mut chip = config.new_chip(&rom_bytes, seed);
 
     let mut event_pump = sdl_context.event_pump().unwrap();
-    let mut executing = true;
+    let mut executing = !options.paused;
     let mut step_once = false;
+    // Set when the ROM does something we can't execute. Nothing runs until a new ROM is loaded.
+    let mut halted_with: Option<Chip8Error> = None;
+    let mut rewind_buffer = RewindBuffer::new(config.rewind_memory_in_megabytes * 1024 * 1024);
+    let mut rewinding = false;
 
-    let mut last_frame_time = std::time::Instant::now();
-    let target_frame_time = Duration::from_millis((1.0 / 60.0 * 1000.0) as u64);
-    let target_chip_frame_time = Duration::from_millis((1.0 / 60.0 * 1000.0) as u64);
+    // The display might refresh at 60Hz, 144Hz, or whatever rate we manage without vsync, so
+    // the scheduler decides how many 60Hz frames to run each time round
+    let mut scheduler = FrameScheduler::new(DEFAULT_MAX_CATCH_UP_FRAMES);
+    let mut last_update_time = std::time::Instant::now();
 
+    // What's held on the keyboard, so the chip can be told after loading a state or a new ROM
     let mut keys = [false; 16];
     'running: loop {
         for event in event_pump.poll_iter() {
@@ -133,8 +215,24 @@ fn main() {
                 }
                 Event::DropFile { filename, .. } => {
                     // TODO(reece): Handle non .ch8 files gracefully!
-                    let rom_bytes = std::fs::read(filename).unwrap();
-                    chip = Chip8::new(&rom_bytes);
+                    let rom_bytes = std::fs::read(&filename).unwrap();
+                    // The new ROM might have its own settings in the database
+                    match Config::load(&options, database.lookup(&rom_bytes))
+                        .and_then(|config| Ok((keymap_from_config(&config)?, config)))
+                    {
+                        Ok((new_keymap, new_config)) => {
+                            keymap = new_keymap;
+                            config = new_config;
+                            palette = palette_from_config(&config);
+                        }
+                        Err(e) => eprintln!("Keeping the old settings: {}", e),
+                    }
+                    chip = config.new_chip(&rom_bytes, seed);
+                    chip.set_keys(keys);
+                    file_path = filename;
+                    rewind_buffer.clear();
+                    halted_with = None;
+                    canvas.window_mut().set_title("Chip 8 Emulator").unwrap();
                 }
                 Event::KeyDown {
                     keycode: Some(keycode),
@@ -155,6 +253,7 @@ fn main() {
                                 Command::PressKeyOnKeypad(chip_key) => match chip_key {
                                     0x0..=0xF => {
                                         keys[*chip_key as usize] = true;
+                                        chip.key_down(*chip_key);
                                     }
                                     _ => {
                                         eprintln!(
@@ -163,6 +262,47 @@ fn main() {
                                         );
                                     }
                                 },
+                                Command::SaveState(slot) => {
+                                    let path = save_state_path(&file_path, *slot);
+                                    match std::fs::write(&path, chip.save_state()) {
+                                        Ok(()) => println!("Saved state to {}", path),
+                                        Err(e) => eprintln!("Couldn't save to {}: {}", path, e),
+                                    }
+                                }
+                                Command::LoadState(slot) => {
+                                    let path = save_state_path(&file_path, *slot);
+                                    match std::fs::read(&path) {
+                                        Ok(state) => match chip.load_state(&state) {
+                                            Ok(()) => {
+                                                println!("Loaded state from {}", path);
+                                                chip.set_keys(keys);
+                                                halted_with = None;
+                                                canvas
+                                                    .window_mut()
+                                                    .set_title("Chip 8 Emulator")
+                                                    .unwrap();
+                                            }
+                                            Err(e) => {
+                                                eprintln!("Couldn't load {}: {}", path, e)
+                                            }
+                                        },
+                                        Err(e) => eprintln!("Couldn't read {}: {}", path, e),
+                                    }
+                                }
+                                Command::Rewind => {
+                                    rewinding = true;
+                                }
+                                Command::CycleExecutionModel => {
+                                    let instructions_per_frame = match config.execution_model {
+                                        ExecutionModel::InstructionsPerFrame(
+                                            instructions_per_frame,
+                                        ) => instructions_per_frame,
+                                        _ => DEFAULT_INSTRUCTIONS_PER_FRAME,
+                                    };
+                                    chip.execution_model =
+                                        chip.execution_model.next(instructions_per_frame);
+                                    println!("Execution model is now {:?}", chip.execution_model);
+                                }
                             }
                         }
                     }
@@ -177,6 +317,7 @@ fn main() {
                                 Command::PressKeyOnKeypad(chip_key) => match chip_key {
                                     0x0..=0xF => {
                                         keys[*chip_key as usize] = false;
+                                        chip.key_up(*chip_key);
                                     }
                                     _ => {
                                         eprintln!(
@@ -185,6 +326,9 @@ fn main() {
                                         );
                                     }
                                 },
+                                Command::Rewind => {
+                                    rewinding = false;
+                                }
                                 _ => { /* We don't care about keyup events for non chip8 keys */ }
                             }
                         }
@@ -194,21 +338,71 @@ fn main() {
             }
         }
 
-        if executing || step_once {
-            if step_once {
-                chip.process_next_instruction(keys);
+        let current_time = std::time::Instant::now();
+        let frames_due = scheduler.frames_due(current_time - last_update_time);
+        last_update_time = current_time;
+
+        if rewinding {
+            for _ in 0..frames_due {
+                let Some(state) = rewind_buffer.pop() else {
+                    break;
+                };
+                // Can only fail if the state came from a different ROM, and we clear the buffer
+                // whenever the ROM changes
+                chip.load_state(&state).unwrap();
+                chip.set_keys(keys);
+                if halted_with.is_some() {
+                    halted_with = None;
+                    canvas.window_mut().set_title("Chip 8 Emulator").unwrap();
+                }
+            }
+        } else if (executing || step_once) && halted_with.is_none() {
+            let result = if step_once {
+                let result = chip.process_next_instruction().map(|_| ());
                 step_once = false;
                 executing = false;
                 chip.print_registers();
+                result
             } else {
-                chip.process_a_frame(
-                    keys,
-                    target_chip_frame_time.as_micros() as u32, // Should be a safe cast, unless someone wants a ridiculously large amount of processing time for a frame
-                );
+                let mut result = Ok(());
+                // Unlimited frames run until their time is up, so they have to share the time
+                // left before the next frame. Otherwise each one takes a whole frame of real
+                // time, and we'd never catch up
+                let unlimited_budget = scheduler.time_until_next_frame() / frames_due.max(1);
+                for _ in 0..frames_due {
+                    rewind_buffer.push(chip.save_state());
+                    result = match chip.execution_model {
+                        ExecutionModel::Unlimited => {
+                            chip.process_a_frame_unlimited(unlimited_budget).map(|_| ())
+                        }
+                        _ => chip.run_a_frame(),
+                    };
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                result
+            };
+
+            if let Err(error) = result {
+                eprintln!("Halted: {}", error);
+                chip.print_registers();
+                canvas
+                    .window_mut()
+                    .set_title(&format!("Chip 8 Emulator - Halted: {}", error))
+                    .unwrap();
+                halted_with = Some(error);
             }
         }
 
-        if chip.should_play_sound() {
+        {
+            // XO-CHIP ROMs can change these at any time, so keep the audio thread up to date
+            let mut wave = device.lock();
+            wave.pattern = chip.audio_pattern;
+            wave.playback_rate = chip.audio_playback_rate();
+            wave.volume = config.volume as f32 / 100.0;
+        }
+        if chip.should_play_sound() && halted_with.is_none() && config.volume > 0 {
             device.resume();
         } else {
             device.pause();
@@ -216,17 +410,16 @@ fn main() {
 
         // NOTE - The flickering in breakout is limited to the paddle, and also happens in the Octo
         // emulator, so I think it's intended
-        draw_display(&mut canvas, &mut texture, &chip.display_buffer);
-
-        let current_frame_time = std::time::Instant::now();
-
-        let latest_frame_time = current_frame_time - last_frame_time;
-        last_frame_time = current_frame_time;
-
-        let time_to_sleep = target_frame_time.saturating_sub(latest_frame_time);
-
-        if !time_to_sleep.is_zero() {
-            std::thread::sleep(time_to_sleep);
+        // The halt screen is just the last frame drawn in red, with the error in the title bar
+        let palette = match halted_with {
+            None => palette,
+            Some(_) => HALTED_PALETTE,
+        };
+        // With vsync, presenting the frame waits for the display instead
+        draw_display(&mut canvas, &mut texture, &chip, &palette);
+
+        if !config.vsync {
+            std::thread::sleep(scheduler.time_until_next_frame());
         }
     }
 }
@@ -234,17 +427,17 @@ fn main() {
 fn draw_display<T: sdl2::render::RenderTarget>(
     canvas: &mut Canvas<T>,
     texture: &mut Texture,
-    display_buffer: &[bool],
+    chip: &Chip8,
+    palette: &Palette,
 ) {
+    let display_width = chip.display_width();
+    let display_height = chip.display_height();
     texture
         .with_lock(None, |buffer: &mut [u8], pitch: usize| {
-            for x in 0..CHIP_DISPLAY_WIDTH_IN_PIXELS {
-                for y in 0..CHIP_DISPLAY_HEIGHT_IN_PIXELS {
-                    let display_buffer_idx = idx_for_display(x as u8, y as u8);
-                    let color = match display_buffer[display_buffer_idx] {
-                        false => Color::RGB(0, 0, 0),
-                        true => Color::RGB(255, 255, 255),
-                    };
+            for x in 0..display_width {
+                for y in 0..display_height {
+                    let display_buffer_idx = chip.idx_for_display(x, y);
+                    let color = palette[chip.display_buffer[display_buffer_idx] as usize];
 
                     let texture_idx = y * pitch + x * 3;
                     let rgb = color.rgb();
@@ -255,29 +448,39 @@ fn draw_display<T: sdl2::render::RenderTarget>(
             }
         })
         .unwrap();
-    canvas.copy(&texture, None, None).unwrap();
+    let used_area = Rect::new(0, 0, display_width as u32, display_height as u32);
+    canvas.copy(&texture, used_area, None).unwrap();
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.present();
 }
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
+/// Plays the chip's 128 bit audio pattern on a loop, 1 bit at a time. For anything but XO-CHIP
+/// ROMs the pattern is just a square wave.
+struct PatternWave {
+    pattern: [u8; 16],
+    /// Pattern bits played per second
+    playback_rate: f32,
+    sample_rate: f32,
+    /// Which bit of the pattern we're on
+    position: f32,
     volume: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for PatternWave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
+        let pattern_length_in_bits = (self.pattern.len() * 8) as f32;
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
+            let bit = self.position as usize;
+            let bit_is_set = (self.pattern[bit / 8] >> (7 - bit % 8)) & 0x1 > 0;
+            *x = if bit_is_set {
                 self.volume
             } else {
                 -self.volume
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            self.position =
+                (self.position + self.playback_rate / self.sample_rate) % pattern_length_in_bits;
         }
     }
 }
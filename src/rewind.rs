@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+/// Frames usually only change a few bytes, so this is minutes of rewind even for XO-CHIP
+pub const DEFAULT_REWIND_BUFFER_SIZE_IN_BYTES: usize = 32 * 1024 * 1024;
+
+/// Unchanged bytes that can sit inside a run of changes, rather than starting a new run. Each run
+/// costs 8 bytes to describe, so shorter gaps are cheaper to keep
+const MAX_GAP_IN_RUN: usize = 8;
+
+/// Ring buffer of save states, one per frame. Once it's full the oldest frames are dropped to
+/// make room.
+///
+/// Only the most recent snapshot is kept whole. Every earlier one is kept as the changes needed
+/// to get back to it from the snapshot after it, which for most frames is a handful of registers
+/// and a few pixels rather than the whole of memory and the display.
+pub struct RewindBuffer {
+    latest: Option<Vec<u8>>,
+    /// Oldest first. The last one steps back from latest
+    deltas: VecDeque<Delta>,
+    size_in_bytes: usize,
+    max_size_in_bytes: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(max_size_in_bytes: usize) -> Self {
+        return RewindBuffer {
+            latest: None,
+            deltas: VecDeque::new(),
+            size_in_bytes: 0,
+            max_size_in_bytes,
+        };
+    }
+
+    /// Snapshots bigger than the whole buffer aren't kept
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if snapshot.len() > self.max_size_in_bytes {
+            return;
+        }
+        if let Some(latest) = self.latest.take() {
+            let delta = Delta::between(&latest, &snapshot);
+            self.size_in_bytes = self.size_in_bytes - latest.len() + delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.size_in_bytes += snapshot.len();
+        self.latest = Some(snapshot);
+
+        // The latest snapshot fits on its own, so there's always a delta to drop until we fit
+        while self.size_in_bytes > self.max_size_in_bytes {
+            let oldest = self.deltas.pop_front().unwrap();
+            self.size_in_bytes -= oldest.len();
+        }
+    }
+
+    /// Takes the most recent snapshot, so calling this repeatedly steps further back in time
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let snapshot = self.latest.take()?;
+        self.size_in_bytes -= snapshot.len();
+        if let Some(delta) = self.deltas.pop_back() {
+            let previous = delta.apply(&snapshot);
+            self.size_in_bytes = self.size_in_bytes - delta.len() + previous.len();
+            self.latest = Some(previous);
+        }
+        return Some(snapshot);
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.size_in_bytes = 0;
+    }
+}
+
+/// How to get a snapshot back from the one that came after it
+enum Delta {
+    /// The whole snapshot, when that's smaller than describing the changes
+    Full(Vec<u8>),
+    /// The bytes that differ, XORed with the later snapshot (or 0 past the end of it). Each run of
+    /// changes is how far it starts after the end of the last one and how long it is, as big
+    /// endian u32s, then the XORed bytes
+    Changes { length: usize, runs: Vec<u8> },
+}
+
+impl Delta {
+    fn between(earlier: &[u8], later: &[u8]) -> Delta {
+        let xor = |i: usize| earlier[i] ^ later.get(i).copied().unwrap_or(0);
+
+        let mut runs = Vec::new();
+        let mut previous_end = 0;
+        let mut i = 0;
+        while i < earlier.len() {
+            if xor(i) == 0 {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            let mut end = i + 1;
+            i += 1;
+            while i < earlier.len() && i - end < MAX_GAP_IN_RUN {
+                if xor(i) != 0 {
+                    end = i + 1;
+                }
+                i += 1;
+            }
+            runs.extend_from_slice(&((start - previous_end) as u32).to_be_bytes());
+            runs.extend_from_slice(&((end - start) as u32).to_be_bytes());
+            runs.extend((start..end).map(xor));
+            previous_end = end;
+        }
+
+        if runs.len() >= earlier.len() {
+            return Delta::Full(earlier.to_vec());
+        }
+        return Delta::Changes {
+            length: earlier.len(),
+            runs,
+        };
+    }
+
+    fn apply(&self, later: &[u8]) -> Vec<u8> {
+        let (length, runs) = match self {
+            Delta::Full(snapshot) => return snapshot.clone(),
+            Delta::Changes { length, runs } => (*length, runs),
+        };
+        let mut snapshot = later[..length.min(later.len())].to_vec();
+        snapshot.resize(length, 0);
+
+        let read_u32 =
+            |at: usize| u32::from_be_bytes(runs[at..at + 4].try_into().unwrap()) as usize;
+        let mut position = 0;
+        let mut cursor = 0;
+        while cursor < runs.len() {
+            position += read_u32(cursor);
+            let run_length = read_u32(cursor + 4);
+            cursor += 8;
+            for (byte, change) in snapshot[position..position + run_length]
+                .iter_mut()
+                .zip(&runs[cursor..cursor + run_length])
+            {
+                *byte ^= change;
+            }
+            position += run_length;
+            cursor += run_length;
+        }
+        return snapshot;
+    }
+
+    fn len(&self) -> usize {
+        return match self {
+            Delta::Full(snapshot) => snapshot.len(),
+            Delta::Changes { runs, .. } => runs.len(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_most_recent_first() {
+        let mut buffer = RewindBuffer::new(100);
+        buffer.push(vec![1; 10]);
+        buffer.push(vec![2; 10]);
+
+        assert_eq!(buffer.pop(), Some(vec![2; 10]));
+        assert_eq!(buffer.pop(), Some(vec![1; 10]));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut buffer = RewindBuffer::new(25);
+        buffer.push(vec![1; 10]);
+        buffer.push(vec![2; 10]);
+        buffer.push(vec![3; 10]);
+        // Too big to ever fit, so shouldn't push anything out
+        buffer.push(vec![4; 30]);
+
+        assert_eq!(buffer.pop(), Some(vec![3; 10]));
+        assert_eq!(buffer.pop(), Some(vec![2; 10]));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn steps_back_through_frames_that_barely_change() {
+        let mut buffer = RewindBuffer::new(100_000);
+        let mut frames = Vec::new();
+        let mut frame = vec![0u8; 20_000];
+        for i in 0..50 {
+            frame[i * 300] = i as u8 + 1;
+            frame[19_999 - i] ^= 0xFF;
+            // Save states grow and shrink with the number of queued key events
+            frame.resize(20_000 + i % 3, 7);
+            frames.push(frame.clone());
+            buffer.push(frame.clone());
+        }
+        // All 50 full frames would be 1MB
+        assert!(buffer.size_in_bytes < 25_000, "{}", buffer.size_in_bytes);
+
+        for expected in frames.iter().rev() {
+            assert_eq!(buffer.pop().as_ref(), Some(expected));
+        }
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.size_in_bytes, 0);
+    }
+}
@@ -0,0 +1,56 @@
+/// Random number source for Cxkk. SplitMix64, so the whole state is one u64 and is trivial to
+/// save and restore. Runs with the same seed and the same input produce the same numbers.
+/// See <https://prng.di.unimi.it/splitmix64.c>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rng {
+    pub state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        return Rng { state: seed };
+    }
+
+    /// Seeded from the OS, for when we don't care about being reproducible
+    pub fn from_entropy() -> Self {
+        return Rng::new(rand::random());
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        return (self.next_u64() >> 56) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_numbers() {
+        let mut first = Rng::new(1234);
+        let mut second = Rng::new(1234);
+        let first_numbers: Vec<u8> = (0..32).map(|_| first.next_u8()).collect();
+        let second_numbers: Vec<u8> = (0..32).map(|_| second.next_u8()).collect();
+        assert_eq!(first_numbers, second_numbers);
+
+        let mut other = Rng::new(4321);
+        let other_numbers: Vec<u8> = (0..32).map(|_| other.next_u8()).collect();
+        assert_ne!(first_numbers, other_numbers);
+    }
+
+    #[test]
+    fn matches_reference_implementation() {
+        // First outputs of the reference splitmix64.c seeded with 1234567
+        let mut rng = Rng::new(1234567);
+        assert_eq!(rng.next_u64(), 6457827717110365317);
+        assert_eq!(rng.next_u64(), 3203168211198807973);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::chip::*;
+
+/// Every save state starts with this, so we don't try to load any old file as one
+const MAGIC: &[u8; 4] = b"CH8S";
+/// Bump this whenever the layout below changes. Older states are rejected rather than guessed at.
+pub const SAVE_STATE_VERSION: u16 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveStateError {
+    /// Doesn't start with MAGIC
+    NotASaveState,
+    UnsupportedVersion(u16),
+    /// The state was saved while running a different ROM to the one that's loaded
+    DifferentRom {
+        expected_hash: u64,
+        found_hash: u64,
+    },
+    /// Ran out of bytes before reading the whole state
+    Truncated,
+    /// The bytes are there, but don't make sense (e.g. an unknown quirk value)
+    Corrupt,
+}
+
+impl std::fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is not supported (expected version {})",
+                version, SAVE_STATE_VERSION
+            ),
+            SaveStateError::DifferentRom {
+                expected_hash,
+                found_hash,
+            } => write!(
+                f,
+                "save state is for a different ROM (ROM hash {:016x}, save state has {:016x})",
+                expected_hash, found_hash
+            ),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl Chip8 {
+    /// Snapshot of everything needed to carry on exactly where we left off. All numbers are big
+    /// endian, to match the CHIP-8 itself.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter {
+            bytes: Vec::with_capacity(self.memory.len() + DISPLAY_BUFFER_SIZE + 256),
+        };
+        writer.write_bytes(MAGIC);
+        writer.write_u16(SAVE_STATE_VERSION);
+        writer.write_u64(self.rom_hash);
+
+        writer.write_bool(self.quirks.shift_uses_vy);
+        writer.write_u8(match self.quirks.load_store_i_increment {
+            LoadStoreIncrement::Unchanged => 0,
+            LoadStoreIncrement::X => 1,
+            LoadStoreIncrement::XPlusOne => 2,
+        });
+        writer.write_bool(self.quirks.logic_ops_reset_vf);
+        writer.write_bool(self.quirks.clip_sprites);
+        writer.write_bool(self.quirks.jump_with_offset_uses_vx);
+        writer.write_bool(self.quirks.display_wait);
+        writer.write_bool(self.quirks.key_wait_on_release);
+        writer.write_bool(self.quirks.xo_chip);
+
+        writer.write_u32(self.memory.len() as u32);
+        writer.write_bytes(&self.memory);
+        writer.write_bytes(&self.data_registers);
+        writer.write_u32(self.program_counter as u32);
+        for address in self.stack {
+            writer.write_u16(address);
+        }
+        writer.write_u8(self.stack_pointer);
+        writer.write_u16(self.i_register);
+        writer.write_bytes(&self.display_buffer);
+        writer.write_u8(self.selected_planes);
+        writer.write_bool(self.high_resolution);
+        writer.write_u8(self.delay_timer);
+        writer.write_u8(self.sound_timer);
+        for key in self.keys {
+            writer.write_bool(key);
+        }
+        writer.write_bool(self.should_play_sound);
+        writer.write_bytes(&self.rpl_flags);
+        writer.write_bool(self.exited);
+        writer.write_bytes(&self.audio_pattern);
+        writer.write_u8(self.pitch);
+        writer.write_u64(self.rng.state);
+        let (model, instructions_per_frame) = match self.execution_model {
+            ExecutionModel::VipTiming => (0, 0),
+            ExecutionModel::InstructionsPerFrame(instructions_per_frame) => {
+                (1, instructions_per_frame)
+            }
+            ExecutionModel::Unlimited => (2, 0),
+        };
+        writer.write_u8(model);
+        writer.write_u32(instructions_per_frame);
+        // Always the same size, whatever we're waiting for
+        let (key_wait, key) = match self.key_wait {
+            KeyWait::NotWaiting => (0, 0),
+            KeyWait::ForPress => (1, 0),
+            KeyWait::ForRelease { key } => (2, key),
+            KeyWait::Finished { key } => (3, key),
+        };
+        writer.write_u8(key_wait);
+        writer.write_u8(key);
+        writer.write_u64(self.cycles);
+        writer.write_u32(self.key_events.len() as u32);
+        for event in &self.key_events {
+            writer.write_u64(event.cycle);
+            writer.write_u8(event.key);
+            writer.write_bool(event.pressed);
+        }
+
+        return writer.bytes;
+    }
+
+    /// Restores a state made by save_state. Nothing is changed if the state can't be loaded.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader { state, position: 0 };
+        if reader.read_bytes(MAGIC.len()) != Ok(MAGIC.as_slice()) {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let version = reader.read_u16()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let found_hash = reader.read_u64()?;
+        if found_hash != self.rom_hash {
+            return Err(SaveStateError::DifferentRom {
+                expected_hash: self.rom_hash,
+                found_hash,
+            });
+        }
+
+        // Fill in a copy so we don't leave ourselves half loaded if something goes wrong
+        let mut chip = self.clone();
+
+        chip.quirks.shift_uses_vy = reader.read_bool()?;
+        chip.quirks.load_store_i_increment = match reader.read_u8()? {
+            0 => LoadStoreIncrement::Unchanged,
+            1 => LoadStoreIncrement::X,
+            2 => LoadStoreIncrement::XPlusOne,
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        chip.quirks.logic_ops_reset_vf = reader.read_bool()?;
+        chip.quirks.clip_sprites = reader.read_bool()?;
+        chip.quirks.jump_with_offset_uses_vx = reader.read_bool()?;
+        chip.quirks.display_wait = reader.read_bool()?;
+        chip.quirks.key_wait_on_release = reader.read_bool()?;
+        chip.quirks.xo_chip = reader.read_bool()?;
+
+        let memory_size = reader.read_u32()? as usize;
+        let expected_memory_size = if chip.quirks.xo_chip {
+            XO_CHIP_MEMORY_SIZE_IN_BYTES
+        } else {
+            CHIP_MEMORY_SIZE_IN_BYTES
+        };
+        if memory_size != expected_memory_size {
+            return Err(SaveStateError::Corrupt);
+        }
+        chip.memory = reader.read_bytes(memory_size)?.to_vec();
+        chip.data_registers.copy_from_slice(reader.read_bytes(16)?);
+        chip.program_counter = reader.read_u32()? as usize;
+        for address in chip.stack.iter_mut() {
+            *address = reader.read_u16()?;
+        }
+        chip.stack_pointer = reader.read_u8()?;
+        if chip.stack_pointer as usize >= chip.stack.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        chip.i_register = reader.read_u16()?;
+        chip.display_buffer
+            .copy_from_slice(reader.read_bytes(DISPLAY_BUFFER_SIZE)?);
+        // Each pixel is a bit per plane, and there are only 2 planes. Anything else can't be drawn
+        if chip.display_buffer.iter().any(|pixel| *pixel > 0b11) {
+            return Err(SaveStateError::Corrupt);
+        }
+        chip.selected_planes = reader.read_u8()?;
+        if chip.selected_planes > 0b11 {
+            return Err(SaveStateError::Corrupt);
+        }
+        chip.high_resolution = reader.read_bool()?;
+        chip.delay_timer = reader.read_u8()?;
+        chip.sound_timer = reader.read_u8()?;
+        for key in chip.keys.iter_mut() {
+            *key = reader.read_bool()?;
+        }
+        chip.should_play_sound = reader.read_bool()?;
+        chip.rpl_flags.copy_from_slice(reader.read_bytes(16)?);
+        chip.exited = reader.read_bool()?;
+        chip.audio_pattern.copy_from_slice(reader.read_bytes(16)?);
+        chip.pitch = reader.read_u8()?;
+        chip.rng.state = reader.read_u64()?;
+        let model = reader.read_u8()?;
+        let instructions_per_frame = reader.read_u32()?;
+        chip.execution_model = match model {
+            0 => ExecutionModel::VipTiming,
+            1 => ExecutionModel::InstructionsPerFrame(instructions_per_frame),
+            2 => ExecutionModel::Unlimited,
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        let key_wait = reader.read_u8()?;
+        let key = reader.read_u8()?;
+        chip.key_wait = match key_wait {
+            0 => KeyWait::NotWaiting,
+            1 => KeyWait::ForPress,
+            2 if key <= 0xF => KeyWait::ForRelease { key },
+            3 if key <= 0xF => KeyWait::Finished { key },
+            _ => return Err(SaveStateError::Corrupt),
+        };
+        chip.cycles = reader.read_u64()?;
+        let key_event_count = reader.read_u32()?;
+        chip.key_events.clear();
+        for _ in 0..key_event_count {
+            let cycle = reader.read_u64()?;
+            let key = reader.read_u8()?;
+            let pressed = reader.read_bool()?;
+            if key > 0xF {
+                return Err(SaveStateError::Corrupt);
+            }
+            chip.key_events.push_back(KeypadEvent {
+                key,
+                pressed,
+                cycle,
+            });
+        }
+
+        if reader.position != state.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+
+        *self = chip;
+        return Ok(());
+    }
+}
+
+struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn write_u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8);
+    }
+
+    fn write_u16(&mut self, val: u16) {
+        self.write_bytes(&val.to_be_bytes());
+    }
+
+    fn write_u32(&mut self, val: u32) {
+        self.write_bytes(&val.to_be_bytes());
+    }
+
+    fn write_u64(&mut self, val: u64) {
+        self.write_bytes(&val.to_be_bytes());
+    }
+}
+
+struct StateReader<'a> {
+    state: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.position + length > self.state.len() {
+            return Err(SaveStateError::Truncated);
+        }
+        let bytes = &self.state[self.position..self.position + length];
+        self.position += length;
+        return Ok(bytes);
+    }
+
+    fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        return Ok(self.read_bytes(1)?[0]);
+    }
+
+    fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        return match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Corrupt),
+        };
+    }
+
+    fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.read_bytes(2)?;
+        return Ok(u16::from_be_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        let bytes = self.read_bytes(4)?;
+        return Ok(u32::from_be_bytes(bytes.try_into().unwrap()));
+    }
+
+    fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        let bytes = self.read_bytes(8)?;
+        return Ok(u64::from_be_bytes(bytes.try_into().unwrap()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running_chip(rom_path: &str) -> Chip8 {
+        let rom = std::fs::read(rom_path).unwrap();
+        let mut chip = Chip8::new(&rom, Quirks::superchip());
+        for _ in 0..10 {
+            chip.process_a_frame(FRAME_TIME_IN_MICROSECONDS).unwrap();
+        }
+        return chip;
+    }
+
+    #[test]
+    fn restores_a_saved_state() {
+        let mut chip = running_chip("roms/test_opcode.ch8");
+        chip.keys[0x4] = true;
+        chip.sound_timer = 30;
+        chip.should_play_sound = true;
+        chip.quirks = Quirks::cosmac_vip();
+        chip.execution_model = ExecutionModel::InstructionsPerFrame(30);
+        chip.key_wait = KeyWait::ForRelease { key: 0x4 };
+        chip.key_up(0x4);
+        let state = chip.save_state();
+
+        let mut restored = running_chip("roms/test_opcode.ch8");
+        for _ in 0..10 {
+            restored
+                .process_a_frame(FRAME_TIME_IN_MICROSECONDS)
+                .unwrap();
+        }
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.program_counter, chip.program_counter);
+        assert_eq!(restored.display_buffer, chip.display_buffer);
+        assert_eq!(restored.keys, chip.keys);
+        assert!(restored.should_play_sound);
+        assert_eq!(restored.quirks, Quirks::cosmac_vip());
+        assert_eq!(
+            restored.execution_model,
+            ExecutionModel::InstructionsPerFrame(30)
+        );
+        assert_eq!(restored.key_wait, KeyWait::ForRelease { key: 0x4 });
+        assert_eq!(restored.cycles, chip.cycles);
+        assert_eq!(restored.key_events, chip.key_events);
+    }
+
+    #[test]
+    fn restored_states_replay_the_same_random_numbers() {
+        let rom = std::fs::read("roms/Maze [David Winter, 199x].ch8").unwrap();
+        let mut chip = Chip8::with_seed(&rom, Quirks::cosmac_vip(), 7);
+        chip.process_a_frame(FRAME_TIME_IN_MICROSECONDS).unwrap();
+        let state = chip.save_state();
+        for _ in 0..30 {
+            chip.process_a_frame(FRAME_TIME_IN_MICROSECONDS).unwrap();
+        }
+
+        let mut replay = Chip8::new(&rom, Quirks::cosmac_vip());
+        replay.load_state(&state).unwrap();
+        for _ in 0..30 {
+            replay.process_a_frame(FRAME_TIME_IN_MICROSECONDS).unwrap();
+        }
+        assert_eq!(replay.display_buffer, chip.display_buffer);
+    }
+
+    #[test]
+    fn rejects_states_from_other_roms() {
+        let state = running_chip("roms/test_opcode.ch8").save_state();
+        let mut other_chip = running_chip("roms/2-ibm-logo.ch8");
+        let before = other_chip.save_state();
+
+        assert!(matches!(
+            other_chip.load_state(&state),
+            Err(SaveStateError::DifferentRom { .. })
+        ));
+        assert_eq!(other_chip.save_state(), before);
+    }
+
+    #[test]
+    fn rejects_broken_states() {
+        let mut chip = running_chip("roms/test_opcode.ch8");
+        let state = chip.save_state();
+
+        assert_eq!(
+            chip.load_state(b"not a state"),
+            Err(SaveStateError::NotASaveState)
+        );
+        assert_eq!(
+            chip.load_state(&state[..state.len() - 1]),
+            Err(SaveStateError::Truncated)
+        );
+
+        let mut future_state = state.clone();
+        future_state[4..6].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            chip.load_state(&future_state),
+            Err(SaveStateError::UnsupportedVersion(SAVE_STATE_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_states_that_would_break_the_chip() {
+        let mut chip = running_chip("roms/test_opcode.ch8");
+        let before = chip.save_state();
+
+        let mut bad_pixel = chip.clone();
+        bad_pixel.display_buffer[0] = 0xFF;
+        let mut bad_planes = chip.clone();
+        bad_planes.selected_planes = 4;
+        // XO-CHIP needs the bigger memory
+        let mut wrong_memory_size = chip.clone();
+        wrong_memory_size.quirks.xo_chip = true;
+
+        for broken in [bad_pixel, bad_planes, wrong_memory_size] {
+            assert_eq!(
+                chip.load_state(&broken.save_state()),
+                Err(SaveStateError::Corrupt)
+            );
+        }
+        assert_eq!(chip.save_state(), before);
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use crate::diagnostic::{Diagnostic, Span};
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TokenType {
     LD,
@@ -21,17 +23,62 @@ pub enum TokenType {
     CLS,
     SHL,
     SHR,
+    SYS,
+    // SUPER-CHIP
+    SCD,
+    SCR,
+    SCL,
+    EXIT,
+    LOW,
+    HIGH,
+    // XO-CHIP
+    PLANE,
+    AUDIO,
+    PITCH,
+    /// Raw bytes, for data the instructions can't express. db or byte
+    DB,
+    /// 16 bit words, high byte first
+    DW,
+    /// Moves the address the next statement goes at, e.g org 0x300
+    ORG,
+    /// Pads with zeros up to the next multiple of an address, e.g align 2
+    ALIGN,
+    /// A bitmap drawn with the SpriteRows on the lines after it
+    SPRITE,
     Number,
     // Not sure if we want this yet!
     Addr,
     Comma,
     IRegister,
+    /// [I], the memory I points at, for LD [I], Vx and LD Vx, [I]
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    /// K, a key press for LD Vx, K
+    Key,
+    /// F, the font sprite for LD F, Vx
+    Font,
+    /// HF, the big font sprite for LD HF, Vx (SUPER-CHIP)
+    BigFont,
+    /// B, the BCD digits for LD B, Vx
+    Bcd,
+    /// R, the RPL user flags for LD R, Vx and LD Vx, R (SUPER-CHIP)
+    RplFlags,
+    /// long, before the 16 bit address in LD I, long addr (XO-CHIP)
+    Long,
+    /// -, between the registers of a range like LD [I], V2 - V5 (XO-CHIP)
+    Dash,
     Newline,
     // Newline used at the terminator for most statements
     Register,
     Label,
+    /// :name value, a label for a constant
     LabelIdentifier,
     Colon,
+    /// name:, a label for the address of whatever comes next. The word doesn't include the colon
+    CodeLabel,
+    /// A row of a sprite, # for pixels that are on and . for ones that are off. e.g ..##..
+    SpriteRow,
     NumericalValue(NumericalValue),
 }
 
@@ -48,6 +95,7 @@ pub struct Token {
     // Just cloning the str's right now so we can move along
     pub word: Vec<char>,
     pub literal: Option<u16>,
+    pub span: Span,
 }
 
 pub struct Scanner {
@@ -55,10 +103,15 @@ pub struct Scanner {
     current_char_idx: usize,
     source_as_chars: Vec<char>,
     keywords: HashMap<String, TokenType>,
+    /// Starts from 1
+    line: usize,
+    line_start_char_idx: usize,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Scanner {
     pub fn new(source: String) -> Self {
+        // Looked up in upper case, so every keyword can be written in either case
         let keywords: HashMap<String, TokenType> = HashMap::from([
             ("JP".to_string(), TokenType::JP),
             ("LD".to_string(), TokenType::LD),
@@ -80,6 +133,30 @@ impl Scanner {
             ("CLS".to_string(), TokenType::CLS),
             ("SHL".to_string(), TokenType::SHL),
             ("SHR".to_string(), TokenType::SHR),
+            ("SYS".to_string(), TokenType::SYS),
+            ("SCD".to_string(), TokenType::SCD),
+            ("SCR".to_string(), TokenType::SCR),
+            ("SCL".to_string(), TokenType::SCL),
+            ("EXIT".to_string(), TokenType::EXIT),
+            ("LOW".to_string(), TokenType::LOW),
+            ("HIGH".to_string(), TokenType::HIGH),
+            ("PLANE".to_string(), TokenType::PLANE),
+            ("AUDIO".to_string(), TokenType::AUDIO),
+            ("PITCH".to_string(), TokenType::PITCH),
+            ("DT".to_string(), TokenType::DelayTimer),
+            ("ST".to_string(), TokenType::SoundTimer),
+            ("K".to_string(), TokenType::Key),
+            ("F".to_string(), TokenType::Font),
+            ("HF".to_string(), TokenType::BigFont),
+            ("B".to_string(), TokenType::Bcd),
+            ("R".to_string(), TokenType::RplFlags),
+            ("LONG".to_string(), TokenType::Long),
+            ("DB".to_string(), TokenType::DB),
+            ("BYTE".to_string(), TokenType::DB),
+            ("DW".to_string(), TokenType::DW),
+            ("ORG".to_string(), TokenType::ORG),
+            ("ALIGN".to_string(), TokenType::ALIGN),
+            ("SPRITE".to_string(), TokenType::SPRITE),
         ]);
 
         let scanner = Scanner {
@@ -87,12 +164,17 @@ impl Scanner {
             current_char_idx: 0,
             source_as_chars: source.chars().collect(),
             keywords,
+            line: 1,
+            line_start_char_idx: 0,
+            diagnostics: Vec::new(),
         };
         return scanner;
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
-        let mut tokens = vec![];
+    /// Every token in the source, ending with a Newline. Anything that can't be scanned is
+    /// reported and skipped, so the parser still sees the rest of the line.
+    pub fn tokenize(&mut self) -> (Vec<Token>, Vec<Diagnostic>) {
+        let mut tokens: Vec<Token> = vec![];
 
         while self.current_char_idx < self.source_as_chars.len() {
             self.start_char_idx = self.current_char_idx;
@@ -100,9 +182,14 @@ impl Scanner {
                 tokens.push(token);
             }
         }
-        dbg!(&tokens);
 
-        return tokens;
+        // So the last statement is terminated like every other one
+        if tokens.last().map(|token| token.token_type) != Some(TokenType::Newline) {
+            self.start_char_idx = self.current_char_idx;
+            tokens.push(self.make_token(TokenType::Newline, None));
+        }
+
+        return (tokens, std::mem::take(&mut self.diagnostics));
     }
 
     fn next_char_is(&self, ch: char) -> bool {
@@ -120,155 +207,181 @@ impl Scanner {
         self.current_char_idx += 1;
     }
 
+    fn advance_by(&mut self, amount: usize) {
+        self.current_char_idx += amount;
+    }
+
+    /// Where the token being scanned is
+    fn span(&self) -> Span {
+        return Span {
+            start: self.start_char_idx,
+            end: self.current_char_idx,
+            line: self.line,
+            column: self.start_char_idx - self.line_start_char_idx + 1,
+        };
+    }
+
+    fn make_token(&self, token_type: TokenType, literal: Option<u16>) -> Token {
+        // SPEEDUP(reece): Don't clone the string
+        return Token {
+            token_type,
+            word: self.source_as_chars[self.start_char_idx..self.current_char_idx].to_owned(),
+            literal,
+            span: self.span(),
+        };
+    }
+
+    /// Reports the token being scanned, and skips it
+    fn error(&mut self, message: impl Into<String>) -> Option<Token> {
+        self.diagnostics.push(Diagnostic::new(message, self.span()));
+        return None;
+    }
+
     fn scan_token(&mut self) -> Option<Token> {
         let ch = self.source_as_chars[self.current_char_idx];
         self.advance();
         match ch {
             ':' => {
                 if !self.peek().is_alphabetic() {
-                    panic!("Was expecting a label name after :");
+                    return self.error("Was expecting a label name after :");
                 }
                 // Try parse a label/identifier
-                while self.peek().is_alphabetic() {
+                while is_identifier_char(self.peek()) {
                     self.advance();
                 }
-                let label = &self.source_as_chars[self.start_char_idx..self.current_char_idx];
-                dbg!(&label);
-                // SPEEDUP(reece): Don't clone the string
-                Some(Token {
-                    token_type: TokenType::LabelIdentifier,
-                    word: label.to_owned(),
-                    literal: None,
-                })
+                let name: String = self.source_as_chars
+                    [self.start_char_idx + 1..self.current_char_idx]
+                    .iter()
+                    .collect();
+                if self.keyword(&name).is_some() {
+                    return self.error(reserved_word_message(&name));
+                }
+                return Some(self.make_token(TokenType::LabelIdentifier, None));
             }
-            '0' => {
-                if self.next_char_is('x') {
+            ';' => {
+                // Comment until the end of the line. The newline is still a token
+                while !self.is_at_end() && self.peek() != '\n' {
                     self.advance();
-                    let val = self.parse_hex_number();
-                    Some(Token {
-                        token_type: TokenType::NumericalValue(NumericalValue::Number),
-                        word: self.source_as_chars[self.start_char_idx..self.current_char_idx]
-                            .to_owned(),
-                        literal: Some(val),
-                    })
-                } else {
-                    panic!("Was expecting hex number after 0 character");
                 }
+                return None;
             }
-            ',' => Some(Token {
-                token_type: TokenType::Comma,
-                word: self.source_as_chars[self.start_char_idx..self.current_char_idx].to_owned(),
-                literal: None,
-            }),
-            'V' => {
-                let register_val = self.parse_hex_number();
-                return Some(Token {
-                    token_type: TokenType::Register,
-                    word: self.source_as_chars[self.start_char_idx..self.current_char_idx]
-                        .to_owned(),
-                    literal: Some(register_val),
-                });
+            '[' => {
+                if !((self.next_char_is('I') || self.next_char_is('i'))
+                    && self.source_as_chars.get(self.current_char_idx + 1) == Some(&']'))
+                {
+                    return self.error("Was expecting I] after [");
+                }
+                self.advance_by(2);
+                return Some(self.make_token(TokenType::IndirectI, None));
             }
-            _ => {
-                if ch.is_alphabetic() {
-                    if let Some(instruction_token_type) = self.parse_instruction() {
-                        return Some(Token {
-                            token_type: instruction_token_type,
-                            word: self.source_as_chars[self.start_char_idx..self.current_char_idx]
-                                .to_owned(),
-                            literal: None,
-                        });
-                    } else {
-                        // Try parse a label/identifier
-                        while self.peek().is_alphabetic() {
-                            self.advance();
-                        }
-                        let label =
-                            &self.source_as_chars[self.start_char_idx..self.current_char_idx];
-                        dbg!(&label);
-                        // SPEEDUP(reece): Don't clone the string
-                        Some(Token {
-                            token_type: TokenType::Label,
-                            word: label.to_owned(),
-                            literal: None,
-                        })
+            '#' | '.' => {
+                while matches!(self.peek(), '#' | '.') {
+                    self.advance();
+                }
+                return Some(self.make_token(TokenType::SpriteRow, None));
+            }
+            '-' => return Some(self.make_token(TokenType::Dash, None)),
+            ',' => return Some(self.make_token(TokenType::Comma, None)),
+            '\n' => {
+                let token = self.make_token(TokenType::Newline, None);
+                self.line += 1;
+                self.line_start_char_idx = self.current_char_idx;
+                return Some(token);
+            }
+            '0' if self.next_char_is('x') => {
+                self.advance();
+                let digits_start = self.current_char_idx;
+                while is_identifier_char(self.peek()) {
+                    self.advance();
+                }
+                return match self.parse_number(digits_start, 16) {
+                    Ok(val) => {
+                        Some(self.make_token(
+                            TokenType::NumericalValue(NumericalValue::Number),
+                            Some(val),
+                        ))
                     }
-                } else if ch == '\n' {
-                    Some(Token {
-                        token_type: TokenType::Newline,
-                        word: self.source_as_chars[self.start_char_idx..self.current_char_idx]
-                            .to_owned(),
-                        literal: None,
-                    })
-                } else if ch.is_whitespace() {
-                    None
-                } else if ch.is_ascii_digit() {
-                    let val = self.parse_decimal_number();
-                    Some(Token {
-                        token_type: TokenType::Number,
-                        word: self.source_as_chars[self.start_char_idx..self.current_char_idx]
-                            .to_owned(),
-                        literal: Some(val),
-                    })
-                } else {
-                    todo!()
+                    Err(message) => self.error(message),
+                };
+            }
+            _ if ch.is_ascii_digit() => {
+                while is_identifier_char(self.peek()) {
+                    self.advance();
                 }
+                return match self.parse_number(self.start_char_idx, 10) {
+                    Ok(val) => Some(self.make_token(TokenType::Number, Some(val))),
+                    Err(message) => self.error(message),
+                };
             }
-        }
-    }
+            _ if ch.is_alphabetic() => {
+                while is_identifier_char(self.peek()) {
+                    self.advance();
+                }
+                let text: String = self.source_as_chars[self.start_char_idx..self.current_char_idx]
+                    .iter()
+                    .collect();
 
-    fn parse_instruction(&mut self) -> Option<TokenType> {
-        while self.peek().is_alphabetic() && !self.next_char_is(',') {
-            self.advance();
-        }
+                // V followed by hex digits is a register, or a typo for one if there's only one
+                // character after it. Anything longer is a word like VALUE
+                let register_digits = &text[ch.len_utf8()..];
+                let looks_like_a_register = register_digits.chars().count() == 1
+                    || (!register_digits.is_empty()
+                        && register_digits.chars().all(|ch| ch.is_ascii_hexdigit()));
+                if (ch == 'V' || ch == 'v') && looks_like_a_register {
+                    return match u16::from_str_radix(register_digits, 16) {
+                        Ok(register) if register <= 0xF => {
+                            Some(self.make_token(TokenType::Register, Some(register)))
+                        }
+                        _ => self.error(format!(
+                            "There is no register {}, they go from V0 to VF",
+                            text
+                        )),
+                    };
+                }
 
-        // SPEEDUP(reece): Don't clone the string
-        let text: String = self.source_as_chars[self.start_char_idx..self.current_char_idx]
-            .iter()
-            .collect();
-        dbg!(&text);
-        if let Some(keyword_type) = self.keywords.get(&text) {
-            return Some(*keyword_type);
+                let keyword_type = self.keyword(&text);
+                if self.next_char_is(':') {
+                    if keyword_type.is_some() {
+                        let error = self.error(reserved_word_message(&text));
+                        // Skip the colon too, so it isn't read as the start of a :label
+                        self.advance();
+                        return error;
+                    }
+                    let mut token = self.make_token(TokenType::CodeLabel, None);
+                    self.advance();
+                    token.span = self.span();
+                    return Some(token);
+                }
+                if let Some(keyword_type) = keyword_type {
+                    return Some(self.make_token(keyword_type, None));
+                }
+                return Some(self.make_token(TokenType::Label, None));
+            }
+            _ if ch.is_whitespace() => return None,
+            _ => return self.error(format!("Unexpected character '{}'", ch)),
         }
-        dbg!("No keyword type found for {}", text);
-        return None;
     }
 
-    fn parse_hex_number(&mut self) -> u16 {
-        while self.peek().is_ascii_hexdigit() {
-            self.advance();
-        }
-
-        // TODO(reece): ROBUSTNESS: Handle V without a register defined immediately afterwards
-        let num_as_string: String;
-        if self.source_as_chars[self.start_char_idx] == 'V' {
-            num_as_string = self.source_as_chars[self.start_char_idx + 1..self.current_char_idx]
-                .iter()
-                .collect();
-        } else {
-            num_as_string = self.source_as_chars[self.start_char_idx + 2..self.current_char_idx]
-                .iter()
-                .collect();
-        }
-
-        // Seems like a safe unwrap (ignoring numbers too big!)
-        let num = u16::from_str_radix(&num_as_string, 16).unwrap();
-        return num;
+    fn keyword(&self, word: &str) -> Option<TokenType> {
+        return self.keywords.get(&word.to_ascii_uppercase()).copied();
     }
 
-    fn parse_decimal_number(&mut self) -> u16 {
-        while self.peek().is_ascii_digit() {
-            self.advance();
-        }
-
-        let num_as_string: String;
-        num_as_string = self.source_as_chars[self.start_char_idx..self.current_char_idx]
+    /// The digits from digits_start up to the current char, which have already been consumed
+    fn parse_number(&self, digits_start: usize, radix: u32) -> Result<u16, String> {
+        let digits: String = self.source_as_chars[digits_start..self.current_char_idx]
             .iter()
             .collect();
-
-        // Seems like a safe unwrap (ignoring numbers too big!)
-        let num = u16::from_str_radix(&num_as_string, 10).unwrap();
-        return num;
+        let number: String = self.source_as_chars[self.start_char_idx..self.current_char_idx]
+            .iter()
+            .collect();
+        if digits.is_empty() {
+            return Err(format!("Was expecting digits after {}", number));
+        }
+        return match u32::from_str_radix(&digits, radix) {
+            Ok(value) if value <= u16::MAX as u32 => Ok(value as u16),
+            Ok(_) => Err(format!("{} is too big, numbers go up to 0xFFFF", number)),
+            Err(_) => Err(format!("{} is not a valid number", number)),
+        };
     }
 
     fn peek(&self) -> char {
@@ -290,8 +403,18 @@ impl Scanner {
     }
 }
 
-pub fn tokenize(source: String) -> Vec<Token> {
-    let mut scanner = Scanner::new(source);
-    let tokens = scanner.tokenize();
-    return tokens;
+/// Mnemonics, operands like F and directives can't be used as label names, since they'd be read as
+/// themselves wherever the label is used
+fn reserved_word_message(word: &str) -> String {
+    return format!("'{}' is a reserved word, so it can't be a label", word);
+}
+
+/// Labels start with a letter, then can have letters, digits and underscores. e.g label_2A4
+fn is_identifier_char(ch: char) -> bool {
+    return ch.is_alphanumeric() || ch == '_';
+}
+
+pub fn tokenize(source: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut scanner = Scanner::new(source.to_string());
+    return scanner.tokenize();
 }
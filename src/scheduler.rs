@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+/// One tick of the 60Hz timers, and one slice of instructions. See Chip8::run_a_frame
+pub const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Most frames to run in one go when we've fallen behind. Any more than this and we'd spend so
+/// long catching up that we'd fall further behind, so the rest are dropped.
+pub const DEFAULT_MAX_CATCH_UP_FRAMES: u32 = 4;
+
+/// Fixed timestep scheduler. The frontend tells it how much real time has passed, and it says how
+/// many 60Hz frames to run, so the emulator runs at the same speed however often the host draws
+/// (60Hz, 120Hz, 144Hz, vsync or not) and catches up when the host drops frames.
+/// See <https://gafferongames.com/post/fix_your_timestep/>
+#[derive(Debug, Clone)]
+pub struct FrameScheduler {
+    /// Real time that hasn't been turned into frames yet
+    accumulator: Duration,
+    max_catch_up_frames: u32,
+    /// Frames dropped because we were too far behind, for the curious
+    pub dropped_frames: u64,
+}
+
+impl FrameScheduler {
+    pub fn new(max_catch_up_frames: u32) -> Self {
+        return FrameScheduler {
+            accumulator: Duration::ZERO,
+            max_catch_up_frames: max_catch_up_frames.max(1),
+            dropped_frames: 0,
+        };
+    }
+
+    /// Adds the real time since the last call, and returns how many frames should run now
+    pub fn frames_due(&mut self, elapsed: Duration) -> u32 {
+        self.accumulator += elapsed;
+        let mut frames = 0;
+        while self.accumulator >= FRAME_DURATION {
+            self.accumulator -= FRAME_DURATION;
+            frames += 1;
+        }
+
+        if frames > self.max_catch_up_frames {
+            self.dropped_frames += (frames - self.max_catch_up_frames) as u64;
+            frames = self.max_catch_up_frames;
+        }
+        return frames;
+    }
+
+    /// How long until the next frame is due, for sleeping when there's no vsync to wait on
+    pub fn time_until_next_frame(&self) -> Duration {
+        return FRAME_DURATION.saturating_sub(self.accumulator);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames run over a second of the host drawing at refresh_rate
+    fn frames_in_a_second(refresh_rate: u64) -> u32 {
+        let mut scheduler = FrameScheduler::new(DEFAULT_MAX_CATCH_UP_FRAMES);
+        // Like the differences between Instants, so the host frames add up to exactly a second
+        let host_time = |host_frame: u64| host_frame * 1_000_000_000 / refresh_rate;
+        return (1..=refresh_rate)
+            .map(|host_frame| {
+                let elapsed = host_time(host_frame) - host_time(host_frame - 1);
+                scheduler.frames_due(Duration::from_nanos(elapsed))
+            })
+            .sum();
+    }
+
+    #[test]
+    fn runs_60_frames_a_second_at_any_refresh_rate() {
+        for refresh_rate in [30, 60, 75, 120, 144, 240] {
+            assert_eq!(frames_in_a_second(refresh_rate), 60, "{}Hz", refresh_rate);
+        }
+    }
+
+    #[test]
+    fn catches_up_after_a_stall_but_not_forever() {
+        let mut scheduler = FrameScheduler::new(4);
+        assert_eq!(scheduler.frames_due(FRAME_DURATION * 3), 3);
+        assert_eq!(scheduler.dropped_frames, 0);
+
+        // A whole second stall only runs the catch up limit, and the rest is dropped
+        assert_eq!(scheduler.frames_due(Duration::from_secs(1)), 4);
+        assert_eq!(scheduler.dropped_frames, 56);
+        assert_eq!(scheduler.frames_due(FRAME_DURATION), 1);
+    }
+
+    #[test]
+    fn carries_partial_frames_over() {
+        let mut scheduler = FrameScheduler::new(DEFAULT_MAX_CATCH_UP_FRAMES);
+        assert_eq!(scheduler.frames_due(FRAME_DURATION / 2), 0);
+        assert_eq!(scheduler.time_until_next_frame(), FRAME_DURATION / 2);
+        assert_eq!(scheduler.frames_due(FRAME_DURATION / 2), 1);
+        assert_eq!(scheduler.time_until_next_frame(), FRAME_DURATION);
+    }
+}
@@ -0,0 +1,14 @@
+LD V0, 0x4
+LD V2, 0x2
+JP V0, 0x208
+JP 0x206
+JP 0x210
+JP 0x214
+JP 0x218
+JP 0x20E
+LD V1, 0x1
+JP 0x212
+LD V1, 0x2
+JP 0x216
+LD V1, 0x3
+JP 0x21A
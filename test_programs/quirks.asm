@@ -0,0 +1,113 @@
+; Checks each quirk and draws what it found as a row of digits, left to right:
+;
+; VF reset      0 if 8xy1 sets VF to 0, 5 if it's left alone
+; Shift         2 if 8xy6 shifts Vy, 8 if it shifts Vx in place
+; Load/store    How far Fx65 moves I past x: 2 (x + 1), 1 (x) or 0 (unchanged)
+; Clipping      0 if sprites are clipped at the edge, 1 if they wrap
+; Jump offset   0 if Bnnn adds V0, 1 if it adds Vx
+; Display wait  1 if DRW waits for the next frame, 0 if it doesn't
+;
+; The results are kept in VA, VB, VC, VD, VE and V9, in that order.
+
+; VF reset
+LD VF, 0x5
+LD V0, 0x1
+LD V1, 0x1
+OR V0, V1
+LD VA, VF
+
+; Shift
+LD V0, 0x10
+LD V1, 0x4
+SHR V0, V1
+LD VB, V0
+
+; Load/store
+LD I, counts
+LD V1, [I]
+LD V0, [I]
+LD VC, V0
+
+; Clipping. Draws a row from x 60, then a pixel at x 0 that only collides if the row wrapped.
+; Both are drawn again to leave the screen blank
+LD V1, 0x3C
+LD V2, 0x14
+LD I, row
+DRW V1, V2, 0x1
+LD V1, 0x0
+LD I, pixel
+DRW V1, V2, 0x1
+LD VD, VF
+DRW V1, V2, 0x1
+LD V1, 0x3C
+LD I, row
+DRW V1, V2, 0x1
+
+; Display wait. Counts how many times a pixel can be drawn and erased before the delay timer runs
+; out, giving up at 20
+LD V3, 0xA
+LD DT, V3
+LD V9, 0x0
+LD V1, 0x0
+LD V2, 0x1F
+LD I, pixel
+wait_loop:
+DRW V1, V2, 0x1
+DRW V1, V2, 0x1
+ADD V9, 0x1
+SNE V9, 0x14
+JP wait_done
+LD V3, DT
+SE V3, 0x0
+JP wait_loop
+wait_done:
+; Waiting for every draw fits at most 5 in 10 frames
+LD V3, 0x7
+SUB V3, V9
+LD V9, VF
+
+; Jump offset. The table is at 0x300, so B300 can be read as V0 + 0x300 or V3 + 0x300
+LD V0, 0x0
+LD V3, 0x2
+JP V0, jump_table
+
+counts:
+db 0x0, 0x1, 0x2, 0x3
+row:
+db 0xFF
+pixel:
+db 0x80
+
+org 0x300
+jump_table:
+JP added_v0
+JP added_v3
+added_v0:
+LD VE, 0x0
+JP draw_results
+added_v3:
+LD VE, 0x1
+
+draw_results:
+LD V1, 0x4
+LD V2, 0xC
+LD F, VA
+DRW V1, V2, 0x5
+ADD V1, 0xA
+LD F, VB
+DRW V1, V2, 0x5
+ADD V1, 0xA
+LD F, VC
+DRW V1, V2, 0x5
+ADD V1, 0xA
+LD F, VD
+DRW V1, V2, 0x5
+ADD V1, 0xA
+LD F, VE
+DRW V1, V2, 0x5
+ADD V1, 0xA
+LD F, V9
+DRW V1, V2, 0x5
+
+halt:
+JP halt